@@ -0,0 +1,201 @@
+/**
+ * End-to-end encryption for uploads, the key only ever lives in the link fragment.
+ *
+ * Layout of an encrypted file:
+ *   magic "RE2E" (4) | version (1) | chunk size (4) | size (8) | base nonce (8) | meta length (2)
+ *   | meta (AES-GCM sealed JSON with name and type) | chunks (AES-GCM, chunk size + 16 bytes each)
+ *
+ * Chunk nonces are the base nonce followed by the chunk index, chunks are bound
+ * to the whole header and flag the last chunk, so they can't be reordered,
+ * swapped between files or cut off.
+ */
+const RistE2E = (() => {
+  const MAGIC = [0x52, 0x45, 0x32, 0x45];
+  const VERSION = 1;
+  const FIXED_LEN = 27;
+  const CHUNK_SIZE = 1024 * 1024;
+  const TAG_LEN = 16;
+  const META_INDEX = 0xffffffff;
+
+  const nonce = (base, index) => {
+    const iv = new Uint8Array(12);
+    iv.set(base, 0);
+    new DataView(iv.buffer).setUint32(8, index);
+    return iv;
+  };
+
+  const concat = (parts) => {
+    const out = new Uint8Array(parts.reduce((len, part) => len + part.length, 0));
+    let offset = 0;
+    for (const part of parts) {
+      out.set(part, offset);
+      offset += part.length;
+    }
+    return out;
+  };
+
+  const chunkAad = (ctx, index) =>
+    concat([ctx.header, new Uint8Array([index === ctx.chunkCount - 1 ? 1 : 0])]);
+
+  const importKey = (raw) =>
+    crypto.subtle.importKey("raw", raw, "AES-GCM", false, ["encrypt", "decrypt"]);
+
+  const encodeKey = (raw) =>
+    btoa(String.fromCharCode(...raw))
+      .replace(/\+/g, "-")
+      .replace(/\//g, "_")
+      .replace(/=+$/, "");
+
+  const decodeKey = (text) => {
+    const base64 = text.replace(/-/g, "+").replace(/_/g, "/");
+    return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+  };
+
+  /** Prepares encrypting `file` with a new key, `size` is the size of the encrypted file. */
+  const create = async (file) => {
+    if (!window.crypto || !crypto.subtle) {
+      throw new Error("Encryption needs a secure context (HTTPS or localhost)");
+    }
+
+    const rawKey = crypto.getRandomValues(new Uint8Array(32));
+    const key = await importKey(rawKey);
+    const base = crypto.getRandomValues(new Uint8Array(8));
+    const meta = new TextEncoder().encode(
+      JSON.stringify({ name: file.name, type: file.type })
+    );
+
+    const fixed = new Uint8Array(FIXED_LEN);
+    const view = new DataView(fixed.buffer);
+    fixed.set(MAGIC, 0);
+    view.setUint8(4, VERSION);
+    view.setUint32(5, CHUNK_SIZE);
+    view.setBigUint64(9, BigInt(file.size));
+    fixed.set(base, 17);
+    view.setUint16(25, meta.length + TAG_LEN);
+
+    const sealedMeta = new Uint8Array(
+      await crypto.subtle.encrypt(
+        { name: "AES-GCM", iv: nonce(base, META_INDEX), additionalData: fixed },
+        key,
+        meta
+      )
+    );
+
+    const header = concat([fixed, sealedMeta]);
+    const chunkCount = Math.ceil(file.size / CHUNK_SIZE);
+    return {
+      key,
+      secret: encodeKey(rawKey),
+      base,
+      header,
+      chunkSize: CHUNK_SIZE,
+      chunkCount,
+      plainSize: file.size,
+      size: header.length + file.size + chunkCount * TAG_LEN,
+    };
+  };
+
+  /** Bytes `start` to `end` (exclusive) of the encrypted file, chunks are sealed as needed. */
+  const encryptRange = async (ctx, file, start, end) => {
+    const parts = [];
+    if (start < ctx.header.length) {
+      parts.push(ctx.header.slice(start, Math.min(end, ctx.header.length)));
+    }
+
+    const sealedSize = ctx.chunkSize + TAG_LEN;
+    const first = Math.max(0, Math.floor((start - ctx.header.length) / sealedSize));
+    const last = Math.min(
+      ctx.chunkCount - 1,
+      Math.floor((end - 1 - ctx.header.length) / sealedSize)
+    );
+
+    for (let index = first; index <= last; index++) {
+      const plain = await file
+        .slice(index * ctx.chunkSize, Math.min((index + 1) * ctx.chunkSize, ctx.plainSize))
+        .arrayBuffer();
+      const sealed = new Uint8Array(
+        await crypto.subtle.encrypt(
+          { name: "AES-GCM", iv: nonce(ctx.base, index), additionalData: chunkAad(ctx, index) },
+          ctx.key,
+          plain
+        )
+      );
+
+      const offset = ctx.header.length + index * sealedSize;
+      parts.push(
+        sealed.slice(Math.max(0, start - offset), Math.min(sealed.length, end - offset))
+      );
+    }
+
+    return concat(parts);
+  };
+
+  /** Reads the header of an encrypted file, failing if `secret` is not its key. */
+  const open = async (bytes, secret) => {
+    if (bytes.length < FIXED_LEN || MAGIC.some((b, i) => bytes[i] !== b)) {
+      throw new Error("This is not an encrypted file");
+    }
+
+    const view = new DataView(bytes.buffer, bytes.byteOffset, FIXED_LEN);
+    if (view.getUint8(4) !== VERSION) {
+      throw new Error("Unsupported encryption version");
+    }
+
+    const chunkSize = view.getUint32(5);
+    const plainSize = Number(view.getBigUint64(9));
+    const base = bytes.slice(17, 25);
+    const header = bytes.slice(0, FIXED_LEN + view.getUint16(25));
+    const key = await importKey(decodeKey(secret));
+
+    let meta;
+    try {
+      meta = await crypto.subtle.decrypt(
+        { name: "AES-GCM", iv: nonce(base, META_INDEX), additionalData: header.slice(0, FIXED_LEN) },
+        key,
+        header.slice(FIXED_LEN)
+      );
+    } catch {
+      throw new Error("Wrong key or damaged file");
+    }
+
+    return {
+      key,
+      base,
+      header,
+      chunkSize,
+      chunkCount: Math.ceil(plainSize / chunkSize),
+      plainSize,
+      meta: JSON.parse(new TextDecoder().decode(meta)),
+    };
+  };
+
+  /** Decrypts the whole file, `bytes` as returned by the server. */
+  const decrypt = async (ctx, bytes) => {
+    const sealedSize = ctx.chunkSize + TAG_LEN;
+    const expected = ctx.header.length + ctx.plainSize + ctx.chunkCount * TAG_LEN;
+    if (bytes.length !== expected) {
+      throw new Error("The file is incomplete or damaged");
+    }
+
+    const parts = [];
+    for (let index = 0; index < ctx.chunkCount; index++) {
+      const offset = ctx.header.length + index * sealedSize;
+      const sealed = bytes.subarray(offset, Math.min(offset + sealedSize, bytes.length));
+      try {
+        parts.push(
+          await crypto.subtle.decrypt(
+            { name: "AES-GCM", iv: nonce(ctx.base, index), additionalData: chunkAad(ctx, index) },
+            ctx.key,
+            sealed
+          )
+        );
+      } catch {
+        throw new Error("The file is damaged");
+      }
+    }
+
+    return new Blob(parts, { type: ctx.meta.type || "application/octet-stream" });
+  };
+
+  return { create, encryptRange, open, decrypt };
+})();
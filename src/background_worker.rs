@@ -2,7 +2,10 @@ use std::{path::Path, time::Duration};
 
 use tokio::time::interval;
 
-use crate::{state, utils};
+use crate::{job_queue, quota, state, utils};
+
+/// Unfinished uploads without any activity for this long are dropped.
+const STALE_UPLOAD_SECS: u64 = 24 * 60 * 60;
 
 pub fn init() -> Result<(), Box<dyn std::error::Error>> {
     tokio::spawn(async move {
@@ -36,19 +39,51 @@ async fn iteration() -> Result<(), Box<dyn std::error::Error>> {
         state.file_db.remove_by_uuid(&row.uuid).await?;
     }
 
+    // Blobs are only removed once no file references them anymore
+    {
+        let _blob_lock = state.file_db.lock_blobs().await;
+        for blob in state.file_db.get_unreferenced_blobs().await? {
+            if !state.file_db.remove_blob(&blob.hash).await? {
+                continue;
+            }
+            println!("[INFO  ] (BW) Removing unreferenced blob: {}", blob.hash);
+            state.storage.delete(&blob.path).await?;
+        }
+    }
+
+    let now = utils::get_current_timestamp();
+    let stale_uploads = state
+        .upload_status
+        .read()
+        .await
+        .iter()
+        .filter(|(_, status)| status.last_activity + STALE_UPLOAD_SECS < now)
+        .map(|(uuid, _)| uuid.clone())
+        .collect::<Vec<_>>();
+    for uuid in stale_uploads {
+        println!("[INFO  ] (BW) Removing stale upload: {}", uuid);
+        state.remove_upload_status(&uuid).await;
+        state.file_db.remove_by_uuid(&uuid).await?;
+    }
+
     let rows = state.video_db.get_expired_videos().await?;
     for row in rows {
         println!("[INFO  ] (BW) Removing expired video: {}", row.uuid);
         state.video_db.remove_by_uuid(&row.uuid).await?;
+        job_queue::remove(&state, &row.uuid).await;
     }
 
+    job_queue::remove_finished(&state).await;
+
+    state.file_db.remove_expired_share_links().await?;
+
+    quota::prune_log(&state).await?;
+
+    state.user_db.remove_expired_sessions().await?;
+
     // Remove files not included in the database
     let upload_dir = &state.config.upload.upload_location;
 
-    if !Path::new(upload_dir).exists() {
-        return Ok(());
-    }
-
     let file_paths = state.file_db.get_paths().await?;
     let video_paths = state.video_db.get_paths().await?;
     let paths = file_paths
@@ -59,25 +94,77 @@ async fn iteration() -> Result<(), Box<dyn std::error::Error>> {
                 .into_iter()
                 .map(|path| utils::get_filename_from_path(&path).unwrap()),
         )
+        .chain(
+            state
+                .video_db
+                .get_batches()
+                .await?
+                .into_iter()
+                .map(|batch| format!("{}.zip", batch)),
+        )
         .collect::<Vec<_>>();
 
-    let entries = std::fs::read_dir(upload_dir)?;
+    // yt-dlp writes temporary files next to the output while a job runs
+    let active_jobs = state
+        .jobs
+        .read()
+        .await
+        .values()
+        .filter(|job| job.state.is_active())
+        .map(|job| job.uuid.clone())
+        .collect::<Vec<_>>();
 
-    for entry in entries {
+    let is_orphan = |name: &str| {
+        // Skip files that are too long
+        // so the background worker does not mess with yt-dlp
+        if name.len() > 54 && !is_blob_key(name) {
+            return false;
+        }
+
+        if active_jobs.iter().any(|uuid| name.starts_with(uuid.as_str())) {
+            return false;
+        }
+
+        !paths.iter().any(|path| path == name)
+    };
+
+    for key in state.storage.list().await? {
+        if !is_orphan(&key) {
+            continue;
+        }
+
+        // Clip downloads are streamed into `.part` files before they are registered
+        if is_temporary(&key) && is_recent(&Path::new(upload_dir).join(&key)) {
+            continue;
+        }
+
+        // Uploads store a blob before registering it, both under the blob lock
+        let blob_lock = match is_blob_key(&key) {
+            true => Some(state.file_db.lock_blobs().await),
+            false => None,
+        };
+        if blob_lock.is_some() && state.file_db.get_blob(&key).await?.is_some() {
+            continue;
+        }
+
+        println!("[INFO  ] (BW) Removing file: {}", &key);
+        state.storage.delete(&key).await?;
+    }
+
+    // Remote backends leave uploads and yt-dlp output in the staging directory
+    if state.storage.shares_staging() || !Path::new(upload_dir).exists() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(upload_dir)? {
         let path = entry?.path();
 
         if path.is_file() {
             let entry_path =
-                utils::get_filename_from_path(path.to_str().unwrap()).unwrap_or(String::new());
-
-            // Skip files that are too long
-            // so the background worker does not mess with yt-dlp
-            if entry_path.len() > 54 {
-                continue;
-            }
+                utils::get_filename_from_path(path.to_str().unwrap()).unwrap_or_default();
 
-            if !paths.contains(&entry_path) {
-                println!("[INFO  ] (BW) Removing file: {}", &entry_path);
+            if is_orphan(&entry_path) && !(is_temporary(&entry_path) && is_recent(&path)) {
+                println!("[INFO  ] (BW) Removing staged file: {}", &entry_path);
                 std::fs::remove_file(path)?;
             }
         }
@@ -85,3 +172,22 @@ async fn iteration() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Blobs are stored under the SHA-256 of their content.
+fn is_blob_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Files still being written or fetched from storage.
+fn is_temporary(name: &str) -> bool {
+    name.ends_with(".part") || name.ends_with(".fetch")
+}
+
+/// Whether the file at `path` was modified within the last hour.
+fn is_recent(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed.as_secs() < 60 * 60)
+}
@@ -0,0 +1,289 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde_json::Value;
+
+use super::{fetch_page, Clip, ClipError, ClipErrorKind, ClipProvider, ClipQuality};
+
+/// Prefix of the content keys in Medal's clip objects, `contentUrl` alone is the source file.
+const CONTENT_URL_KEY: &str = "contentUrl";
+
+pub struct Medal;
+
+#[rocket::async_trait]
+impl ClipProvider for Medal {
+    fn name(&self) -> &'static str {
+        "MedalTV"
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        url.len() >= 20 && url.starts_with("https://medal.tv")
+    }
+
+    /// Clip pages look like `https://medal.tv/games/<game>/clips/<id>/<slug>`.
+    fn clip_id(&self, url: &str) -> Option<String> {
+        let path = url.split(['?', '#']).next()?;
+        let mut segments = path.split('/');
+        segments.find(|segment| *segment == "clips")?;
+        segments
+            .next()
+            .filter(|id| !id.is_empty())
+            .map(|id| id.to_string())
+    }
+
+    async fn resolve_all(&self, client: &reqwest::Client, url: &str) -> Result<Vec<Clip>, ClipError> {
+        let body = fetch_page(client, url).await?;
+        parse_page(&body, self.clip_id(url).as_deref())
+    }
+}
+
+/// Reads every clip from the `hydrationData` of a clip page.
+/// Pages also hold related clips, those that can't be read are skipped,
+/// only the `target` clip failing fails the page.
+pub fn parse_page(html: &str, target: Option<&str>) -> Result<Vec<Clip>, ClipError> {
+    let hydration_data = parse_hydration_data(html)?;
+
+    let clips = hydration_data["clips"].as_object().ok_or_else(|| {
+        ClipError::new(
+            ClipErrorKind::NoClipsFound,
+            "Could not find any clip objects in hydration data",
+        )
+    })?;
+
+    if clips.is_empty() {
+        return Err(ClipError::new(
+            ClipErrorKind::NoClipsFound,
+            "Could not find any clips in hydration data",
+        ));
+    }
+
+    let mut parsed = Vec::with_capacity(clips.len());
+    let mut first_error = None;
+    for (id, clip) in clips {
+        match parse_clip(&hydration_data, id, clip) {
+            Ok(clip) => parsed.push(clip),
+            Err(e) if target == Some(id.as_str()) => return Err(e),
+            Err(e) => {
+                println!("[WARN  ] Skipping unparsable clip {}: {}", id, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) if parsed.is_empty() => Err(e),
+        _ => Ok(parsed),
+    }
+}
+
+pub fn parse_hydration_data(html: &str) -> Result<Value, ClipError> {
+    static HYDRATION_DATA_RE: OnceLock<Regex> = OnceLock::new();
+    let re = HYDRATION_DATA_RE.get_or_init(|| Regex::new(r#"var hydrationData=(\{.*\})"#).unwrap());
+
+    let script_tag = re
+        .captures(html)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str())
+        .ok_or_else(|| {
+            ClipError::new(
+                ClipErrorKind::NoDataFound,
+                "Could not find hydration data in response",
+            )
+        })?;
+
+    serde_json::from_str::<Value>(script_tag).map_err(|e| {
+        eprintln!("Failed to parse hydration data: {}", e);
+        ClipError::new(ClipErrorKind::InvalidData, "Failed to parse hydration data")
+    })
+}
+
+fn parse_clip(hydration_data: &Value, id: &str, clip: &Value) -> Result<Clip, ClipError> {
+    let title = clip["contentTitle"].as_str().ok_or_else(|| {
+        ClipError::new(
+            ClipErrorKind::InvalidData,
+            "Could not find title in hydration data",
+        )
+    })?;
+
+    let qualities = clip
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter_map(|(key, value)| {
+                    let suffix = key.strip_prefix(CONTENT_URL_KEY)?;
+                    let url = value.as_str().filter(|url| !url.is_empty())?;
+                    if suffix.is_empty() {
+                        return Some(ClipQuality {
+                            label: String::from("original"),
+                            url: url.to_string(),
+                        });
+                    }
+
+                    // Only resolutions like `720p`, other keys hold streams or thumbnails
+                    let height = suffix.strip_suffix('p')?;
+                    if height.is_empty() || !height.chars().all(|c| c.is_ascii_digit()) {
+                        return None;
+                    }
+                    Some(ClipQuality {
+                        label: suffix.to_string(),
+                        url: url.replace("144", suffix),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if qualities.is_empty() {
+        return Err(ClipError::new(
+            ClipErrorKind::MissingContent,
+            "Could not find contentUrl in hydration data",
+        ));
+    }
+
+    // Games and users are either inlined or referenced by id
+    let game = clip["categoryName"].as_str().or_else(|| {
+        let category = id_string(&clip["categoryId"])?;
+        hydration_data["categories"][category.as_str()]["categoryName"].as_str()
+    });
+    let author = clip["poster"]["userName"].as_str().or_else(|| {
+        let poster = id_string(&clip["posterId"])?;
+        hydration_data["users"][poster.as_str()]["userName"].as_str()
+    });
+
+    Ok(Clip {
+        id: id.to_string(),
+        title: title.to_string(),
+        duration_secs: clip["videoLengthSeconds"]
+            .as_f64()
+            .map(|secs| secs.round() as u64),
+        game: game.map(|game| game.to_string()),
+        author: author.map(|author| author.to_string()),
+        qualities,
+    })
+}
+
+fn id_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIP_PAGE: &str = include_str!("fixtures/medal_clip.html");
+
+    fn labels(clip: &Clip) -> Vec<&str> {
+        let mut labels = clip.qualities.iter().map(|quality| quality.label.as_str()).collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn reads_clip_ids_from_urls() {
+        let medal = Medal;
+        assert_eq!(
+            medal.clip_id("https://medal.tv/games/valorant/clips/jP3kQ9xZ2aB/d1337VtcG3kq"),
+            Some(String::from("jP3kQ9xZ2aB"))
+        );
+        assert_eq!(
+            medal.clip_id("https://medal.tv/games/valorant/clips/jP3kQ9xZ2aB?invite=cr-MSxabc"),
+            Some(String::from("jP3kQ9xZ2aB"))
+        );
+        assert_eq!(
+            medal.clip_id("https://medal.tv/games/valorant/clips/jP3kQ9xZ2aB#comments"),
+            Some(String::from("jP3kQ9xZ2aB"))
+        );
+        assert_eq!(medal.clip_id("https://medal.tv/games/valorant/clips/"), None);
+        assert_eq!(medal.clip_id("https://medal.tv/games/valorant"), None);
+    }
+
+    #[test]
+    fn parses_saved_clip_page() {
+        let clips = parse_page(CLIP_PAGE, Some("jP3kQ9xZ2aB")).unwrap();
+        assert_eq!(clips.len(), 2);
+
+        let clip = clips.iter().find(|clip| clip.id == "jP3kQ9xZ2aB").unwrap();
+        assert_eq!(clip.title, "Ace on Ascent");
+        assert_eq!(clip.duration_secs, Some(27));
+        // Referenced by id in the fixture
+        assert_eq!(clip.game.as_deref(), Some("Valorant"));
+        assert_eq!(clip.author.as_deref(), Some("aceplayer"));
+        assert_eq!(labels(clip), ["1080p", "144p", "720p", "original"]);
+        assert_eq!(
+            clip.quality("original").unwrap().url,
+            "https://cdn.medal.tv/source/clip-jP3kQ9xZ2aB.mp4"
+        );
+        assert_eq!(
+            clip.quality("720p").unwrap().url,
+            "https://cdn.medal.tv/720p/clip-jP3kQ9xZ2aB-720p.mp4"
+        );
+
+        let related = clips.iter().find(|clip| clip.id == "Xw7bN2mLq0R").unwrap();
+        assert_eq!(related.title, "1v4 clutch");
+        assert_eq!(related.duration_secs, Some(41));
+        // Inlined in the fixture
+        assert_eq!(related.game.as_deref(), Some("Valorant"));
+        assert_eq!(related.author.as_deref(), Some("clutchking"));
+        assert_eq!(labels(related), ["720p", "original"]);
+    }
+
+    #[test]
+    fn refuses_pages_without_clips() {
+        let error = parse_page("<html><body>Not found</body></html>", None).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::NoDataFound);
+
+        let error = parse_page(r#"<script>var hydrationData={"clips":{}}</script>"#, None).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::NoClipsFound);
+
+        let error = parse_page(r#"<script>var hydrationData={"clips":{"a":}}</script>"#, None).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn skips_unparsable_related_clips() {
+        let page = CLIP_PAGE.replace(
+            r#""contentTitle":"1v4 clutch","#,
+            r#""contentTitleMissing":"1v4 clutch","#,
+        );
+        let clips = parse_page(&page, Some("jP3kQ9xZ2aB")).unwrap();
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].id, "jP3kQ9xZ2aB");
+
+        let error = parse_page(&page, Some("Xw7bN2mLq0R")).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::InvalidData);
+
+        let page = r#"<script>var hydrationData={"clips":{"a":{"contentUrl":"https://cdn.medal.tv/a.mp4"}}}</script>"#;
+        let error = parse_page(page, None).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_clip_objects() {
+        let hydration_data = parse_hydration_data(CLIP_PAGE).unwrap();
+
+        let clip = serde_json::json!({
+            "contentTitle": "Untitled",
+            "contentUrl": "https://cdn.medal.tv/source/clip.mp4",
+            "contentUrlHls": "https://cdn.medal.tv/hls/clip.m3u8",
+            "contentUrlp": "https://cdn.medal.tv/p/clip.mp4",
+        });
+        let clip = parse_clip(&hydration_data, "a", &clip).unwrap();
+        assert_eq!(labels(&clip), ["original"]);
+        assert_eq!(clip.duration_secs, None);
+        assert_eq!(clip.game, None);
+        assert_eq!(clip.author, None);
+
+        let untitled = serde_json::json!({"contentUrl": "https://cdn.medal.tv/source/clip.mp4"});
+        let error = parse_clip(&hydration_data, "a", &untitled).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::InvalidData);
+
+        let without_content = serde_json::json!({"contentTitle": "Untitled", "contentUrl": ""});
+        let error = parse_clip(&hydration_data, "a", &without_content).unwrap_err();
+        assert_eq!(error.kind, ClipErrorKind::MissingContent);
+    }
+}
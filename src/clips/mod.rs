@@ -0,0 +1,258 @@
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+pub mod medal;
+
+// MARK: Models
+/// A clip resolved from a provider page.
+#[derive(Serialize, Clone, Debug)]
+pub struct Clip {
+    pub id: String,
+    pub title: String,
+    pub duration_secs: Option<u64>,
+    pub game: Option<String>,
+    pub author: Option<String>,
+    pub qualities: Vec<ClipQuality>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ClipQuality {
+    /// Provider specific name like `720p`, `original` for the source file.
+    pub label: String,
+    #[serde(skip)]
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub struct ClipError {
+    pub kind: ClipErrorKind,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClipErrorKind {
+    /// The provider could not be reached.
+    RequestFailed,
+    /// The provider answered with an error status.
+    ProviderError,
+    NoBody,
+    NoDataFound,
+    InvalidData,
+    NoClipsFound,
+    MissingContent,
+    /// The content response was not a video.
+    InvalidContent,
+    /// The clip is larger than the configured maximum.
+    TooLarge,
+    DownloadFailed,
+}
+
+impl ClipError {
+    pub fn new(kind: ClipErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ClipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl Clip {
+    pub fn quality(&self, label: &str) -> Option<&ClipQuality> {
+        self.qualities.iter().find(|quality| quality.label == label)
+    }
+
+    /// The quality closest to `label`, preferring the higher one on a tie.
+    pub fn nearest_quality(&self, label: &str) -> Option<&ClipQuality> {
+        if let Some(quality) = self.quality(label) {
+            return Some(quality);
+        }
+
+        let target = quality_rank(label) as i64;
+        self.qualities.iter().min_by_key(|quality| {
+            let rank = quality_rank(&quality.label) as i64;
+            ((rank - target).abs(), -rank)
+        })
+    }
+}
+
+/// Height of a quality label like `720p`, unknown labels like `original` rank highest.
+fn quality_rank(label: &str) -> u32 {
+    label
+        .strip_suffix('p')
+        .and_then(|height| height.parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+// MARK: Provider
+/// A site clips can be downloaded from.
+/// Page parsing is kept separate from the network so it can run against saved pages.
+#[rocket::async_trait]
+pub trait ClipProvider: Send + Sync {
+    /// Name stored as the extractor of downloaded clips.
+    fn name(&self) -> &'static str;
+
+    fn matches(&self, url: &str) -> bool;
+
+    /// Id of the clip `url` points to, if it can be read from the URL.
+    fn clip_id(&self, _url: &str) -> Option<String> {
+        None
+    }
+
+    /// Fetches the page at `url` and reads every clip on it.
+    async fn resolve_all(&self, client: &reqwest::Client, url: &str) -> Result<Vec<Clip>, ClipError>;
+
+    /// Fetches the page at `url` and reads the clip it points to,
+    /// the first clip on the page if the URL does not tell.
+    async fn resolve(&self, client: &reqwest::Client, url: &str) -> Result<Clip, ClipError> {
+        let clips = self.resolve_all(client, url).await?;
+        let id = self.clip_id(url);
+
+        let index = id
+            .and_then(|id| clips.iter().position(|clip| clip.id == id))
+            .unwrap_or(0);
+        clips.into_iter().nth(index).ok_or_else(|| {
+            ClipError::new(ClipErrorKind::NoClipsFound, "Could not find any clips")
+        })
+    }
+
+    /// Labels of the qualities `clip` can be downloaded in.
+    fn qualities(&self, clip: &Clip) -> Vec<String> {
+        clip.qualities
+            .iter()
+            .map(|quality| quality.label.clone())
+            .collect()
+    }
+
+    /// Starts downloading `clip` in `quality`, the body is left to the caller.
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        quality: &ClipQuality,
+    ) -> Result<reqwest::Response, ClipError> {
+        client.get(&quality.url).send().await.map_err(|e| {
+            ClipError::new(ClipErrorKind::RequestFailed, e.to_string())
+        })
+    }
+}
+
+/// All supported providers, new sites only need to be added here.
+pub fn providers() -> Vec<Box<dyn ClipProvider>> {
+    vec![Box::new(medal::Medal)]
+}
+
+/// Finds the provider handling `url`.
+pub fn find(url: &str) -> Option<Box<dyn ClipProvider>> {
+    providers().into_iter().find(|provider| provider.matches(url))
+}
+
+/// Fetches a provider page as text.
+pub async fn fetch_page(client: &reqwest::Client, url: &str) -> Result<String, ClipError> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| ClipError::new(ClipErrorKind::RequestFailed, e.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(ClipError::new(
+            ClipErrorKind::ProviderError,
+            format!("Provider returned an error with status: {}", response.status()),
+        ));
+    }
+
+    response
+        .text()
+        .await
+        .map_err(|e| ClipError::new(ClipErrorKind::NoBody, e.to_string()))
+}
+
+/// Streams the body of `response` to `path`, refusing anything that is not a video
+/// or larger than `max_size_bytes`. The file is written next to `path` first and
+/// only moved into place once complete, so `path` never holds a partial clip.
+pub async fn save_response(
+    mut response: reqwest::Response,
+    path: &str,
+    max_size_bytes: u64,
+) -> Result<u64, ClipError> {
+    if !response.status().is_success() {
+        return Err(ClipError::new(
+            ClipErrorKind::ProviderError,
+            format!("Provider returned an error with status: {}", response.status()),
+        ));
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    if !content_type.starts_with("video/") && !content_type.starts_with("application/octet-stream") {
+        return Err(ClipError::new(
+            ClipErrorKind::InvalidContent,
+            format!("Provider returned {} instead of a video", content_type),
+        ));
+    }
+
+    if response.content_length().unwrap_or(0) > max_size_bytes {
+        return Err(too_large(max_size_bytes));
+    }
+
+    let part_path = format!("{}.part", path);
+    let result = write_part(&mut response, &part_path, max_size_bytes).await;
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = tokio::fs::rename(&part_path, path).await {
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Err(ClipError::new(ClipErrorKind::DownloadFailed, e.to_string()));
+    }
+
+    Ok(size)
+}
+
+async fn write_part(
+    response: &mut reqwest::Response,
+    part_path: &str,
+    max_size_bytes: u64,
+) -> Result<u64, ClipError> {
+    let write_error = |e: std::io::Error| ClipError::new(ClipErrorKind::DownloadFailed, e.to_string());
+
+    let mut file = tokio::fs::File::create(part_path).await.map_err(write_error)?;
+    let mut size = 0u64;
+
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| ClipError::new(ClipErrorKind::DownloadFailed, e.to_string()))?
+    {
+        size += chunk.len() as u64;
+        // Content-Length may be missing or wrong, so the cap is enforced while streaming
+        if size > max_size_bytes {
+            return Err(too_large(max_size_bytes));
+        }
+        file.write_all(&chunk).await.map_err(write_error)?;
+    }
+
+    file.flush().await.map_err(write_error)?;
+    file.sync_all().await.map_err(write_error)?;
+    Ok(size)
+}
+
+fn too_large(max_size_bytes: u64) -> ClipError {
+    ClipError::new(
+        ClipErrorKind::TooLarge,
+        format!("Clip is larger than {} bytes", max_size_bytes),
+    )
+}
@@ -1,10 +1,12 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
-use crate::db::user::UserKind;
+use crate::db::user::{User, UserKind};
+use crate::utils;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
@@ -13,6 +15,8 @@ pub struct Config {
     pub accounts: AccountsConfig,
     pub upload: UploadConfig,
     pub yt_dlp: YtDlpConfig,
+    pub clips: ClipsConfig,
+    pub storage: StorageConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,6 +30,25 @@ pub struct DatabaseConfig {
 pub struct UploadConfig {
     pub max_size_bytes: i64,
     pub upload_location: String,
+    pub quotas: QuotaConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotaConfig {
+    /// Limits by user kind, e.g. `user` or `guest`.
+    pub roles: HashMap<String, QuotaLimits>,
+    /// Limits by user name, these replace the limits of the user's kind.
+    pub users: HashMap<String, QuotaLimits>,
+}
+
+/// 0 means no limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct QuotaLimits {
+    /// Bytes of all stored files, including yt-dlp and clip downloads.
+    pub max_total_bytes: u64,
+    pub max_files: u64,
+    /// Bytes stored in the last 24 hours.
+    pub max_daily_bytes: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,12 +56,64 @@ pub struct YtDlpConfig {
     pub enabled: bool,
     pub dpl_exec_path: String,
     pub dpl_args: Vec<String>,
+    pub max_concurrent_jobs: usize,
+    pub max_jobs_per_user: usize,
+    pub max_playlist_items: usize,
+    /// yt-dlp `extractor_key`s that may be used, empty allows all.
+    pub allowed_extractors: Vec<String>,
+    /// yt-dlp `extractor_key`s that are always refused.
+    pub denied_extractors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClipsConfig {
+    /// Clips larger than this are refused while downloading.
+    pub max_size_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StorageConfig {
+    /// `local` keeps files in `upload.upload_location`, `s3` moves them to a bucket.
+    pub backend: String,
+    pub s3: S3Config,
+    pub encryption: EncryptionConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptionConfig {
+    /// Encrypts stored files, files stored before stay readable as they are.
+    pub enabled: bool,
+    /// Hex encoded 32 byte key, `RIST_MASTER_KEY` takes precedence.
+    pub master_key: String,
+    /// Keys files may still be encrypted with until `rist rotate-keys` ran,
+    /// `RIST_PREVIOUS_MASTER_KEYS` (comma separated) takes precedence.
+    pub previous_keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com` or `http://localhost:9000` for MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key.
+    pub prefix: String,
+    /// Addresses the bucket as `<endpoint>/<bucket>` instead of `<bucket>.<endpoint>`.
+    pub path_style: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    pub session_ttl_secs: u64,
+    pub secure_cookies: bool,
+    /// Signs unlock cookies of password protected files, generated on first start.
+    pub link_secret: String,
+    /// How long a correct file password unlocks the file.
+    pub unlock_ttl_secs: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,6 +128,45 @@ pub struct UserConfig {
     pub token: String,
 }
 
+impl YtDlpConfig {
+    /// Checks a yt-dlp `extractor_key` against the allow and deny lists.
+    pub fn allows_extractor(&self, extractor: &str) -> bool {
+        if self
+            .denied_extractors
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(extractor))
+        {
+            return false;
+        }
+
+        self.allowed_extractors.is_empty()
+            || self
+                .allowed_extractors
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(extractor))
+    }
+}
+
+impl QuotaConfig {
+    pub fn limits_for(&self, user: &User) -> QuotaLimits {
+        self.users
+            .get(&user.name)
+            .or_else(|| self.roles.get(user.kind().as_str()))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl QuotaLimits {
+    fn from_value(value: &Value) -> Self {
+        QuotaLimits {
+            max_total_bytes: value["max_total_bytes"].as_u64().unwrap_or(0),
+            max_files: value["max_files"].as_u64().unwrap_or(0),
+            max_daily_bytes: value["max_daily_bytes"].as_u64().unwrap_or(0),
+        }
+    }
+}
+
 impl Config {
     pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let mut config = Config::default();
@@ -88,6 +202,10 @@ impl Config {
             server: ServerConfig {
                 host: String::from("0.0.0.0"),
                 port: 3003,
+                session_ttl_secs: 3 * 60 * 60, // 3 hours
+                secure_cookies: false,
+                link_secret: utils::generate_token(),
+                unlock_ttl_secs: 15 * 60, // 15 minutes
             },
             accounts: AccountsConfig {
                 user: vec![UserConfig {
@@ -99,11 +217,40 @@ impl Config {
             upload: UploadConfig {
                 max_size_bytes: 100 * 1024 * 1024, // 100 MB
                 upload_location: String::from("./files/"),
+                quotas: QuotaConfig {
+                    roles: HashMap::new(),
+                    users: HashMap::new(),
+                },
             },
             yt_dlp: YtDlpConfig {
                 enabled: false,
                 dpl_exec_path: String::from("yt-dlp"),
                 dpl_args: vec![],
+                max_concurrent_jobs: 2,
+                max_jobs_per_user: 2,
+                max_playlist_items: 50,
+                allowed_extractors: vec![],
+                denied_extractors: vec![],
+            },
+            clips: ClipsConfig {
+                max_size_bytes: 500 * 1024 * 1024, // 500 MB
+            },
+            storage: StorageConfig {
+                backend: String::from("local"),
+                s3: S3Config {
+                    endpoint: String::from("http://localhost:9000"),
+                    bucket: String::new(),
+                    region: String::from("us-east-1"),
+                    access_key: String::new(),
+                    secret_key: String::new(),
+                    prefix: String::new(),
+                    path_style: true,
+                },
+                encryption: EncryptionConfig {
+                    enabled: false,
+                    master_key: String::new(),
+                    previous_keys: vec![],
+                },
             },
         }
     }
@@ -125,6 +272,25 @@ impl Config {
                     if let Some(server_value) = value.as_object() {
                         self.server.host = server_value["host"].as_str().unwrap_or("").to_string();
                         self.server.port = server_value["port"].as_u64().unwrap_or(0) as u16;
+                        self.server.session_ttl_secs = server_value
+                            .get("session_ttl_secs")
+                            .and_then(Value::as_u64)
+                            .unwrap_or(self.server.session_ttl_secs);
+                        self.server.secure_cookies = server_value
+                            .get("secure_cookies")
+                            .and_then(Value::as_bool)
+                            .unwrap_or(false);
+                        if let Some(secret) = server_value
+                            .get("link_secret")
+                            .and_then(Value::as_str)
+                            .filter(|s| !s.is_empty())
+                        {
+                            self.server.link_secret = secret.to_string();
+                        }
+                        self.server.unlock_ttl_secs = server_value
+                            .get("unlock_ttl_secs")
+                            .and_then(Value::as_u64)
+                            .unwrap_or(self.server.unlock_ttl_secs);
                     }
                 }
                 "accounts" => {
@@ -153,6 +319,21 @@ impl Config {
                             .unwrap_or("")
                             .to_string();
                     }
+                    if let Some(quotas_value) = value["quotas"].as_object() {
+                        let quotas = &mut self.upload.quotas;
+                        for (field, target) in [("roles", &mut quotas.roles), ("users", &mut quotas.users)] {
+                            *target = quotas_value
+                                .get(field)
+                                .and_then(|v| v.as_object())
+                                .map(|limits| {
+                                    limits
+                                        .iter()
+                                        .map(|(name, limits)| (name.clone(), QuotaLimits::from_value(limits)))
+                                        .collect()
+                                })
+                                .unwrap_or_default();
+                        }
+                    }
                 }
                 "yt_dlp" => {
                     if let Some(yt_dlp_value) = value.as_object() {
@@ -168,9 +349,93 @@ impl Config {
                                 .iter()
                                 .map(|arg| arg.as_str().unwrap_or("").to_string())
                                 .collect::<Vec<_>>(),
+                            max_concurrent_jobs: yt_dlp_value
+                                .get("max_concurrent_jobs")
+                                .and_then(Value::as_u64)
+                                .map(|jobs| jobs.max(1) as usize)
+                                .unwrap_or(self.yt_dlp.max_concurrent_jobs),
+                            max_jobs_per_user: yt_dlp_value
+                                .get("max_jobs_per_user")
+                                .and_then(Value::as_u64)
+                                .map(|jobs| jobs.max(1) as usize)
+                                .unwrap_or(self.yt_dlp.max_jobs_per_user),
+                            max_playlist_items: yt_dlp_value
+                                .get("max_playlist_items")
+                                .and_then(Value::as_u64)
+                                .map(|items| items as usize)
+                                .unwrap_or(self.yt_dlp.max_playlist_items),
+                            allowed_extractors: yt_dlp_value
+                                .get("allowed_extractors")
+                                .and_then(Value::as_array)
+                                .unwrap_or(&Vec::new())
+                                .iter()
+                                .map(|arg| arg.as_str().unwrap_or("").to_string())
+                                .collect::<Vec<_>>(),
+                            denied_extractors: yt_dlp_value
+                                .get("denied_extractors")
+                                .and_then(Value::as_array)
+                                .unwrap_or(&Vec::new())
+                                .iter()
+                                .map(|arg| arg.as_str().unwrap_or("").to_string())
+                                .collect::<Vec<_>>(),
                         };
                     }
                 }
+                "clips" => {
+                    if let Some(clips_value) = value.as_object() {
+                        self.clips.max_size_bytes = clips_value
+                            .get("max_size_bytes")
+                            .and_then(Value::as_u64)
+                            .unwrap_or(self.clips.max_size_bytes);
+                    }
+                }
+                "storage" => {
+                    if let Some(storage_value) = value.as_object() {
+                        self.storage.backend = storage_value
+                            .get("backend")
+                            .and_then(Value::as_str)
+                            .unwrap_or(&self.storage.backend)
+                            .to_string();
+                    }
+                    if let Some(s3_value) = value["s3"].as_object() {
+                        let s3 = &mut self.storage.s3;
+                        for (field, target) in [
+                            ("endpoint", &mut s3.endpoint),
+                            ("bucket", &mut s3.bucket),
+                            ("region", &mut s3.region),
+                            ("access_key", &mut s3.access_key),
+                            ("secret_key", &mut s3.secret_key),
+                            ("prefix", &mut s3.prefix),
+                        ] {
+                            if let Some(value) = s3_value.get(field).and_then(|v| v.as_str()) {
+                                *target = value.to_string();
+                            }
+                        }
+                        s3.path_style = s3_value
+                            .get("path_style")
+                            .and_then(Value::as_bool)
+                            .unwrap_or(s3.path_style);
+                    }
+                    if let Some(encryption_value) = value["encryption"].as_object() {
+                        let encryption = &mut self.storage.encryption;
+                        encryption.enabled = encryption_value
+                            .get("enabled")
+                            .and_then(Value::as_bool)
+                            .unwrap_or(false);
+                        encryption.master_key = encryption_value
+                            .get("master_key")
+                            .and_then(Value::as_str)
+                            .unwrap_or("")
+                            .to_string();
+                        encryption.previous_keys = encryption_value
+                            .get("previous_keys")
+                            .and_then(Value::as_array)
+                            .unwrap_or(&Vec::new())
+                            .iter()
+                            .filter_map(|key| key.as_str().map(|key| key.to_string()))
+                            .collect::<Vec<_>>();
+                    }
+                }
                 _ => {}
             }
         }
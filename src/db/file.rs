@@ -2,13 +2,17 @@ use std::path::Path;
 use sqlx::Row;
 use serde::Serialize;
 use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
+use tokio::sync::{Mutex, MutexGuard};
 
-use crate::{state, utils};
+use crate::{db, state, utils};
 
 
 pub struct FileDB {
     pub path: String,
     pool: SqlitePool,
+    /// Held while a blob is stored or removed, so a new reference never
+    /// points at content the background worker is deleting.
+    blob_lock: Mutex<()>,
 }
 
 impl FileDB {
@@ -28,7 +32,11 @@ impl FileDB {
         }
 
         let pool = SqlitePool::connect(path).await?;
-        
+        Self::open(pool, sqlite_path).await
+    }
+
+    /// Creates missing tables and migrates older databases on an open pool.
+    async fn open(pool: SqlitePool, path: String) -> Result<Self, Box<dyn std::error::Error>> {
         sqlx::query(
             r"CREATE TABLE IF NOT EXISTS Files (
               id INTEGER PRIMARY KEY,
@@ -39,7 +47,9 @@ impl FileDB {
               size INTEGER NOT NULL,
               created TEXT NOT NULL,
               expires_at TEXT NOT NULL,
-              access_count INTEGER NOT NULL
+              access_count INTEGER NOT NULL,
+              declared_hash TEXT NOT NULL DEFAULT '-',
+              owner INTEGER NOT NULL DEFAULT 0
             );",
         ).execute(&pool)
         .await
@@ -51,26 +61,114 @@ impl FileDB {
             e
         })?;
 
+        db::add_column_if_missing(&pool, "Files", "declared_hash", "TEXT NOT NULL DEFAULT '-'").await?;
+        db::add_column_if_missing(&pool, "Files", "owner", "INTEGER NOT NULL DEFAULT 0").await?;
+        // Files uploaded before blobs existed are moved into blobs below
+        db::add_column_if_missing(&pool, "Files", "blob", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Files", "encrypted", "INTEGER NOT NULL DEFAULT 0").await?;
+        db::add_column_if_missing(&pool, "Files", "password_hash", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Files", "max_downloads", "INTEGER NOT NULL DEFAULT 0").await?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS Blobs (
+              hash TEXT PRIMARY KEY,
+              path TEXT NOT NULL,
+              size INTEGER NOT NULL,
+              refcount INTEGER NOT NULL,
+              created TEXT NOT NULL
+            );",
+        ).execute(&pool)
+        .await
+          .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'FileDB' failed to create table 'Blobs': {}",
+                e
+            );
+            e
+        })?;
+
+        // Move finished files of older versions into blobs, equal content ends up
+        // in one of their files and the background worker removes the others
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r"INSERT INTO Blobs (hash, path, size, refcount, created)
+            SELECT hash, MIN(path), MAX(size), COUNT(*), ? FROM Files WHERE blob = '' AND hash != '-' GROUP BY hash
+            ON CONFLICT(hash) DO UPDATE SET refcount = refcount + excluded.refcount",
+        )
+        .bind(utils::get_current_timestamp() as i64)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE Files SET blob = hash, path = (SELECT path FROM Blobs WHERE Blobs.hash = Files.hash) WHERE blob = '' AND hash != '-'")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await.map_err(|e| {
+            eprintln!("[ERROR] Database 'FileDB' failed to migrate files into blobs: {}", e);
+            e
+        })?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS ShareLinks (
+              id TEXT PRIMARY KEY,
+              file_uuid TEXT NOT NULL,
+              label TEXT NOT NULL,
+              created INTEGER NOT NULL,
+              expires_at INTEGER NOT NULL,
+              max_downloads INTEGER NOT NULL,
+              access_count INTEGER NOT NULL
+            );",
+        ).execute(&pool)
+        .await
+          .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'FileDB' failed to create table 'ShareLinks': {}",
+                e
+            );
+            e
+        })?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS UploadLog (
+              id INTEGER PRIMARY KEY,
+              owner INTEGER NOT NULL,
+              size INTEGER NOT NULL,
+              created INTEGER NOT NULL
+            );",
+        ).execute(&pool)
+        .await
+          .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'FileDB' failed to create table 'UploadLog': {}",
+                e
+            );
+            e
+        })?;
+
         Ok(Self {
-            path: sqlite_path,
+            path,
             pool,
+            blob_lock: Mutex::new(()),
         })
     }
 
-    pub async fn add_from_request(&self, uuid: &str, file_name: String, file_size: u64, expires_at: u64) -> Result<(), sqlx::Error> {
+    pub async fn add_from_request(&self, uuid: &str, request: FileRequest) -> Result<(), sqlx::Error> {
         let state = state::State::get().await.map_err(|_| sqlx::Error::WorkerCrashed)?;
 
         let path = format!("{}{}", state.config.upload.upload_location, uuid);
 
-        sqlx::query("INSERT INTO Files (uuid, path, hash, name, size, created, expires_at, access_count) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+        sqlx::query("INSERT INTO Files (uuid, path, hash, name, size, created, expires_at, access_count, declared_hash, owner, encrypted, password_hash, max_downloads) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
             .bind(uuid)
             .bind(path)
             .bind("-")
-            .bind(file_name)
-            .bind(file_size as i64)
+            .bind(request.name)
+            .bind(request.size as i64)
             .bind(utils::get_current_timestamp() as i64)
-            .bind(expires_at as i64)
+            .bind(request.expires_at as i64)
             .bind(0)
+            .bind(request.declared_hash)
+            .bind(request.owner)
+            .bind(request.encrypted)
+            .bind(request.password_hash)
+            .bind(request.max_downloads)
             .execute(&self.pool)
             .await
             .map(|_| ())
@@ -83,12 +181,15 @@ impl FileDB {
             .await
     }
 
-    pub async fn get_by_hash(&self, hash: &str) -> Result<Option<File>, sqlx::Error> {
+    /// Only files the same user uploaded are returned, so a bare hash
+    /// does not reveal whether someone else stored that content.
+    pub async fn get_by_hash_for_owner(&self, hash: &str, owner: u16) -> Result<Option<File>, sqlx::Error> {
         if hash == "-" {
             return Ok(None);
         }
-        sqlx::query_as::<_, File>("SELECT * FROM Files WHERE hash = ?")
+        sqlx::query_as::<_, File>("SELECT * FROM Files WHERE hash = ? AND owner = ?")
             .bind(hash)
+            .bind(owner)
             .fetch_optional(&self.pool)
             .await
     }
@@ -108,22 +209,117 @@ impl FileDB {
             .map(|_| ())
     }
 
-    pub async fn increment_access_count(&self, uuid: &str) -> Result<(), sqlx::Error> {
-        sqlx::query("UPDATE Files SET access_count = access_count + 1 WHERE uuid = ?")
+    pub async fn get_by_owner(
+        &self,
+        owner: u16,
+        sort: FileSort,
+        descending: bool,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<File>, sqlx::Error> {
+        let query = format!(
+            "SELECT * FROM Files WHERE owner = ? ORDER BY {} {}, id {} LIMIT ? OFFSET ?",
+            sort.as_order_by(),
+            if descending { "DESC" } else { "ASC" },
+            if descending { "DESC" } else { "ASC" },
+        );
+
+        sqlx::query_as::<_, File>(&query)
+            .bind(owner)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn count_by_owner(&self, owner: u16) -> Result<i64, sqlx::Error> {
+        sqlx::query("SELECT COUNT(*) FROM Files WHERE owner = ?")
+            .bind(owner)
+            .fetch_one(&self.pool)
+            .await
+            .map(|row| row.get(0))
+    }
+
+    /// Total size and number of the owner's files, unfinished uploads included.
+    pub async fn get_usage(&self, owner: u16) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query("SELECT COALESCE(SUM(size), 0), COUNT(*) FROM Files WHERE owner = ?")
+            .bind(owner)
+            .fetch_one(&self.pool)
+            .await
+            .map(|row| (row.get(0), row.get(1)))
+    }
+
+    pub async fn rename(&self, uuid: &str, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Files SET name = ? WHERE uuid = ?")
+            .bind(name)
             .bind(uuid)
             .execute(&self.pool)
             .await
             .map(|_| ())
     }
 
-    pub async fn remove_by_uuid(&self, uuid: &str) -> Result<(), sqlx::Error> {
-        sqlx::query("DELETE FROM Files WHERE uuid = ?")
+    pub async fn set_expires_at(&self, uuid: &str, expires_at: u64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Files SET expires_at = ? WHERE uuid = ?")
+            .bind(expires_at as i64)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    /// Counts a download of a file with `max_downloads`, in a single statement so
+    /// concurrent downloads can't exceed the limit. `None` if no download is left,
+    /// otherwise whether this was the last one.
+    pub async fn claim_download(&self, uuid: &str) -> Result<Option<bool>, sqlx::Error> {
+        sqlx::query("UPDATE Files SET access_count = access_count + 1 WHERE uuid = ? AND access_count < max_downloads RETURNING access_count >= max_downloads")
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|row| row.map(|row| row.get(0)))
+    }
+
+    /// Gives back a download claimed by [`FileDB::claim_download`] that did not finish.
+    pub async fn release_download(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Files SET access_count = access_count - 1 WHERE uuid = ? AND access_count > 0")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn increment_access_count(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Files SET access_count = access_count + 1 WHERE uuid = ?")
             .bind(uuid)
             .execute(&self.pool)
             .await
             .map(|_| ())
     }
 
+    /// Removes the file and drops its reference to the blob.
+    pub async fn remove_by_uuid(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let blob: Option<String> = sqlx::query("DELETE FROM Files WHERE uuid = ? RETURNING blob")
+            .bind(uuid)
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|row| row.get(0));
+
+        if let Some(blob) = blob.filter(|blob| !blob.is_empty()) {
+            sqlx::query("UPDATE Blobs SET refcount = refcount - 1 WHERE hash = ?")
+                .bind(blob)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM ShareLinks WHERE file_uuid = ?")
+            .bind(uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
     pub async fn get_expired_files(&self) -> Result<Vec<File>, sqlx::Error> {
         sqlx::query_as::<_, File>("SELECT * FROM Files WHERE expires_at < ? AND expires_at <> 0")
             .bind(utils::get_current_timestamp() as i64)
@@ -132,13 +328,242 @@ impl FileDB {
     }
 
     pub async fn get_paths(&self) -> Result<Vec<String>, sqlx::Error> {
-        sqlx::query("SELECT path FROM Files")
+        sqlx::query("SELECT path FROM Files UNION SELECT path FROM Blobs")
             .fetch_all(&self.pool)
             .await
             .map(|rows| rows.into_iter().map(|row| row.get(0)).collect())
     }
 }
 
+// MARK: Blobs
+impl FileDB {
+    pub async fn lock_blobs(&self) -> MutexGuard<'_, ()> {
+        self.blob_lock.lock().await
+    }
+
+    pub async fn get_blob(&self, hash: &str) -> Result<Option<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>("SELECT * FROM Blobs WHERE hash = ?")
+            .bind(hash)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Points the file `uuid` at the blob `hash`, creating the blob at `path` if it is new.
+    pub async fn attach_blob(&self, uuid: &str, hash: &str, path: &str, size: i64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("INSERT INTO Blobs (hash, path, size, refcount, created) VALUES (?, ?, ?, 1, ?) ON CONFLICT(hash) DO UPDATE SET refcount = refcount + 1")
+            .bind(hash)
+            .bind(path)
+            .bind(size)
+            .bind(utils::get_current_timestamp() as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("UPDATE Files SET blob = ?, hash = ?, size = ?, path = (SELECT path FROM Blobs WHERE hash = ?) WHERE uuid = ?")
+            .bind(hash)
+            .bind(hash)
+            .bind(size)
+            .bind(hash)
+            .bind(uuid)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    /// Adds a finished file referencing an existing blob, no data has to be uploaded.
+    /// Size and hash are taken from the blob.
+    pub async fn add_from_blob(&self, uuid: &str, request: &FileRequest, blob: &Blob) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let updated = sqlx::query("UPDATE Blobs SET refcount = refcount + 1 WHERE hash = ? AND refcount > 0")
+            .bind(&blob.hash)
+            .execute(&mut *tx)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query("INSERT INTO Files (uuid, path, hash, name, size, created, expires_at, access_count, declared_hash, owner, blob, encrypted, password_hash, max_downloads) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(uuid)
+            .bind(&blob.path)
+            .bind(&blob.hash)
+            .bind(&request.name)
+            .bind(blob.size)
+            .bind(utils::get_current_timestamp() as i64)
+            .bind(request.expires_at as i64)
+            .bind(0)
+            .bind(&blob.hash)
+            .bind(request.owner)
+            .bind(&blob.hash)
+            .bind(request.encrypted)
+            .bind(&request.password_hash)
+            .bind(request.max_downloads)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn get_unreferenced_blobs(&self) -> Result<Vec<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>("SELECT * FROM Blobs WHERE refcount <= 0")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Removes the blob row unless it gained a reference in the meantime.
+    pub async fn remove_blob(&self, hash: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query("DELETE FROM Blobs WHERE hash = ? AND refcount <= 0")
+            .bind(hash)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() > 0)
+    }
+
+    /// Removes the blob once no file references it anymore and returns it, so its
+    /// data can be deleted. Call with [`FileDB::lock_blobs`] held.
+    pub async fn take_unreferenced_blob(&self, hash: &str) -> Result<Option<Blob>, sqlx::Error> {
+        match self.get_blob(hash).await? {
+            Some(blob) if blob.refcount <= 0 && self.remove_blob(&blob.hash).await? => Ok(Some(blob)),
+            _ => Ok(None),
+        }
+    }
+}
+
+// MARK: Share Links
+impl FileDB {
+    pub async fn add_share_link(&self, id: &str, file_uuid: &str, label: &str, expires_at: u64, max_downloads: u32) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO ShareLinks (id, file_uuid, label, created, expires_at, max_downloads, access_count) VALUES (?, ?, ?, ?, ?, ?, 0)")
+            .bind(id)
+            .bind(file_uuid)
+            .bind(label)
+            .bind(utils::get_current_timestamp() as i64)
+            .bind(expires_at as i64)
+            .bind(max_downloads)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_share_link(&self, id: &str) -> Result<Option<ShareLink>, sqlx::Error> {
+        sqlx::query_as::<_, ShareLink>("SELECT * FROM ShareLinks WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn get_share_links(&self, file_uuid: &str) -> Result<Vec<ShareLink>, sqlx::Error> {
+        sqlx::query_as::<_, ShareLink>("SELECT * FROM ShareLinks WHERE file_uuid = ? ORDER BY created")
+            .bind(file_uuid)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// `false` if the file has no such link.
+    pub async fn remove_share_link(&self, file_uuid: &str, id: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query("DELETE FROM ShareLinks WHERE id = ? AND file_uuid = ?")
+            .bind(id)
+            .bind(file_uuid)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() > 0)
+    }
+
+    /// Counts a download through the link like [`FileDB::claim_download`],
+    /// `None` if the link expired or has no download left.
+    pub async fn claim_share_link(&self, id: &str) -> Result<Option<bool>, sqlx::Error> {
+        sqlx::query("UPDATE ShareLinks SET access_count = access_count + 1 WHERE id = ? AND (max_downloads = 0 OR access_count < max_downloads) AND (expires_at = 0 OR expires_at > ?) RETURNING max_downloads > 0 AND access_count >= max_downloads")
+            .bind(id)
+            .bind(utils::get_current_timestamp() as i64)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|row| row.map(|row| row.get(0)))
+    }
+
+    pub async fn release_share_link(&self, id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE ShareLinks SET access_count = access_count - 1 WHERE id = ? AND access_count > 0")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_expired_share_links(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM ShareLinks WHERE expires_at < ? AND expires_at <> 0")
+            .bind(utils::get_current_timestamp() as i64)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    // MARK: Upload Log
+    /// Records stored bytes for daily quotas, entries outlive the files they belong to.
+    pub async fn log_upload(&self, owner: u16, size: u64) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO UploadLog (owner, size, created) VALUES (?, ?, ?)")
+            .bind(owner)
+            .bind(size as i64)
+            .bind(utils::get_current_timestamp() as i64)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn uploaded_since(&self, owner: u16, since: u64) -> Result<i64, sqlx::Error> {
+        sqlx::query("SELECT COALESCE(SUM(size), 0) FROM UploadLog WHERE owner = ? AND created >= ?")
+            .bind(owner)
+            .bind(since as i64)
+            .fetch_one(&self.pool)
+            .await
+            .map(|row| row.get(0))
+    }
+
+    pub async fn remove_upload_log_before(&self, before: u64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM UploadLog WHERE created < ?")
+            .bind(before as i64)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// An extra link to a file with its own expiry and download limit.
+#[derive(sqlx::FromRow)]
+pub struct ShareLink {
+    pub id: String,
+    pub file_uuid: String,
+    pub label: String,
+    pub created: i64,
+    /// 0 if the link does not expire.
+    pub expires_at: i64,
+    /// 0 for no limit.
+    pub max_downloads: i64,
+    pub access_count: i64,
+}
+
+/// What an upload request fixes about the file before any data arrived.
+pub struct FileRequest {
+    pub name: String,
+    pub size: u64,
+    pub declared_hash: String,
+    pub expires_at: u64,
+    pub owner: u16,
+    pub encrypted: bool,
+    /// As produced by [`utils::hash_password`], empty for files without a password.
+    pub password_hash: String,
+    /// 0 allows unlimited downloads.
+    pub max_downloads: u32,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Blob {
+    pub hash: String,
+    pub path: String,
+    pub size: i64,
+    pub refcount: i64,
+    pub created: String,
+}
+
 #[derive(sqlx::FromRow)]
 pub struct File {
     pub id: i64,
@@ -150,6 +575,36 @@ pub struct File {
     pub created: String,
     pub expires_at: String,
     pub access_count: i64,
+    pub declared_hash: String,
+    pub owner: u16,
+    /// Hash of the blob holding the content, empty for files stored on their own.
+    pub blob: String,
+    /// Encrypted in the browser, served through the decrypt page.
+    pub encrypted: bool,
+    /// Argon2 hash of the password protecting downloads, empty if there is none.
+    pub password_hash: String,
+    /// The file is deleted after this many downloads, 0 for no limit.
+    pub max_downloads: i64,
+}
+
+#[derive(FromFormField, Clone, Copy)]
+pub enum FileSort {
+    #[field(value = "size")]
+    Size,
+    #[field(value = "created")]
+    Created,
+    #[field(value = "access_count")]
+    AccessCount,
+}
+
+impl FileSort {
+    pub fn as_order_by(&self) -> &str {
+        match self {
+            FileSort::Size => "size",
+            FileSort::Created => "CAST(created AS INTEGER)",
+            FileSort::AccessCount => "access_count",
+        }
+    }
 }
 
 #[derive(Serialize, Clone, PartialEq)]
@@ -159,6 +614,7 @@ pub enum FileState {
     Finishing,
     Error,
     UploadCancelled,
+    Incomplete,
 }
 
 impl FileState {
@@ -169,6 +625,7 @@ impl FileState {
             FileState::Finishing => 2,
             FileState::Error => 3,
             FileState::UploadCancelled => 4,
+            FileState::Incomplete => 5,
         }
     }
 
@@ -179,7 +636,142 @@ impl FileState {
             2 => FileState::Finishing,
             3 => FileState::Error,
             4 => FileState::UploadCancelled,
+            5 => FileState::Incomplete,
             _ => FileState::AwaitingData,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// A single connection, so every query sees the same in-memory database.
+    async fn memory_db() -> FileDB {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        FileDB::open(pool, String::from("sqlite::memory:")).await.unwrap()
+    }
+
+    async fn add_file(db: &FileDB, uuid: &str) {
+        sqlx::query("INSERT INTO Files (uuid, path, hash, name, size, created, expires_at, access_count) VALUES (?, '', '-', 'test', 0, 0, 0, 0)")
+            .bind(uuid)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    fn request() -> FileRequest {
+        FileRequest {
+            name: String::from("copy"),
+            size: 4,
+            declared_hash: String::from("-"),
+            expires_at: 0,
+            owner: 1,
+            encrypted: false,
+            password_hash: String::new(),
+            max_downloads: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn blob_refcount_follows_files() {
+        let db = memory_db().await;
+        add_file(&db, "first").await;
+        db.attach_blob("first", "abcd", "blobs/abcd", 4).await.unwrap();
+
+        let blob = db.get_blob("abcd").await.unwrap().unwrap();
+        assert_eq!(blob.refcount, 1);
+        db.add_from_blob("second", &request(), &blob).await.unwrap();
+        assert_eq!(db.get_blob("abcd").await.unwrap().unwrap().refcount, 2);
+
+        let copy = db.get_by_uuid("second").await.unwrap().unwrap();
+        assert_eq!(copy.path, "blobs/abcd");
+        assert_eq!(copy.size, 4);
+
+        db.remove_by_uuid("first").await.unwrap();
+        assert_eq!(db.get_blob("abcd").await.unwrap().unwrap().refcount, 1);
+    }
+
+    #[tokio::test]
+    async fn blob_is_only_removed_without_references() {
+        let db = memory_db().await;
+        add_file(&db, "first").await;
+        db.attach_blob("first", "abcd", "blobs/abcd", 4).await.unwrap();
+        let blob = db.get_blob("abcd").await.unwrap().unwrap();
+        db.add_from_blob("second", &request(), &blob).await.unwrap();
+
+        db.remove_by_uuid("first").await.unwrap();
+        assert!(!db.remove_blob("abcd").await.unwrap());
+        assert!(db.take_unreferenced_blob("abcd").await.unwrap().is_none());
+        assert!(db.get_unreferenced_blobs().await.unwrap().is_empty());
+        assert!(db.get_blob("abcd").await.unwrap().is_some());
+
+        db.remove_by_uuid("second").await.unwrap();
+        let removed = db.take_unreferenced_blob("abcd").await.unwrap().unwrap();
+        assert_eq!(removed.path, "blobs/abcd");
+        assert!(db.get_blob("abcd").await.unwrap().is_none());
+        assert!(db.take_unreferenced_blob("abcd").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unreferenced_blob_takes_no_new_files() {
+        let db = memory_db().await;
+        add_file(&db, "first").await;
+        db.attach_blob("first", "abcd", "blobs/abcd", 4).await.unwrap();
+        let blob = db.get_blob("abcd").await.unwrap().unwrap();
+
+        db.remove_by_uuid("first").await.unwrap();
+        assert_eq!(db.get_unreferenced_blobs().await.unwrap().len(), 1);
+        assert!(matches!(
+            db.add_from_blob("second", &request(), &blob).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(db.get_by_uuid("second").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_claims_take_the_last_download_once() {
+        let db = memory_db().await;
+        add_file(&db, "limited").await;
+        sqlx::query("UPDATE Files SET max_downloads = 1 WHERE uuid = 'limited'")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(db.claim_download("limited"), db.claim_download("limited"));
+        let mut claims = vec![first.unwrap(), second.unwrap()];
+        claims.sort();
+        assert_eq!(claims, vec![None, Some(true)]);
+        assert_eq!(db.get_by_uuid("limited").await.unwrap().unwrap().access_count, 1);
+
+        db.release_download("limited").await.unwrap();
+        assert_eq!(db.get_by_uuid("limited").await.unwrap().unwrap().access_count, 0);
+        assert_eq!(db.claim_download("limited").await.unwrap(), Some(true));
+        assert_eq!(db.claim_download("limited").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn claims_count_down_to_the_last_download() {
+        let db = memory_db().await;
+        add_file(&db, "limited").await;
+        sqlx::query("UPDATE Files SET max_downloads = 2 WHERE uuid = 'limited'")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        assert_eq!(db.claim_download("limited").await.unwrap(), Some(false));
+        assert_eq!(db.claim_download("limited").await.unwrap(), Some(true));
+        assert_eq!(db.claim_download("limited").await.unwrap(), None);
+
+        db.release_download("limited").await.unwrap();
+        db.release_download("limited").await.unwrap();
+        db.release_download("limited").await.unwrap();
+        assert_eq!(db.get_by_uuid("limited").await.unwrap().unwrap().access_count, 0);
+    }
+}
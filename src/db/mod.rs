@@ -1,3 +1,34 @@
+use sqlx::{Row, SqlitePool};
+
 pub mod file;
 pub mod user;
 pub mod video;
+
+/// Adds a column to an existing table, so databases created by older
+/// versions pick up new fields without being recreated.
+pub async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(pool)
+        .await?;
+
+    if columns
+        .iter()
+        .any(|row| row.get::<String, _>("name") == column)
+    {
+        return Ok(());
+    }
+
+    println!("[DEBUG ] Adding column '{}' to table '{}'", column, table);
+    sqlx::query(&format!(
+        "ALTER TABLE {} ADD COLUMN {} {}",
+        table, column, definition
+    ))
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
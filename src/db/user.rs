@@ -1,9 +1,11 @@
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
-use sqlx::{migrate::MigrateDatabase, sqlite::SqliteQueryResult, Sqlite, SqlitePool};
+use sqlx::{migrate::MigrateDatabase, sqlite::SqliteQueryResult, Row, Sqlite, SqlitePool};
 
-use crate::{state, utils};
+use crate::{db, state, utils};
+
+pub const API_KEY_PREFIX: &str = "rist_";
 
 pub struct UserDB {
     pub path: String,
@@ -33,7 +35,8 @@ impl UserDB {
               id INTEGER PRIMARY KEY,
               name TEXT NOT NULL UNIQUE,
               kind INTEGER NOT NULL,
-              token TEXT NOT NULL
+              token TEXT NOT NULL,
+              revoked INTEGER NOT NULL DEFAULT 0
             );",
         )
         .execute(&pool)
@@ -46,8 +49,62 @@ impl UserDB {
             e
         })?;
 
-        // Delete all users; Users are synced via config
-        sqlx::query("DELETE FROM Users").execute(&pool).await?;
+        db::add_column_if_missing(&pool, "Users", "revoked", "INTEGER NOT NULL DEFAULT 0").await?;
+        db::add_column_if_missing(&pool, "Users", "from_config", "INTEGER NOT NULL DEFAULT 0").await?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS Sessions (
+              id INTEGER PRIMARY KEY,
+              session_hash TEXT NOT NULL UNIQUE,
+              user INTEGER NOT NULL,
+              created INTEGER NOT NULL,
+              expires_at INTEGER NOT NULL
+            );",
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'UserDB' failed to create table 'Sessions': {}",
+                e
+            );
+            e
+        })?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS ApiKeys (
+              id INTEGER PRIMARY KEY,
+              user INTEGER NOT NULL,
+              name TEXT NOT NULL,
+              prefix TEXT NOT NULL,
+              key_hash TEXT NOT NULL UNIQUE,
+              scopes TEXT NOT NULL,
+              created INTEGER NOT NULL,
+              expires_at INTEGER NOT NULL,
+              last_used INTEGER NOT NULL
+            );",
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'UserDB' failed to create table 'ApiKeys': {}",
+                e
+            );
+            e
+        })?;
+
+        // Hash tokens stored in plaintext by older versions
+        let plain_tokens = sqlx::query("SELECT id, token FROM Users WHERE token NOT LIKE 'sha256$%'")
+            .fetch_all(&pool)
+            .await?;
+        for row in plain_tokens {
+            sqlx::query("UPDATE Users SET token = ? WHERE id = ?")
+                .bind(utils::hash_token(row.get::<String, _>(1).as_str()))
+                .bind(row.get::<i64, _>(0))
+                .execute(&pool)
+                .await?;
+        }
 
         Ok(Self {
             path: sqlite_path,
@@ -64,28 +121,258 @@ impl UserDB {
         sqlx::query(r"INSERT INTO Users (name, kind, token) VALUES (?, ?, ?)")
             .bind(name)
             .bind(kind as i32)
-            .bind(token)
+            .bind(utils::hash_token(token))
             .execute(&self.pool)
             .await
     }
 
+    /// Upserts config accounts by name, so ids stay stable across restarts.
+    /// The config stays authoritative for the name, kind and token of its accounts,
+    /// which is why the API can't change them.
     pub async fn sync_with_config(&self) -> Result<(), Box<dyn std::error::Error>> {
         let state = state::State::get().await.unwrap();
 
+        // Accounts removed from the config are revoked along with their credentials
+        let config_users = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE from_config = 1")
+            .fetch_all(&self.pool)
+            .await?;
+        for user in config_users {
+            if state.config.accounts.user.iter().any(|u| u.name == user.name) {
+                continue;
+            }
+
+            self.set_revoked(user.id, true).await?;
+            self.remove_sessions_for_user(user.id).await?;
+            self.remove_api_keys_for_user(user.id).await?;
+        }
+
         for user in state.config.accounts.user.iter() {
-            self.insert(&user.name, user.kind.clone(), &user.token)
-                .await?;
+            sqlx::query(
+                r"INSERT INTO Users (name, kind, token, from_config) VALUES (?, ?, ?, 1)
+                ON CONFLICT(name) DO UPDATE SET kind = excluded.kind, token = excluded.token, from_config = 1, revoked = 0",
+            )
+            .bind(&user.name)
+            .bind(user.kind.clone() as i32)
+            .bind(utils::hash_token(&user.token))
+            .execute(&self.pool)
+            .await?;
         }
 
         Ok(())
     }
 
+    /// Tokens are only stored salted, so every active user is checked.
     pub async fn get(&self, token: &str) -> Result<Option<User>, sqlx::Error> {
-        sqlx::query_as::<_, User>("SELECT * FROM Users WHERE token = ?")
-            .bind(token)
+        let users = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE revoked = 0")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(users
+            .into_iter()
+            .find(|user| utils::verify_token(token, &user.token)))
+    }
+
+    pub async fn get_by_id(&self, id: u16) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>("SELECT * FROM Users WHERE id = ?")
+            .bind(id)
             .fetch_optional(&self.pool)
             .await
     }
+
+    pub async fn get_all(&self) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>("SELECT * FROM Users ORDER BY id")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn update(&self, id: u16, name: &str, kind: UserKind) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Users SET name = ?, kind = ? WHERE id = ?")
+            .bind(name)
+            .bind(kind as i32)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn set_token(&self, id: u16, token: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Users SET token = ? WHERE id = ?")
+            .bind(utils::hash_token(token))
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    /// Revoked users are kept so the ids referenced by files and videos stay valid.
+    pub async fn set_revoked(&self, id: u16, revoked: bool) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Users SET revoked = ? WHERE id = ?")
+            .bind(revoked)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    // MARK: Sessions
+
+    /// Creates a session and returns its id; only a hash of it is stored.
+    pub async fn create_session(&self, user: u16, ttl_secs: u64) -> Result<String, sqlx::Error> {
+        let session_id = utils::generate_token();
+        let now = utils::get_current_timestamp();
+
+        sqlx::query("INSERT INTO Sessions (session_hash, user, created, expires_at) VALUES (?, ?, ?, ?)")
+            .bind(utils::sha256_hex(&session_id))
+            .bind(user)
+            .bind(now as i64)
+            .bind((now + ttl_secs) as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(session_id)
+    }
+
+    pub async fn get_by_session(&self, session_id: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            r"SELECT Users.* FROM Sessions
+            JOIN Users ON Users.id = Sessions.user
+            WHERE Sessions.session_hash = ? AND Sessions.expires_at > ? AND Users.revoked = 0",
+        )
+        .bind(utils::sha256_hex(session_id))
+        .bind(utils::get_current_timestamp() as i64)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn remove_session(&self, session_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM Sessions WHERE session_hash = ?")
+            .bind(utils::sha256_hex(session_id))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_sessions_for_user(&self, user: u16) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM Sessions WHERE user = ?")
+            .bind(user)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_expired_sessions(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM Sessions WHERE expires_at <= ?")
+            .bind(utils::get_current_timestamp() as i64)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    // MARK: API keys
+
+    /// Creates an API key and returns the key; only a hash of it is stored.
+    pub async fn create_api_key(
+        &self,
+        user: u16,
+        name: &str,
+        scopes: &[PermissionKind],
+        expires_at: u64,
+    ) -> Result<(ApiKey, String), sqlx::Error> {
+        let key = format!("{}{}", API_KEY_PREFIX, utils::generate_token());
+        let prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+        let scopes = scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let now = utils::get_current_timestamp() as i64;
+
+        let result = sqlx::query(
+            r"INSERT INTO ApiKeys (user, name, prefix, key_hash, scopes, created, expires_at, last_used)
+            VALUES (?, ?, ?, ?, ?, ?, ?, 0)",
+        )
+        .bind(user)
+        .bind(name)
+        .bind(&prefix)
+        .bind(utils::sha256_hex(&key))
+        .bind(&scopes)
+        .bind(now)
+        .bind(expires_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        let api_key = ApiKey {
+            id: result.last_insert_rowid(),
+            user,
+            name: name.to_string(),
+            prefix,
+            key_hash: String::new(),
+            scopes,
+            created: now,
+            expires_at: expires_at as i64,
+            last_used: 0,
+        };
+
+        Ok((api_key, key))
+    }
+
+    /// Returns the owner of a valid API key and marks the key as used.
+    pub async fn get_by_api_key(&self, key: &str) -> Result<Option<(User, ApiKey)>, sqlx::Error> {
+        let now = utils::get_current_timestamp() as i64;
+        let api_key = match sqlx::query_as::<_, ApiKey>(
+            "SELECT * FROM ApiKeys WHERE key_hash = ? AND (expires_at = 0 OR expires_at > ?)",
+        )
+        .bind(utils::sha256_hex(key))
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?
+        {
+            Some(api_key) => api_key,
+            None => return Ok(None),
+        };
+
+        let user = match sqlx::query_as::<_, User>("SELECT * FROM Users WHERE id = ? AND revoked = 0")
+            .bind(api_key.user)
+            .fetch_optional(&self.pool)
+            .await?
+        {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        sqlx::query("UPDATE ApiKeys SET last_used = ? WHERE id = ?")
+            .bind(now)
+            .bind(api_key.id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(Some((user, api_key)))
+    }
+
+    pub async fn get_api_keys(&self, user: u16) -> Result<Vec<ApiKey>, sqlx::Error> {
+        sqlx::query_as::<_, ApiKey>("SELECT * FROM ApiKeys WHERE user = ? ORDER BY id")
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Returns `false` when the user has no key with this id.
+    pub async fn remove_api_key(&self, user: u16, id: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query("DELETE FROM ApiKeys WHERE id = ? AND user = ?")
+            .bind(id)
+            .bind(user)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() > 0)
+    }
+
+    pub async fn remove_api_keys_for_user(&self, user: u16) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM ApiKeys WHERE user = ?")
+            .bind(user)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -128,6 +415,16 @@ impl UserKind {
         }
     }
 
+    pub fn as_str(&self) -> &str {
+        match self {
+            UserKind::Admin => "admin",
+            UserKind::User => "user",
+            UserKind::Guest => "guest",
+            UserKind::YtOnly => "yt_only",
+            UserKind::FileOnly => "file_only",
+        }
+    }
+
     pub fn from_str(kind: &str) -> Self {
         match kind {
             "admin" => UserKind::Admin,
@@ -145,7 +442,11 @@ pub struct User {
     pub id: u16,
     pub name: String,
     pub kind: u8,
+    /// Salted token hash, see [`utils::hash_token`]
     pub token: String,
+    pub revoked: bool,
+    /// Defined in the config, which owns its name, kind and token
+    pub from_config: bool,
 }
 
 impl User {
@@ -156,7 +457,11 @@ impl User {
     pub fn has_permissions_to(&self, kind: PermissionKind) -> bool {
         match self.kind() {
             UserKind::Admin => true,
-            UserKind::User => kind != PermissionKind::FileRemove,
+            UserKind::User => {
+                kind != PermissionKind::FileRemove
+                    && kind != PermissionKind::FileManage
+                    && kind != PermissionKind::UserManage
+            }
             UserKind::Guest => false,
             UserKind::YtOnly => kind == PermissionKind::YoutubeDownload || kind == PermissionKind::MedalDownload,
             UserKind::FileOnly => kind == PermissionKind::FileUpload,
@@ -164,10 +469,83 @@ impl User {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum PermissionKind {
+    #[serde(rename = "file_upload")]
     FileUpload,
+
+    #[serde(rename = "file_remove")]
     FileRemove,
+
+    /// Acting on files of other users.
+    #[serde(rename = "file_manage")]
+    FileManage,
+
+    #[serde(rename = "youtube_download")]
     YoutubeDownload,
+
+    #[serde(rename = "medal_download")]
     MedalDownload,
+
+    #[serde(rename = "user_manage")]
+    UserManage,
+}
+
+impl PermissionKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            PermissionKind::FileUpload => "file_upload",
+            PermissionKind::FileRemove => "file_remove",
+            PermissionKind::FileManage => "file_manage",
+            PermissionKind::YoutubeDownload => "youtube_download",
+            PermissionKind::MedalDownload => "medal_download",
+            PermissionKind::UserManage => "user_manage",
+        }
+    }
+
+    pub fn from_str(kind: &str) -> Option<Self> {
+        match kind {
+            "file_upload" => Some(PermissionKind::FileUpload),
+            "file_remove" => Some(PermissionKind::FileRemove),
+            "file_manage" => Some(PermissionKind::FileManage),
+            "youtube_download" => Some(PermissionKind::YoutubeDownload),
+            "medal_download" => Some(PermissionKind::MedalDownload),
+            "user_manage" => Some(PermissionKind::UserManage),
+            _ => None,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user: u16,
+    pub name: String,
+    /// First characters of the key, shown so users can tell keys apart
+    pub prefix: String,
+    pub key_hash: String,
+    /// Comma separated [`PermissionKind`] names, empty allows everything the user can do
+    pub scopes: String,
+    pub created: i64,
+    pub expires_at: i64,
+    pub last_used: i64,
+}
+
+impl ApiKey {
+    pub fn scopes(&self) -> Option<Vec<PermissionKind>> {
+        if self.scopes.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.scopes
+                .split(',')
+                .filter_map(PermissionKind::from_str)
+                .collect(),
+        )
+    }
+
+    pub fn allows(&self, kind: PermissionKind) -> bool {
+        self.scopes().map_or(true, |scopes| scopes.contains(&kind))
+    }
 }
@@ -1,9 +1,9 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::Row;
 use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
 use std::path::Path;
 
-use crate::utils;
+use crate::{db, utils, yt_dlp::PlaylistEntry};
 
 pub struct VideoDB {
     pub path: String,
@@ -52,6 +52,34 @@ impl VideoDB {
             e
         })?;
 
+        db::add_column_if_missing(&pool, "Videos", "url", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "extractor", "TEXT NOT NULL DEFAULT 'Youtube'").await?;
+        db::add_column_if_missing(&pool, "Videos", "batch", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "format_id", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "container", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "section", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "options", "TEXT NOT NULL DEFAULT ''").await?;
+        db::add_column_if_missing(&pool, "Videos", "size", "INTEGER NOT NULL DEFAULT 0").await?;
+
+        sqlx::query(
+            r"CREATE TABLE IF NOT EXISTS Sidecars (
+            id INTEGER PRIMARY KEY,
+            video TEXT NOT NULL,
+            kind INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            path TEXT NOT NULL
+          );",
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            eprintln!(
+                "[ERROR] Database 'VideoDB' failed to create table 'Sidecars': {}",
+                e
+            );
+            e
+        })?;
+
         Ok(Self {
             path: sqlite_path,
             pool,
@@ -88,7 +116,7 @@ impl VideoDB {
 
     pub async fn add(&self, video: &Video) -> Result<sqlx::sqlite::SqliteQueryResult, sqlx::Error> {
         sqlx::query(
-            r"INSERT INTO Videos (uuid, vid_id, name, format, quality, path, created, expires_at, user) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            r"INSERT INTO Videos (uuid, vid_id, name, format, quality, path, created, expires_at, user, url, extractor, batch, format_id, container, section, options) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )
         .bind(video.uuid.clone())
         .bind(video.vid_id.clone())
@@ -99,6 +127,13 @@ impl VideoDB {
         .bind(video.created.clone())
         .bind(video.expires_at.clone())
         .bind(video.user)
+        .bind(video.url.clone())
+        .bind(video.extractor.clone())
+        .bind(video.batch.clone())
+        .bind(video.format_id.clone())
+        .bind(video.container.clone())
+        .bind(video.section.clone())
+        .bind(video.options.clone())
         .execute(&self.pool)
         .await
     }
@@ -110,6 +145,42 @@ impl VideoDB {
             .await
     }
 
+    /// Finished downloads of the same media in the same format and quality, newest first.
+    pub async fn get_cached(&self, video: &Video) -> Result<Vec<Video>, sqlx::Error> {
+        sqlx::query_as::<_, Video>(
+            r"SELECT * FROM Videos
+            WHERE extractor = ? AND vid_id = ? AND format = ? AND quality = ?
+            AND format_id = ? AND container = ? AND section = ? AND options = ? AND path <> ''
+            AND (CAST(expires_at AS INTEGER) = 0 OR CAST(expires_at AS INTEGER) > ?)
+            ORDER BY CAST(created AS INTEGER) DESC",
+        )
+        .bind(&video.extractor)
+        .bind(&video.vid_id)
+        .bind(video.format)
+        .bind(video.quality)
+        .bind(&video.format_id)
+        .bind(&video.container)
+        .bind(&video.section)
+        .bind(&video.options)
+        .bind(utils::get_current_timestamp() as i64)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_by_batch(&self, batch: &str) -> Result<Vec<Video>, sqlx::Error> {
+        sqlx::query_as::<_, Video>("SELECT * FROM Videos WHERE batch = ? ORDER BY id")
+            .bind(batch)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn get_batches(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query("SELECT DISTINCT batch FROM Videos WHERE batch <> ''")
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
     pub async fn get_expired_videos(&self) -> Result<Vec<Video>, sqlx::Error> {
         sqlx::query_as::<_, Video>("SELECT * FROM Videos WHERE expires_at < ? AND expires_at <> 0")
             .bind(utils::get_current_timestamp() as i64)
@@ -118,6 +189,8 @@ impl VideoDB {
     }
 
     pub async fn remove_by_uuid(&self, uuid: &str) -> Result<(), sqlx::Error> {
+        self.remove_sidecars(uuid).await?;
+
         sqlx::query("DELETE FROM Videos WHERE uuid = ?")
             .bind(uuid)
             .execute(&self.pool)
@@ -125,13 +198,73 @@ impl VideoDB {
             .map(|_| ())
     }
 
+    /// Counts `size` more stored bytes for the video, its sidecars included.
+    pub async fn add_size(&self, uuid: &str, size: u64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE Videos SET size = size + ? WHERE uuid = ?")
+            .bind(size as i64)
+            .bind(uuid)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    /// Total size and number of the user's stored videos and clips.
+    pub async fn get_usage(&self, user: u16) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query("SELECT COALESCE(SUM(size), 0), COUNT(*) FROM Videos WHERE user = ? AND size > 0")
+            .bind(user)
+            .fetch_one(&self.pool)
+            .await
+            .map(|row| (row.get(0), row.get(1)))
+    }
+
+    /// Paths of all videos and their sidecar files.
     pub async fn get_paths(&self) -> Result<Vec<String>, sqlx::Error> {
-        sqlx::query("SELECT path FROM Videos")
+        sqlx::query("SELECT path FROM Videos UNION SELECT path FROM Sidecars")
             .fetch_all(&self.pool)
             .await
             .map(|rows| rows.into_iter().map(|row| row.get(0)).collect())
     }
 
+    pub async fn add_sidecar(
+        &self,
+        video: &str,
+        kind: SidecarKind,
+        label: &str,
+        path: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO Sidecars (video, kind, label, path) VALUES (?, ?, ?, ?)")
+            .bind(video)
+            .bind(kind.to_u8())
+            .bind(label)
+            .bind(path)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_sidecars(&self, video: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM Sidecars WHERE video = ?")
+            .bind(video)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_sidecars(&self, video: &str) -> Result<Vec<Sidecar>, sqlx::Error> {
+        sqlx::query_as::<_, Sidecar>("SELECT * FROM Sidecars WHERE video = ? ORDER BY kind, label")
+            .bind(video)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn get_sidecar(&self, video: &str, id: i64) -> Result<Option<Sidecar>, sqlx::Error> {
+        sqlx::query_as::<_, Sidecar>("SELECT * FROM Sidecars WHERE video = ? AND id = ?")
+            .bind(video)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
     pub async fn update_data(&self, uuid: &str, video: &Video) -> Result<(), sqlx::Error> {
         sqlx::query("UPDATE Videos SET vid_id = ?, name = ?, format = ?, quality = ?, path = ?, created = ?, expires_at = ?, user = ? WHERE uuid = ?")
             .bind(video.vid_id.to_string())
@@ -160,13 +293,94 @@ pub struct Video {
     pub created: String,
     pub expires_at: String,
     pub user: u16,
+    /// Page the media was requested from, passed to yt-dlp again for the download.
+    pub url: String,
+    /// yt-dlp `extractor_key`, e.g. `Youtube`, `Vimeo` or `Soundcloud`.
+    pub extractor: String,
+    /// Playlist download this video belongs to, empty for single requests.
+    pub batch: String,
+    /// Exact yt-dlp format id, overrides `quality` when set.
+    pub format_id: String,
+    /// Target container, overrides the extension of `format` when set.
+    pub container: String,
+    /// `--download-sections` value, empty for the whole media.
+    pub section: String,
+    /// [`DownloadOptions`] as JSON, empty for the defaults.
+    pub options: String,
+}
+
+/// Extra files written next to the media.
+#[derive(sqlx::FromRow)]
+pub struct Sidecar {
+    pub id: i64,
+    pub video: String,
+    pub kind: u8,
+    /// Subtitle language, chapter number or empty.
+    pub label: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SidecarKind {
+    Subtitle,
+    Thumbnail,
+    Chapter,
+    Bundle,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct DownloadOptions {
+    /// Subtitle languages to fetch, e.g. `en` or `pt-BR`.
+    #[serde(default)]
+    pub subtitles: Vec<String>,
+    /// Fall back to automatically generated subtitles.
+    #[serde(default)]
+    pub auto_subtitles: bool,
+    /// Convert subtitles, otherwise the best available format is kept.
+    #[serde(default)]
+    pub subtitle_format: Option<SubtitleFormat>,
+    /// Store the thumbnail as a separate file.
+    #[serde(default)]
+    pub write_thumbnail: bool,
+    #[serde(default)]
+    pub embed_thumbnail: bool,
+    #[serde(default)]
+    pub embed_metadata: bool,
+    /// Store every chapter as a separate file next to the full media.
+    #[serde(default)]
+    pub split_chapters: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    Srt,
+    Vtt,
 }
 
 impl Video {
     pub fn from_yt_json(json: serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
-        let vid_id = json.get("id").unwrap().as_str().unwrap().to_string();
+        let vid_id = json["id"]
+            .as_str()
+            .ok_or("yt-dlp output has no 'id'")?
+            .to_string();
+
+        let name = json["title"]
+            .as_str()
+            .ok_or("yt-dlp output has no 'title'")?
+            .to_string();
 
-        let name = json.get("title").unwrap().as_str().unwrap().to_string();
+        let url = json["webpage_url"]
+            .as_str()
+            .or(json["original_url"].as_str())
+            .ok_or("yt-dlp output has no 'webpage_url'")?
+            .to_string();
+
+        let extractor = json["extractor_key"]
+            .as_str()
+            .ok_or("yt-dlp output has no 'extractor_key'")?
+            .to_string();
 
         let format = 0;
         let quality = 0;
@@ -185,8 +399,91 @@ impl Video {
             created,
             expires_at,
             user,
+            url,
+            extractor,
+            batch: String::new(),
+            format_id: String::new(),
+            container: String::new(),
+            section: String::new(),
+            options: String::new(),
         })
     }
+
+    /// Builds a video from an entry of a `--flat-playlist` listing.
+    pub fn from_playlist_entry(entry: &PlaylistEntry) -> Self {
+        let mut video = Self {
+            uuid: "".to_string(),
+            vid_id: entry.id.clone(),
+            name: entry.title.clone(),
+            format: 0,
+            quality: 0,
+            path: "".to_string(),
+            created: utils::get_current_timestamp().to_string(),
+            expires_at: (utils::get_current_timestamp() + 65_321).to_string(),
+            user: 0,
+            url: entry.url.clone(),
+            extractor: entry.extractor.clone(),
+            batch: String::new(),
+            format_id: String::new(),
+            container: String::new(),
+            section: String::new(),
+            options: String::new(),
+        };
+        if video.name.is_empty() {
+            video.name = video.vid_id.clone();
+        }
+        video
+    }
+
+    pub fn options(&self) -> DownloadOptions {
+        serde_json::from_str(&self.options).unwrap_or_default()
+    }
+
+    pub fn batch_id(&self) -> Option<&str> {
+        if self.batch.is_empty() {
+            None
+        } else {
+            Some(&self.batch)
+        }
+    }
+
+    /// URL handed to yt-dlp, rows from before `url` was stored are YouTube ids.
+    pub fn source_url(&self) -> String {
+        if self.url.is_empty() {
+            format!("https://www.youtube.com/watch?v={}", self.vid_id)
+        } else {
+            self.url.clone()
+        }
+    }
+}
+
+impl SidecarKind {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            SidecarKind::Subtitle => 0,
+            SidecarKind::Thumbnail => 1,
+            SidecarKind::Chapter => 2,
+            SidecarKind::Bundle => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => SidecarKind::Subtitle,
+            1 => SidecarKind::Thumbnail,
+            2 => SidecarKind::Chapter,
+            _ => SidecarKind::Bundle,
+        }
+    }
+}
+
+impl SubtitleFormat {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Vtt => "vtt",
+        }
+    }
 }
 
 #[derive(Deserialize)]
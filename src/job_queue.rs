@@ -0,0 +1,467 @@
+use std::{collections::{HashMap, HashSet}, path::Path, process::Stdio, sync::Arc};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::RwLock,
+};
+
+use crate::{
+    db::{
+        user::User,
+        video::{SidecarKind, Video},
+    },
+    quota::{self, QuotaError},
+    state::State,
+    utils,
+    yt_dlp::{self, OutputFile},
+};
+
+/// Finished jobs are kept this long so clients can still read their status.
+pub const FINISHED_JOB_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Lines of yt-dlp's stderr kept for the error message of a failed job.
+const STDERR_TAIL_LINES: usize = 5;
+
+pub type JobMap = Arc<RwLock<HashMap<String, DownloadJob>>>;
+
+// MARK: Models
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DownloadJob {
+    pub uuid: String,
+    #[serde(skip)]
+    pub user: u16,
+    pub batch: Option<String>,
+    pub state: JobState,
+    pub percent: f32,
+    pub eta_secs: Option<u64>,
+    pub speed: Option<String>,
+    pub error: Option<String>,
+    pub created: u64,
+    pub updated: u64,
+}
+
+#[derive(Debug)]
+pub enum EnqueueError {
+    TooManyJobs,
+}
+
+impl JobState {
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+impl DownloadJob {
+    fn new(uuid: &str, user: u16, batch: Option<&str>) -> Self {
+        let now = utils::get_current_timestamp();
+        Self {
+            uuid: uuid.to_string(),
+            user,
+            batch: batch.map(|batch| batch.to_string()),
+            state: JobState::Queued,
+            percent: 0.0,
+            eta_secs: None,
+            speed: None,
+            error: None,
+            created: now,
+            updated: now,
+        }
+    }
+
+    /// Status of a video that was downloaded before the job was forgotten.
+    pub fn finished(uuid: &str, user: u16) -> Self {
+        let mut job = Self::new(uuid, user, None);
+        job.state = JobState::Done;
+        job.percent = 100.0;
+        job
+    }
+}
+
+// MARK: Queue
+
+/// Queues the download of video `uuid`, returning the existing job if one is still active.
+/// All jobs of a batch count as one against `max_jobs_per_user`.
+pub async fn enqueue(
+    state: &State,
+    uuid: &str,
+    user: u16,
+    batch: Option<&str>,
+) -> Result<DownloadJob, EnqueueError> {
+    let mut jobs = state.jobs.write().await;
+
+    if let Some(job) = jobs.get(uuid) {
+        if job.state.is_active() {
+            return Ok(job.clone());
+        }
+    }
+
+    let group = batch.unwrap_or(uuid);
+    let active = jobs
+        .values()
+        .filter(|job| job.user == user && job.state.is_active())
+        .map(|job| job.batch.as_deref().unwrap_or(&job.uuid))
+        .collect::<HashSet<_>>();
+    if !active.contains(group) && active.len() >= state.config.yt_dlp.max_jobs_per_user {
+        return Err(EnqueueError::TooManyJobs);
+    }
+
+    let job = DownloadJob::new(uuid, user, batch);
+    jobs.insert(uuid.to_string(), job.clone());
+    drop(jobs);
+
+    let uuid = uuid.to_string();
+    tokio::spawn(async move { run_job(uuid).await });
+
+    Ok(job)
+}
+
+pub async fn get(state: &State, uuid: &str) -> Option<DownloadJob> {
+    state.jobs.read().await.get(uuid).cloned()
+}
+
+pub async fn remove(state: &State, uuid: &str) {
+    state.jobs.write().await.remove(uuid);
+}
+
+/// Drops finished jobs older than [`FINISHED_JOB_TTL_SECS`].
+pub async fn remove_finished(state: &State) {
+    let now = utils::get_current_timestamp();
+    state
+        .jobs
+        .write()
+        .await
+        .retain(|_, job| job.state.is_active() || job.updated + FINISHED_JOB_TTL_SECS >= now);
+}
+
+async fn update_job<F: FnOnce(&mut DownloadJob)>(state: &State, uuid: &str, update: F) {
+    if let Some(job) = state.jobs.write().await.get_mut(uuid) {
+        update(job);
+        job.updated = utils::get_current_timestamp();
+    }
+}
+
+// MARK: Cache
+
+/// Copies a finished download of the same media, format and quality to `video`.
+/// Returns the new path, the copy keeps the content alive for the new expiry
+/// even after the original row expires. Local storage hard links instead of copying.
+pub async fn reuse_cached(state: &State, video: &Video) -> Option<String> {
+    let cached = match state.video_db.get_cached(video).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'VideoDB' failed to get cached videos: {}", e);
+            return None;
+        }
+    };
+
+    let mut source = None;
+    for cached in cached {
+        if state.storage.exists(&cached.path).await {
+            source = Some(cached);
+            break;
+        }
+    }
+    let source = source?;
+    let prefix = yt_dlp::output_path(&state.config.upload.upload_location, video);
+    let path = format!(
+        "{}.{}",
+        &prefix,
+        utils::get_extension_from_path(&source.path)?
+    );
+
+    if let Err(e) = state.storage.copy(&source.path, &path).await {
+        eprintln!("[ERROR] Failed to copy {}: {}", &source.path, e);
+        return None;
+    }
+
+    // Sidecars share the media's name up to its extension
+    let source_prefix = source
+        .path
+        .rsplit_once('.')
+        .map(|(prefix, _)| prefix)
+        .unwrap_or(&source.path);
+    let sidecars = state
+        .video_db
+        .get_sidecars(&source.uuid)
+        .await
+        .unwrap_or_default();
+    for sidecar in sidecars {
+        let kind = SidecarKind::from_u8(sidecar.kind);
+        let suffix = match sidecar.path.strip_prefix(source_prefix) {
+            Some(suffix) if kind != SidecarKind::Bundle => suffix,
+            _ => continue,
+        };
+
+        let sidecar_path = format!("{}{}", &prefix, suffix);
+        if let Err(e) = state.storage.copy(&sidecar.path, &sidecar_path).await {
+            eprintln!("[ERROR] Failed to copy {}: {}", &sidecar.path, e);
+        } else if let Err(e) = state
+            .video_db
+            .add_sidecar(&video.uuid, kind, &sidecar.label, &sidecar_path)
+            .await
+        {
+            eprintln!("[ERROR] Database 'VideoDB' failed to add sidecar: {}", e);
+        }
+    }
+
+    println!("[INFO  ] Reusing download {} for {}", source.uuid, video.uuid);
+    Some(path)
+}
+
+// MARK: Worker
+async fn run_job(uuid: String) {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("[ERROR] Download job {} failed to get state: {}", uuid, e);
+            return;
+        }
+    };
+
+    // Waits here while all slots are taken
+    let _permit = match state.job_slots.clone().acquire_owned().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("[ERROR] Download job {} failed to get a slot: {}", uuid, e);
+            return;
+        }
+    };
+
+    update_job(&state, &uuid, |job| job.state = JobState::Running).await;
+
+    match download(&state, &uuid).await {
+        Ok(()) => {
+            update_job(&state, &uuid, |job| {
+                job.state = JobState::Done;
+                job.percent = 100.0;
+                job.eta_secs = None;
+                job.speed = None;
+            })
+            .await;
+        }
+        Err(e) => {
+            eprintln!("[ERROR] Download job {} failed: {}", uuid, e);
+            update_job(&state, &uuid, |job| {
+                job.state = JobState::Failed;
+                job.eta_secs = None;
+                job.speed = None;
+                job.error = Some(e);
+            })
+            .await;
+        }
+    }
+}
+
+async fn download(state: &State, uuid: &str) -> Result<(), String> {
+    let mut video = match state.video_db.get_by_uuid(uuid).await {
+        Ok(Some(video)) => video,
+        Ok(None) => return Err(String::from("Video not found")),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'VideoDB' failed to get video: {}", e);
+            return Err(e.to_string());
+        }
+    };
+
+    let user = owner(state, &video).await?;
+
+    // Another request may have fetched the same media while this one was queued
+    if let Some(path) = reuse_cached(state, &video).await {
+        video.path = path;
+        record_output(state, &user, &video).await?;
+        return update_video(state, &video).await;
+    }
+
+    // The request only refused users already at their limit, this keeps yt-dlp
+    // from fetching formats known to be too large. Output is checked once stored.
+    let remaining = quota::allowance(state, &user).await.map_err(|e| {
+        eprintln!("[ERROR] Failed to get quota usage: {}", e);
+        e.to_string()
+    })?;
+    if !remaining.allows(1, 1) {
+        return Err(String::from("Download exceeds your quota"));
+    }
+
+    let path_str = yt_dlp::output_path(&state.config.upload.upload_location, &video);
+
+    let mut cmd = yt_dlp::download_command(&state.config.yt_dlp, &video, &path_str, remaining.max_size());
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("yt-dlp failed to start: {}", e))?;
+
+    // Drain stderr alongside stdout so yt-dlp never blocks on a full pipe
+    let stderr = child.stderr.take();
+    let stderr_tail = tokio::spawn(async move {
+        let mut tail = Vec::new();
+        if let Some(stderr) = stderr {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.remove(0);
+                }
+                tail.push(line);
+            }
+        }
+        tail.join("\n")
+    });
+
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some(progress) = yt_dlp::parse_progress(&line) {
+                update_job(state, uuid, |job| {
+                    job.percent = progress.percent;
+                    job.eta_secs = progress.eta_secs;
+                    job.speed = progress.speed;
+                })
+                .await;
+            }
+        }
+    }
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| format!("yt-dlp failed to wait: {}", e))?;
+    let stderr_tail = stderr_tail.await.unwrap_or_default();
+
+    if !status.success() {
+        return Err(format!("yt-dlp exited with {}: {}", status, stderr_tail));
+    }
+
+    collect_output(state, &mut video, &path_str).await?;
+    record_output(state, &user, &video).await?;
+    update_video(state, &video).await
+}
+
+async fn owner(state: &State, video: &Video) -> Result<User, String> {
+    match state.user_db.get_by_id(video.user).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(String::from("User not found")),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to get user: {}", e);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// Counts the stored output of `video` towards the quota of `user`,
+/// removing it if it does not fit anymore.
+async fn record_output(state: &State, user: &User, video: &Video) -> Result<(), String> {
+    let recorded = match quota::reserve(state, user, 0, 0).await {
+        Ok(mut reservation) => quota::record_video_within(state, video, &mut reservation.remaining).await,
+        Err(QuotaError::Exceeded(_)) => false,
+        Err(QuotaError::Database(e)) => {
+            eprintln!("[ERROR] Failed to get quota usage: {}", e);
+            remove_output(state, video).await;
+            return Err(e.to_string());
+        }
+    };
+
+    if !recorded {
+        remove_output(state, video).await;
+        return Err(String::from("Download exceeds your quota"));
+    }
+    Ok(())
+}
+
+/// Deletes the stored media of `video` and its sidecars.
+pub async fn remove_output(state: &State, video: &Video) {
+    let mut paths = vec![video.path.clone()];
+    match state.video_db.get_sidecars(&video.uuid).await {
+        Ok(sidecars) => paths.extend(
+            sidecars
+                .into_iter()
+                .filter(|sidecar| SidecarKind::from_u8(sidecar.kind) != SidecarKind::Bundle)
+                .map(|sidecar| sidecar.path),
+        ),
+        Err(e) => eprintln!("[ERROR] Database 'VideoDB' failed to get sidecars: {}", e),
+    }
+
+    for path in paths.iter().filter(|path| !path.is_empty()) {
+        if let Err(e) = state.storage.delete(path).await {
+            // The background worker cleans up anything left behind
+            eprintln!("[WARN  ] Failed to remove file {}: {}", path, e);
+        }
+    }
+    if let Err(e) = state.video_db.remove_sidecars(&video.uuid).await {
+        eprintln!("[ERROR] Database 'VideoDB' failed to remove sidecars: {}", e);
+    }
+}
+
+/// Finds the media yt-dlp wrote for `output` and records the files next to it as sidecars.
+async fn collect_output(state: &State, video: &mut Video, output: &str) -> Result<(), String> {
+    let prefix = utils::get_filename_from_path(output).unwrap_or_default();
+    let directory = utils::get_directory_from_path(output).unwrap_or_default();
+
+    let mut entries = tokio::fs::read_dir(&directory)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", &directory, e))?;
+
+    let mut sidecars = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path().to_string_lossy().to_string();
+
+        match yt_dlp::classify_output(&prefix, &file_name) {
+            Some(OutputFile::Media) => video.path = path,
+            Some(OutputFile::Subtitle(language)) => {
+                sidecars.push((SidecarKind::Subtitle, language, path))
+            }
+            Some(OutputFile::Thumbnail) => sidecars.push((SidecarKind::Thumbnail, String::new(), path)),
+            Some(OutputFile::Chapter(number)) => {
+                sidecars.push((SidecarKind::Chapter, number.to_string(), path))
+            }
+            None => {}
+        }
+    }
+
+    if video.path.is_empty() {
+        return Err(String::from("yt-dlp did not produce a file"));
+    }
+
+    store(state, &video.path).await?;
+    for (kind, label, path) in sidecars {
+        store(state, &path).await?;
+        if let Err(e) = state.video_db.add_sidecar(&video.uuid, kind, &label, &path).await {
+            eprintln!("[ERROR] Database 'VideoDB' failed to add sidecar: {}", e);
+            return Err(e.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Hands a finished output file to the storage backend.
+async fn store(state: &State, path: &str) -> Result<(), String> {
+    state
+        .storage
+        .put_file(path, Path::new(path))
+        .await
+        .map_err(|e| {
+            eprintln!("[ERROR] Storage '{}' failed to store {}: {}", state.storage.name(), path, e);
+            e.to_string()
+        })
+}
+
+async fn update_video(state: &State, video: &Video) -> Result<(), String> {
+    state
+        .video_db
+        .update_data(&video.uuid, video)
+        .await
+        .map_err(|e| {
+            eprintln!("[ERROR] Database 'VideoDB' failed to update video: {}", e);
+            e.to_string()
+        })
+}
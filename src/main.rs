@@ -9,8 +9,13 @@ pub mod config;
 pub mod db;
 pub mod routes;
 pub mod state;
+pub mod storage;
 pub mod utils;
 pub mod background_worker;
+pub mod clips;
+pub mod job_queue;
+pub mod quota;
+pub mod yt_dlp;
 
 #[launch]
 async fn rocket() -> _ {
@@ -18,6 +23,10 @@ async fn rocket() -> _ {
     println!("[INFO  ] Version: {}", env!("CARGO_PKG_VERSION"));
     println!("[INFO  ] TIME check: {}", utils::get_current_timestamp());
 
+    if std::env::args().nth(1).as_deref() == Some("rotate-keys") {
+        rotate_keys().await;
+    }
+
     // Setup main state
     let _ = state::State::init().await.map_err(|e| {
         eprintln!("[FATAL ] Failed to initialize main state: {}", e);
@@ -66,21 +75,72 @@ async fn rocket() -> _ {
                 routes::index::dash_upload_file,
                 routes::index::upload_style,
                 routes::index::sha_js,
+                routes::index::e2e_js,
                 routes::index::youtube_page,
                 routes::index::youtube_style,
                 routes::index::medal_page,
                 routes::api::authorize,
+                routes::api::session_info,
+                routes::api::logout,
+                routes::api_keys::list_api_keys,
+                routes::api_keys::create_api_key,
+                routes::api_keys::revoke_api_key,
+                routes::users::list_users,
+                routes::users::create_user,
+                routes::users::update_user,
+                routes::users::revoke_user,
+                routes::users::rotate_user_token,
                 routes::upload::request_upload,
                 routes::upload::upload_file,
+                routes::upload::upload_chunk,
+                routes::upload::upload_offset,
                 routes::upload::get_upload_status,
                 routes::download::download_file,
+                routes::download::unlock_file,
+                routes::download::download_shared,
+                routes::download::unlock_shared,
+                routes::files::list_files,
+                routes::files::update_file,
+                routes::files::delete_file,
+                routes::files::list_share_links,
+                routes::files::create_share_link,
+                routes::files::revoke_share_link,
                 routes::youtube::youtube_request,
+                routes::youtube::youtube_formats,
                 routes::youtube::youtube_download,
+                routes::youtube::youtube_files,
+                routes::youtube::youtube_file,
+                routes::youtube::youtube_bundle,
+                routes::youtube::youtube_job_status,
+                routes::youtube::youtube_job_enqueue,
+                routes::youtube::youtube_batch_status,
+                routes::youtube::youtube_batch_zip,
                 routes::medal::download_medal_clip,
+                routes::medal::medal_clip_info,
             ],
         )
 }
 
+/// `rist rotate-keys`: re-encrypts the data keys of stored files with the current master key.
+async fn rotate_keys() -> ! {
+    let config_path = std::env::var("CONFIG_PATH").unwrap_or("./config.json".to_string());
+    let config = config::Config::load(&config_path).unwrap_or_else(|e| {
+        eprintln!("[FATAL ] Failed to load config: {}", e);
+        std::process::exit(1);
+    });
+
+    match storage::encrypted::rotate_keys(&config).await {
+        Ok(rotated) => {
+            println!("[INFO  ] Rotated the keys of {} files", rotated);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("[FATAL ] Failed to rotate keys: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 async fn before_launch() {
     let state = State::get().await.unwrap();
 
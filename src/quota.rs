@@ -0,0 +1,212 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::{
+    config::QuotaLimits,
+    db::{
+        user::User,
+        video::{SidecarKind, Video},
+    },
+    state::State,
+    utils,
+};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Per user, the lock held from a quota check until what it allowed is stored.
+pub type QuotaLockMap = Arc<Mutex<HashMap<u16, Arc<Mutex<()>>>>>;
+
+/// What a user stores right now, uploads and yt-dlp or clip downloads together.
+pub struct Usage {
+    pub total_bytes: u64,
+    pub files: u64,
+    /// Bytes stored in the last 24 hours, including files deleted since.
+    pub daily_bytes: u64,
+}
+
+/// What is left of each limit, `None` where there is no limit.
+#[derive(Serialize, Debug)]
+pub struct Allowance {
+    pub total_bytes: Option<u64>,
+    pub files: Option<u64>,
+    pub daily_bytes: Option<u64>,
+}
+
+/// A passed quota check, further checks of the user wait until it is dropped,
+/// so concurrent requests can't all pass the same check.
+pub struct Reservation {
+    pub remaining: Allowance,
+    _lock: OwnedMutexGuard<()>,
+}
+
+pub enum QuotaError {
+    Exceeded(Allowance),
+    Database(sqlx::Error),
+}
+
+impl Allowance {
+    /// Whether `files` more files of `size` bytes in total fit.
+    pub fn allows(&self, files: u64, size: u64) -> bool {
+        self.total_bytes.map_or(true, |left| size <= left)
+            && self.files.map_or(true, |left| left >= files)
+            && self.daily_bytes.map_or(true, |left| size <= left)
+    }
+
+    /// Shrinks what is left by `files` files of `size` bytes in total.
+    fn take(&mut self, files: u64, size: u64) {
+        for (left, used) in [
+            (&mut self.total_bytes, size),
+            (&mut self.files, files),
+            (&mut self.daily_bytes, size),
+        ] {
+            if let Some(left) = left {
+                *left = left.saturating_sub(used);
+            }
+        }
+    }
+
+    /// Largest single file that still fits, `None` without a byte limit.
+    pub fn max_size(&self) -> Option<u64> {
+        match (self.total_bytes, self.daily_bytes) {
+            (Some(total), Some(daily)) => Some(total.min(daily)),
+            (total, daily) => total.or(daily),
+        }
+    }
+}
+
+pub async fn usage(state: &State, user: u16) -> Result<Usage, sqlx::Error> {
+    let (file_bytes, files) = state.file_db.get_usage(user).await?;
+    let (video_bytes, videos) = state.video_db.get_usage(user).await?;
+    let since = utils::get_current_timestamp().saturating_sub(DAY_SECS);
+    let daily_bytes = state.file_db.uploaded_since(user, since).await?;
+
+    Ok(Usage {
+        total_bytes: (file_bytes + video_bytes).max(0) as u64,
+        files: (files + videos).max(0) as u64,
+        daily_bytes: daily_bytes.max(0) as u64,
+    })
+}
+
+pub async fn allowance(state: &State, user: &User) -> Result<Allowance, sqlx::Error> {
+    let limits = state.config.upload.quotas.limits_for(user);
+    let usage = usage(state, user.id).await?;
+    Ok(remaining(&limits, &usage))
+}
+
+/// Checks that `files` more files of `size` bytes in total fit the quota of `user`.
+/// Keep the reservation until the files are stored and logged.
+pub async fn reserve(state: &State, user: &User, files: u64, size: u64) -> Result<Reservation, QuotaError> {
+    let lock = Arc::clone(state.quota_locks.lock().await.entry(user.id).or_default());
+    let lock = lock.lock_owned().await;
+
+    let remaining = allowance(state, user).await.map_err(QuotaError::Database)?;
+    if !remaining.allows(files, size) {
+        return Err(QuotaError::Exceeded(remaining));
+    }
+
+    Ok(Reservation {
+        remaining,
+        _lock: lock,
+    })
+}
+
+fn remaining(limits: &QuotaLimits, usage: &Usage) -> Allowance {
+    let left = |limit: u64, used: u64| match limit {
+        0 => None,
+        limit => Some(limit.saturating_sub(used)),
+    };
+
+    Allowance {
+        total_bytes: left(limits.max_total_bytes, usage.total_bytes),
+        files: left(limits.max_files, usage.files),
+        daily_bytes: left(limits.max_daily_bytes, usage.daily_bytes),
+    }
+}
+
+/// Counts a stored yt-dlp or clip download and its sidecars towards its user's quota.
+pub async fn record_video(state: &State, video: &Video) {
+    let size = stored_size(state, video).await;
+    add_usage(state, video, size).await;
+}
+
+/// Like [`record_video`], but only if the download still fits `remaining`, which
+/// shrinks by it. The size of yt-dlp output is only known once it is stored, so the
+/// check before the download can't limit it. Returns whether it was counted.
+pub async fn record_video_within(state: &State, video: &Video, remaining: &mut Allowance) -> bool {
+    let size = stored_size(state, video).await;
+    if !remaining.allows(1, size) {
+        return false;
+    }
+
+    remaining.take(1, size);
+    add_usage(state, video, size).await;
+    true
+}
+
+/// Bytes stored for a video and its sidecars, bundles are not counted.
+async fn stored_size(state: &State, video: &Video) -> u64 {
+    let mut paths = vec![video.path.clone()];
+    match state.video_db.get_sidecars(&video.uuid).await {
+        Ok(sidecars) => paths.extend(
+            sidecars
+                .into_iter()
+                .filter(|sidecar| SidecarKind::from_u8(sidecar.kind) != SidecarKind::Bundle)
+                .map(|sidecar| sidecar.path),
+        ),
+        Err(e) => eprintln!("[ERROR] Database 'VideoDB' failed to get sidecars: {}", e),
+    }
+
+    let mut size = 0;
+    for path in paths {
+        match state.storage.stat(&path).await {
+            Ok(Some(stat)) => size += stat.size,
+            Ok(None) => {}
+            Err(e) => eprintln!("[ERROR] Storage '{}' failed to stat {}: {}", state.storage.name(), path, e),
+        }
+    }
+    size
+}
+
+async fn add_usage(state: &State, video: &Video, size: u64) {
+    if let Err(e) = state.video_db.add_size(&video.uuid, size).await {
+        eprintln!("[ERROR] Database 'VideoDB' failed to update size: {}", e);
+    }
+    if let Err(e) = state.file_db.log_upload(video.user, size).await {
+        eprintln!("[ERROR] Database 'FileDB' failed to log upload: {}", e);
+    }
+}
+
+/// Removes upload log entries no daily quota looks at anymore.
+pub async fn prune_log(state: &State) -> Result<(), sqlx::Error> {
+    let before = utils::get_current_timestamp().saturating_sub(DAY_SECS);
+    state.file_db.remove_upload_log_before(before).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taking_shrinks_every_limit() {
+        let mut remaining = Allowance {
+            total_bytes: Some(100),
+            files: Some(2),
+            daily_bytes: None,
+        };
+        assert!(remaining.allows(1, 100));
+        assert!(!remaining.allows(1, 101));
+        assert_eq!(remaining.max_size(), Some(100));
+
+        remaining.take(1, 60);
+        assert_eq!(remaining.total_bytes, Some(40));
+        assert_eq!(remaining.files, Some(1));
+        assert_eq!(remaining.daily_bytes, None);
+        assert!(!remaining.allows(1, 41));
+
+        remaining.take(1, 60);
+        assert_eq!(remaining.total_bytes, Some(0));
+        assert!(!remaining.allows(1, 0));
+    }
+}
@@ -1,11 +1,12 @@
-use rocket::http::Status;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::serde::json::Json;
+use rocket::time::Duration;
 use rocket_governor::RocketGovernor;
 use serde::{Deserialize, Serialize};
 
 use crate::{db::user::User, state};
 
-use super::StrictRateLimitGuard;
+use super::{BaseRateLimitGuard, StrictRateLimitGuard, TokenAuth, SESSION_COOKIE};
 
 #[derive(Deserialize, FromForm)]
 pub struct AuthorizeRequest {
@@ -20,15 +21,69 @@ pub struct AuthorizeResponse {
 #[post("/api/authorize", format = "json", data = "<data>")]
 pub async fn authorize<'r>(
     _srt: RocketGovernor<'r, StrictRateLimitGuard>,
+    cookies: &CookieJar<'_>,
     data: Json<AuthorizeRequest>,
 ) -> Result<Json<AuthorizeResponse>, Status> {
     let user = match check_auth(&data.0.token).await {
         Ok(user) => user,
         Err(status) => return Err(status),
     };
+
+    let state = match state::State::get().await {
+        Ok(state) => state,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let ttl = state.config.server.session_ttl_secs;
+    let session_id = match state.user_db.create_session(user.id, ttl).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to create session: {}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    cookies.add(
+        Cookie::build((SESSION_COOKIE, session_id))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .secure(state.config.server.secure_cookies)
+            .max_age(Duration::seconds(ttl as i64)),
+    );
+
     Ok(Json(AuthorizeResponse { role: user.kind }))
 }
 
+#[get("/api/authorize")]
+pub async fn session_info<'r>(
+    _brl: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+) -> Json<AuthorizeResponse> {
+    Json(AuthorizeResponse { role: auth.0.kind })
+}
+
+#[post("/api/logout")]
+pub async fn logout<'r>(
+    _brl: RocketGovernor<'r, BaseRateLimitGuard>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        let state = match state::State::get().await {
+            Ok(state) => state,
+            Err(_) => return Status::InternalServerError,
+        };
+
+        if let Err(e) = state.user_db.remove_session(cookie.value()).await {
+            eprintln!("[ERROR] Database 'UserDB' failed to remove session: {}", e);
+            return Status::InternalServerError;
+        }
+    }
+
+    cookies.remove(Cookie::build(SESSION_COOKIE).path("/"));
+    Status::NoContent
+}
+
 pub async fn check_auth(token: &String) -> Result<User, Status> {
     let state = match state::State::get().await {
         Ok(state) => state,
@@ -0,0 +1,229 @@
+use std::io::Cursor;
+
+use rocket::{
+    http::{ContentType, Status},
+    response,
+    serde::json::Json,
+    Request, Response,
+};
+use rocket_governor::RocketGovernor;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::user::{ApiKey, PermissionKind},
+    state::State,
+    utils,
+};
+
+use super::{RateLimitGuard, TokenAuth};
+
+// MARK: Models
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Option<Vec<PermissionKind>>,
+    pub created: i64,
+    pub expires_at: i64,
+    pub last_used: i64,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyCreateResponse {
+    pub api_key: ApiKeyInfo,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyCreateRequest {
+    pub name: String,
+    pub scopes: Option<Vec<PermissionKind>>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum ApiKeyErrorKind {
+    KeyNotFound,
+    InvalidDataSupplied,
+    ServerIssue,
+    NoPermissions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyError {
+    pub kind: ApiKeyErrorKind,
+    pub status: Status,
+    pub message: String,
+}
+
+impl ApiKeyInfo {
+    pub fn from_api_key(api_key: &ApiKey) -> Self {
+        Self {
+            id: api_key.id,
+            name: api_key.name.clone(),
+            prefix: api_key.prefix.clone(),
+            scopes: api_key.scopes(),
+            created: api_key.created,
+            expires_at: api_key.expires_at,
+            last_used: api_key.last_used,
+        }
+    }
+}
+
+// MARK: Responders
+impl<'r, 'o: 'r> response::Responder<'r, 'o> for ApiKeyError {
+    fn respond_to(self, _: &Request) -> rocket::response::Result<'o> {
+        let mut res = Response::new();
+        res.set_status(self.status);
+
+        let body = json!({
+            "status": self.status.code,
+            "error": self.kind,
+            "message": self.message
+        })
+        .to_string();
+        res.set_sized_body(body.len(), Cursor::new(body));
+        res.set_header(ContentType::new("application", "json"));
+        Ok(res)
+    }
+}
+
+// MARK: List keys
+#[get("/api/keys")]
+pub async fn list_api_keys<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiKeyError> {
+    check_not_api_key(&auth)?;
+
+    let state = get_state().await?;
+    match state.user_db.get_api_keys(auth.0.id).await {
+        Ok(keys) => Ok(Json(keys.iter().map(ApiKeyInfo::from_api_key).collect())),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to list API keys: {}", e);
+            Err(ApiKeyError {
+                kind: ApiKeyErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+// MARK: Create key
+#[post("/api/keys", format = "json", data = "<data>")]
+pub async fn create_api_key<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    data: Json<ApiKeyCreateRequest>,
+) -> Result<Json<ApiKeyCreateResponse>, ApiKeyError> {
+    check_not_api_key(&auth)?;
+
+    let name = data.0.name.trim().to_string();
+    if name.is_empty() || name.len() > 64 {
+        return Err(ApiKeyError {
+            kind: ApiKeyErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Name must be between 1 and 64 characters"),
+        });
+    }
+
+    // Leaving out scopes gives the key every permission of its owner
+    if data.0.scopes.as_ref().is_some_and(|scopes| scopes.is_empty()) {
+        return Err(ApiKeyError {
+            kind: ApiKeyErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Scopes must not be empty, leave them out for an unrestricted key"),
+        });
+    }
+    let scopes = data.0.scopes.unwrap_or_default();
+    if let Some(scope) = scopes.iter().find(|scope| !auth.0.has_permissions_to(**scope)) {
+        return Err(ApiKeyError {
+            kind: ApiKeyErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: format!("Your account does not have the '{}' permission", scope.as_str()),
+        });
+    }
+
+    // 0 never expires
+    let expires_at = data.0.expires_at.unwrap_or(0);
+    if expires_at != 0 && expires_at <= utils::get_current_timestamp() {
+        return Err(ApiKeyError {
+            kind: ApiKeyErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Expiration must be in the future"),
+        });
+    }
+
+    let state = get_state().await?;
+    match state
+        .user_db
+        .create_api_key(auth.0.id, &name, &scopes, expires_at)
+        .await
+    {
+        Ok((api_key, key)) => Ok(Json(ApiKeyCreateResponse {
+            api_key: ApiKeyInfo::from_api_key(&api_key),
+            key,
+        })),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to create API key: {}", e);
+            Err(ApiKeyError {
+                kind: ApiKeyErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+// MARK: Revoke key
+#[delete("/api/keys/<id>")]
+pub async fn revoke_api_key<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    id: i64,
+) -> Result<Status, ApiKeyError> {
+    check_not_api_key(&auth)?;
+
+    let state = get_state().await?;
+    match state.user_db.remove_api_key(auth.0.id, id).await {
+        Ok(true) => Ok(Status::NoContent),
+        Ok(false) => Err(ApiKeyError {
+            kind: ApiKeyErrorKind::KeyNotFound,
+            status: Status::NotFound,
+            message: String::from("API key not found"),
+        }),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to remove API key: {}", e);
+            Err(ApiKeyError {
+                kind: ApiKeyErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+// MARK: Utils
+
+/// API keys can not manage API keys, otherwise a scoped key could mint a full one.
+fn check_not_api_key(auth: &TokenAuth) -> Result<(), ApiKeyError> {
+    if auth.is_api_key() {
+        return Err(ApiKeyError {
+            kind: ApiKeyErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("API keys can not manage API keys"),
+        });
+    }
+    Ok(())
+}
+
+async fn get_state() -> Result<std::sync::Arc<State>, ApiKeyError> {
+    State::get().await.map_err(|_| ApiKeyError {
+        kind: ApiKeyErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: String::from("Server issue"),
+    })
+}
@@ -1,21 +1,38 @@
-use std::{fs, io::Cursor, path::Path};
+use std::{fs, path::Path};
 
 use rocket::{
-    http::ContentType,
-    response::{self, content::RawHtml},
-    Request, Response,
+    form::Form,
+    http::{Cookie, CookieJar, SameSite},
+    response::{self, content::RawHtml, Redirect},
+    time::Duration,
+    Request,
 };
 use rocket_governor::RocketGovernor;
 
-use crate::state::State;
+use crate::{
+    db::file::{File, ShareLink},
+    state::State,
+    utils,
+};
+
+use super::{
+    stream::{FileStream, FinishHook, StreamRequest},
+    RateLimitGuard, UnlockRateLimitGuard,
+};
 
-use super::RateLimitGuard;
+#[derive(FromForm)]
+pub struct UnlockForm {
+    pub password: String,
+}
 
 pub struct DownloadResponse {
     pub found: bool,
     pub finished: bool,
-    pub filename: String,
-    pub data: Vec<u8>,
+    /// Serves the page decrypting the file in the browser instead of the file.
+    pub decrypt: bool,
+    /// Serves the password prompt instead of the file.
+    pub locked: bool,
+    pub file: Option<FileStream>,
 }
 
 impl DownloadResponse {
@@ -23,15 +40,16 @@ impl DownloadResponse {
         Self {
             found: false,
             finished: false,
-            filename: String::new(),
-            data: Vec::new(),
+            decrypt: false,
+            locked: false,
+            file: None,
         }
     }
 }
 
 #[rocket::async_trait]
 impl<'r, 'o: 'r> response::Responder<'r, 'o> for DownloadResponse {
-    fn respond_to(self, req: &Request) -> rocket::response::Result<'o> {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'o> {
         if !self.found {
             let path = Path::new("frontend/404.html");
             let content = fs::read_to_string(path).unwrap();
@@ -44,23 +62,33 @@ impl<'r, 'o: 'r> response::Responder<'r, 'o> for DownloadResponse {
             return Ok(RawHtml(content).respond_to(req).unwrap());
         }
 
-        let mut res = Response::new();
+        if self.locked {
+            let path = Path::new("frontend/password.html");
+            let content = fs::read_to_string(path).unwrap();
+            return Ok(RawHtml(content).respond_to(req).unwrap());
+        }
 
-        res.set_header(ContentType::new("application", "octet-stream"));
-        res.set_raw_header(
-            "Content-Disposition",
-            format!("attachment; filename=\"{}\"", self.filename),
-        );
-        res.set_sized_body(self.data.len(), Cursor::new(self.data));
+        if self.decrypt {
+            let path = Path::new("frontend/decrypt.html");
+            let content = fs::read_to_string(path).unwrap();
+            return Ok(RawHtml(content).respond_to(req).unwrap());
+        }
 
-        Ok(res)
+        match self.file {
+            Some(file) => file.respond_to(req),
+            None => DownloadResponse::default().respond_to(req),
+        }
     }
 }
 
-#[get("/f?<u>")]
+/// `raw` skips the decrypt page of encrypted files, it is used by the page itself.
+#[get("/f?<u>&<raw>")]
 pub async fn download_file<'r>(
     _rt: RocketGovernor<'r, RateLimitGuard>,
+    stream_request: StreamRequest,
+    cookies: &CookieJar<'_>,
     u: String,
+    raw: Option<bool>,
 ) -> DownloadResponse {
     let state = match State::get().await {
         Ok(state) => state,
@@ -73,8 +101,9 @@ pub async fn download_file<'r>(
         return DownloadResponse {
             found: true,
             finished: false,
-            filename: String::new(),
-            data: Vec::new(),
+            decrypt: false,
+            locked: false,
+            file: None,
         };
     }
 
@@ -90,25 +119,310 @@ pub async fn download_file<'r>(
         }
     };
 
-    let path = Path::new(&file.path);
-    let content = match fs::read(path) {
-        Ok(content) => content,
+    serve(&state, file, None, stream_request, cookies, raw).await
+}
+
+/// Downloads through an extra share link, see [`share_token`].
+#[get("/s/<token>?<raw>")]
+pub async fn download_shared<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    stream_request: StreamRequest,
+    cookies: &CookieJar<'_>,
+    token: &str,
+    raw: Option<bool>,
+) -> DownloadResponse {
+    let state = match State::get().await {
+        Ok(state) => state,
         Err(_) => {
             return DownloadResponse::default();
         }
     };
 
-    match state.file_db.increment_access_count(&u).await {
-        Ok(_) => {}
-        Err(_) => {
+    let (link, file) = match get_shared_file(&state, token).await {
+        Some(shared) => shared,
+        None => return DownloadResponse::default(),
+    };
+
+    serve(&state, file, Some(link), stream_request, cookies, raw).await
+}
+
+/// Serves `file`, counting the download on the file and on `link`.
+async fn serve(
+    state: &State,
+    file: File,
+    link: Option<ShareLink>,
+    stream_request: StreamRequest,
+    cookies: &CookieJar<'_>,
+    raw: Option<bool>,
+) -> DownloadResponse {
+    if !file.password_hash.is_empty() && !is_unlocked(state, cookies, &file) {
+        return DownloadResponse {
+            found: true,
+            finished: true,
+            decrypt: false,
+            locked: true,
+            file: None,
+        };
+    }
+
+    // The key is only in the link fragment, so the browser has to decrypt
+    if file.encrypted && raw != Some(true) {
+        return DownloadResponse {
+            found: true,
+            finished: true,
+            decrypt: true,
+            locked: false,
+            file: None,
+        };
+    }
+
+    let mut stream = match FileStream::open(file.path.clone(), file.name.clone(), Some(file.hash.clone())).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("[ERROR] Failed to open file {}: {}", &file.uuid, e);
+            }
             return DownloadResponse::default();
         }
+    };
+
+    // Limited files and links count every read and always send the whole file,
+    // so ranges can't fetch them piece by piece or use them up with a partial read.
+    // Otherwise resumed ranges and HEAD requests are part of an earlier download
+    let limited = file.max_downloads > 0 || link.as_ref().is_some_and(|link| link.max_downloads > 0);
+    let counts = match limited {
+        true => !stream_request.is_head,
+        false => stream_request.counts_as_download(),
+    };
+    if !counts {
+        return DownloadResponse {
+            found: true,
+            finished: true,
+            decrypt: false,
+            locked: false,
+            file: Some(stream),
+        };
+    }
+
+    let mut claim = DownloadClaim {
+        file,
+        link: None,
+        last: false,
+    };
+
+    if let Some(link) = link {
+        match state.file_db.claim_share_link(&link.id).await {
+            Ok(Some(_)) => claim.link = Some(link.id),
+            Ok(None) => return DownloadResponse::default(),
+            Err(e) => {
+                eprintln!("[ERROR] Database 'FileDB' failed to count share link download: {}", e);
+                return DownloadResponse::default();
+            }
+        }
+    }
+
+    let counted = match claim.file.max_downloads > 0 {
+        true => state.file_db.claim_download(&claim.file.uuid).await,
+        false => state.file_db.increment_access_count(&claim.file.uuid).await.map(|_| Some(false)),
+    };
+    match counted {
+        Ok(Some(last)) => claim.last = last,
+        result => {
+            if let Err(e) = result {
+                eprintln!("[ERROR] Database 'FileDB' failed to count download: {}", e);
+            }
+            // The file download was not claimed, only the link gets its count back
+            claim.release_link(state).await;
+            return DownloadResponse::default();
+        }
+    }
+
+    if limited {
+        stream = stream.on_finish(download_finished(claim));
     }
 
     DownloadResponse {
         found: true,
         finished: true,
-        filename: file.name,
-        data: content,
+        decrypt: false,
+        locked: false,
+        file: Some(stream),
     }
 }
+
+/// Downloads counted for a response that is still being sent.
+struct DownloadClaim {
+    file: File,
+    link: Option<String>,
+    /// The file has no download left after this one.
+    last: bool,
+}
+
+impl DownloadClaim {
+    async fn release(&self, state: &State) {
+        if self.file.max_downloads > 0 {
+            if let Err(e) = state.file_db.release_download(&self.file.uuid).await {
+                eprintln!("[ERROR] Database 'FileDB' failed to release download: {}", e);
+            }
+        }
+        self.release_link(state).await;
+    }
+
+    async fn release_link(&self, state: &State) {
+        if let Some(link) = &self.link {
+            if let Err(e) = state.file_db.release_share_link(link).await {
+                eprintln!("[ERROR] Database 'FileDB' failed to release share link download: {}", e);
+            }
+        }
+    }
+}
+
+/// Gives the download back if it did not finish, removes the file after its last one.
+fn download_finished(claim: DownloadClaim) -> FinishHook {
+    Box::new(move |complete| {
+        tokio::spawn(async move {
+            let state = match State::get().await {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+
+            if !complete {
+                claim.release(&state).await;
+                return;
+            }
+
+            if claim.last {
+                let file = &claim.file;
+                println!("[INFO  ] File {} reached its download limit, removing it", file.uuid);
+                if let Err(e) = state.remove_file(file).await {
+                    eprintln!("[ERROR] Failed to remove file {}: {}", file.uuid, e);
+                }
+            }
+        });
+    })
+}
+
+// MARK: Share Links
+/// Token of the share link `id`, signed so link ids can't be guessed.
+pub fn share_token(state: &State, id: &str) -> String {
+    let signature = utils::sign(&state.config.server.link_secret, &format!("share:{}", id));
+    format!("{}.{}", id, &signature[..32])
+}
+
+/// The link and its file, `None` if the token is invalid, revoked or expired.
+async fn get_shared_file(state: &State, token: &str) -> Option<(ShareLink, File)> {
+    let (id, _) = token.split_once('.')?;
+    if !utils::constant_time_eq(share_token(state, id).as_bytes(), token.as_bytes()) {
+        return None;
+    }
+
+    let link = state.file_db.get_share_link(id).await.ok()??;
+    if link.expires_at != 0 && link.expires_at as u64 <= utils::get_current_timestamp() {
+        return None;
+    }
+
+    let file = state.file_db.get_by_uuid(&link.file_uuid).await.ok()??;
+    // Files still being uploaded are not shared yet
+    if state.upload_status.read().await.contains_key(&file.uuid) {
+        return None;
+    }
+    Some((link, file))
+}
+
+// MARK: Unlock
+/// Checks the password of a protected file and sets a short lived unlock cookie.
+#[post("/f/unlock?<u>", data = "<form>")]
+pub async fn unlock_file<'r>(
+    _urt: RocketGovernor<'r, UnlockRateLimitGuard>,
+    cookies: &CookieJar<'_>,
+    u: String,
+    form: Form<UnlockForm>,
+) -> Result<Redirect, DownloadResponse> {
+    let state = State::get().await.map_err(|_| DownloadResponse::default())?;
+
+    let file = match state.file_db.get_by_uuid(&u).await {
+        Ok(Some(file)) => file,
+        _ => return Err(DownloadResponse::default()),
+    };
+
+    let target = format!("/f?u={}", file.uuid);
+    match unlock(&state, cookies, &file, form.into_inner().password).await {
+        true => Ok(Redirect::to(target)),
+        false => Ok(Redirect::to(format!("{}&wrong=true", target))),
+    }
+}
+
+/// [`unlock_file`] for share links, which must not reveal the file uuid.
+#[post("/s/<token>/unlock", data = "<form>")]
+pub async fn unlock_shared<'r>(
+    _urt: RocketGovernor<'r, UnlockRateLimitGuard>,
+    cookies: &CookieJar<'_>,
+    token: &str,
+    form: Form<UnlockForm>,
+) -> Result<Redirect, DownloadResponse> {
+    let state = State::get().await.map_err(|_| DownloadResponse::default())?;
+
+    let (link, file) = get_shared_file(&state, token)
+        .await
+        .ok_or_else(DownloadResponse::default)?;
+
+    let target = format!("/s/{}", share_token(&state, &link.id));
+    match unlock(&state, cookies, &file, form.into_inner().password).await {
+        true => Ok(Redirect::to(target)),
+        false => Ok(Redirect::to(format!("{}?wrong=true", target))),
+    }
+}
+
+/// Sets the unlock cookie if `password` is correct.
+async fn unlock(state: &State, cookies: &CookieJar<'_>, file: &File, password: String) -> bool {
+    if file.password_hash.is_empty() {
+        return true;
+    }
+
+    let password_hash = file.password_hash.clone();
+    let correct = tokio::task::spawn_blocking(move || utils::verify_password(&password, &password_hash))
+        .await
+        .unwrap_or(false);
+    if !correct {
+        println!("[INFO  ] Wrong password for file {}", file.uuid);
+        return false;
+    }
+
+    let ttl = state.config.server.unlock_ttl_secs;
+    let expires = utils::get_current_timestamp() + ttl;
+    let signature = utils::sign(&state.config.server.link_secret, &unlock_message(file, expires));
+    cookies.add(
+        Cookie::build((unlock_cookie(&file.uuid), format!("{}.{}", expires, signature)))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .secure(state.config.server.secure_cookies)
+            .max_age(Duration::seconds(ttl as i64)),
+    );
+    true
+}
+
+/// Named after a hash of the uuid, share links must not reveal it.
+fn unlock_cookie(uuid: &str) -> String {
+    format!("unlock_{}", &utils::sha256_hex(uuid)[..16])
+}
+
+/// Includes the password hash, so changing the password locks the file again.
+fn unlock_message(file: &File, expires: u64) -> String {
+    format!("unlock:{}:{}:{}", file.uuid, expires, file.password_hash)
+}
+
+fn is_unlocked(state: &State, cookies: &CookieJar<'_>, file: &File) -> bool {
+    let cookie = match cookies.get(&unlock_cookie(&file.uuid)) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+
+    let (expires, signature) = match cookie.value().split_once('.') {
+        Some((expires, signature)) => (expires.parse::<u64>().unwrap_or(0), signature),
+        None => return false,
+    };
+
+    expires > utils::get_current_timestamp()
+        && utils::verify_signature(&state.config.server.link_secret, &unlock_message(file, expires), signature)
+}
@@ -0,0 +1,537 @@
+use std::io::Cursor;
+
+use rocket::{
+    http::{ContentType, Status},
+    response,
+    serde::json::Json,
+    Request, Response,
+};
+use rocket_governor::RocketGovernor;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        file::{File, FileSort, ShareLink},
+        user::PermissionKind,
+    },
+    state::State,
+    utils,
+};
+
+use super::{download::share_token, BaseRateLimitGuard, RateLimitGuard, TokenAuth};
+
+const DEFAULT_PER_PAGE: u32 = 25;
+const MAX_PER_PAGE: u32 = 100;
+const MAX_LABEL_LEN: usize = 100;
+
+// MARK: Models
+#[derive(Serialize)]
+pub struct FileInfo {
+    pub uuid: String,
+    pub name: String,
+    pub hash: String,
+    pub size: i64,
+    pub created: u64,
+    pub expires_at: u64,
+    pub access_count: i64,
+    pub pending: bool,
+    pub encrypted: bool,
+    pub protected: bool,
+    pub max_downloads: i64,
+}
+
+#[derive(Serialize)]
+pub struct FileListResponse {
+    pub total: i64,
+    pub page: u32,
+    pub per_page: u32,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct FileUpdateRequest {
+    pub name: Option<String>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkInfo {
+    pub id: String,
+    pub label: String,
+    pub url: String,
+    pub created: i64,
+    pub expires_at: i64,
+    pub max_downloads: i64,
+    pub access_count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ShareLinkRequest {
+    pub label: Option<String>,
+    /// 0 keeps the link as long as the file.
+    #[serde(default)]
+    pub expires_at: u64,
+    /// 0 allows unlimited downloads.
+    #[serde(default)]
+    pub max_downloads: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub enum FileErrorKind {
+    FileNotFound,
+    InvalidDataSupplied,
+    ServerIssue,
+    NoPermissions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileError {
+    pub kind: FileErrorKind,
+    pub status: Status,
+    pub message: String,
+}
+
+impl FileInfo {
+    pub fn from_file(file: File, pending: bool) -> Self {
+        Self {
+            uuid: file.uuid,
+            name: file.name,
+            hash: file.hash,
+            size: file.size,
+            created: file.created.parse().unwrap_or(0),
+            expires_at: file.expires_at.parse().unwrap_or(0),
+            access_count: file.access_count,
+            pending,
+            encrypted: file.encrypted,
+            protected: !file.password_hash.is_empty(),
+            max_downloads: file.max_downloads,
+        }
+    }
+}
+
+impl ShareLinkInfo {
+    pub fn from_link(state: &State, link: ShareLink) -> Self {
+        Self {
+            url: format!("/s/{}", share_token(state, &link.id)),
+            id: link.id,
+            label: link.label,
+            created: link.created,
+            expires_at: link.expires_at,
+            max_downloads: link.max_downloads,
+            access_count: link.access_count,
+        }
+    }
+}
+
+// MARK: Responders
+impl<'r, 'o: 'r> response::Responder<'r, 'o> for FileError {
+    fn respond_to(self, _: &Request) -> rocket::response::Result<'o> {
+        let mut res = Response::new();
+        res.set_status(self.status);
+
+        let body = json!({
+            "status": self.status.code,
+            "error": self.kind,
+            "message": self.message
+        })
+        .to_string();
+        res.set_sized_body(body.len(), Cursor::new(body));
+        res.set_header(ContentType::new("application", "json"));
+        Ok(res)
+    }
+}
+
+// MARK: List files
+#[get("/api/files?<page>&<per_page>&<sort>&<desc>")]
+pub async fn list_files<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    page: Option<u32>,
+    per_page: Option<u32>,
+    sort: Option<FileSort>,
+    desc: Option<bool>,
+) -> Result<Json<FileListResponse>, FileError> {
+    check_file_scope(&auth)?;
+
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let sort = sort.unwrap_or(FileSort::Created);
+
+    let total = match state.file_db.count_by_owner(auth.0.id).await {
+        Ok(total) => total,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to count files: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+    };
+
+    let files = match state
+        .file_db
+        .get_by_owner(
+            auth.0.id,
+            sort,
+            desc.unwrap_or(true),
+            per_page,
+            (page - 1).saturating_mul(per_page),
+        )
+        .await
+    {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to list files: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+    };
+
+    let upload_status = state.upload_status.read().await;
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let pending = upload_status.contains_key(&file.uuid);
+            FileInfo::from_file(file, pending)
+        })
+        .collect();
+
+    Ok(Json(FileListResponse {
+        total,
+        page,
+        per_page,
+        files,
+    }))
+}
+
+// MARK: Update file
+#[patch("/api/files/<uuid>", format = "json", data = "<data>")]
+pub async fn update_file<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+    data: Json<FileUpdateRequest>,
+) -> Result<Json<FileInfo>, FileError> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let mut file = get_managed_file(&auth, uuid).await?;
+
+    if let Some(name) = data.0.name {
+        let name = name.trim().to_string();
+        if name.is_empty() || name.len() > 255 || name.contains(['/', '\\']) {
+            return Err(FileError {
+                kind: FileErrorKind::InvalidDataSupplied,
+                status: Status::BadRequest,
+                message: String::from("Invalid file name"),
+            });
+        }
+
+        if let Err(e) = state.file_db.rename(uuid, &name).await {
+            eprintln!("[ERROR] Database 'FileDB' failed to rename file: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+        file.name = name;
+    }
+
+    if let Some(expires_at) = data.0.expires_at {
+        // 0 keeps the file forever
+        if expires_at != 0 && expires_at <= utils::get_current_timestamp() {
+            return Err(FileError {
+                kind: FileErrorKind::InvalidDataSupplied,
+                status: Status::BadRequest,
+                message: String::from("Expiration must be in the future"),
+            });
+        }
+
+        if let Err(e) = state.file_db.set_expires_at(uuid, expires_at).await {
+            eprintln!("[ERROR] Database 'FileDB' failed to update expiration: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+        file.expires_at = expires_at.to_string();
+    }
+
+    let pending = state.upload_status.read().await.contains_key(uuid);
+    Ok(Json(FileInfo::from_file(file, pending)))
+}
+
+// MARK: Delete file
+#[delete("/api/files/<uuid>")]
+pub async fn delete_file<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<Status, FileError> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    if !auth.has_permissions_to(PermissionKind::FileRemove) {
+        return Err(FileError {
+            kind: FileErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
+        });
+    }
+
+    let file = get_managed_file(&auth, uuid).await?;
+
+    if let Err(e) = state.remove_file(&file).await {
+        eprintln!("[ERROR] Database 'FileDB' failed to remove file: {}", e);
+        return Err(FileError {
+            kind: FileErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        });
+    }
+
+    Ok(Status::NoContent)
+}
+
+// MARK: Share links
+#[get("/api/files/<uuid>/links")]
+pub async fn list_share_links<'r>(
+    _rt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<Json<Vec<ShareLinkInfo>>, FileError> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let file = get_managed_file(&auth, uuid).await?;
+
+    let links = match state.file_db.get_share_links(&file.uuid).await {
+        Ok(links) => links,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to get share links: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+    };
+
+    Ok(Json(
+        links
+            .into_iter()
+            .map(|link| ShareLinkInfo::from_link(&state, link))
+            .collect(),
+    ))
+}
+
+#[post("/api/files/<uuid>/links", format = "json", data = "<data>")]
+pub async fn create_share_link<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+    data: Json<ShareLinkRequest>,
+) -> Result<Json<ShareLinkInfo>, FileError> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let file = get_managed_file(&auth, uuid).await?;
+
+    let label = data.0.label.unwrap_or_default().trim().to_string();
+    if label.len() > MAX_LABEL_LEN {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: format!("Label is longer than {} bytes", MAX_LABEL_LEN),
+        });
+    }
+
+    if data.0.expires_at != 0 && data.0.expires_at <= utils::get_current_timestamp() {
+        return Err(FileError {
+            kind: FileErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Expiration must be in the future"),
+        });
+    }
+
+    let id = Uuid::new_v4().to_string();
+    if let Err(e) = state
+        .file_db
+        .add_share_link(&id, &file.uuid, &label, data.0.expires_at, data.0.max_downloads)
+        .await
+    {
+        eprintln!("[ERROR] Database 'FileDB' failed to add share link: {}", e);
+        return Err(FileError {
+            kind: FileErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        });
+    }
+
+    match state.file_db.get_share_link(&id).await {
+        Ok(Some(link)) => Ok(Json(ShareLinkInfo::from_link(&state, link))),
+        Ok(None) => Err(FileError {
+            kind: FileErrorKind::FileNotFound,
+            status: Status::NotFound,
+            message: String::from("Share link not found"),
+        }),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to get share link: {}", e);
+            Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+#[delete("/api/files/<uuid>/links/<id>")]
+pub async fn revoke_share_link<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+    id: &str,
+) -> Result<Status, FileError> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let file = get_managed_file(&auth, uuid).await?;
+
+    match state.file_db.remove_share_link(&file.uuid, id).await {
+        Ok(true) => Ok(Status::NoContent),
+        Ok(false) => Err(FileError {
+            kind: FileErrorKind::FileNotFound,
+            status: Status::NotFound,
+            message: String::from("Share link not found"),
+        }),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to remove share link: {}", e);
+            Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+// MARK: Utils
+
+/// API keys manage files only with the upload scope.
+fn check_file_scope(auth: &TokenAuth) -> Result<(), FileError> {
+    if !auth.allows(PermissionKind::FileUpload) {
+        return Err(FileError {
+            kind: FileErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the file if `user` may manage it.
+/// Owners manage their own files, other users' files need `FileManage`.
+async fn get_managed_file(auth: &TokenAuth, uuid: &str) -> Result<File, FileError> {
+    check_file_scope(auth)?;
+
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => {
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Server issue"),
+            })
+        }
+    };
+
+    let file = match state.file_db.get_by_uuid(uuid).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            return Err(FileError {
+                kind: FileErrorKind::FileNotFound,
+                status: Status::NotFound,
+                message: String::from("File not found"),
+            })
+        }
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to get file: {}", e);
+            return Err(FileError {
+                kind: FileErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            });
+        }
+    };
+
+    if file.owner != auth.0.id && !auth.has_permissions_to(PermissionKind::FileManage) {
+        // Do not reveal files of other users
+        return Err(FileError {
+            kind: FileErrorKind::FileNotFound,
+            status: Status::NotFound,
+            message: String::from("File not found"),
+        });
+    }
+
+    Ok(file)
+}
@@ -58,6 +58,11 @@ pub async fn sha_js<'r>(_brl: RocketGovernor<'r, BaseRateLimitGuard>) -> Option<
   NamedFile::open("frontend/assets/sha.js").await.ok()
 }
 
+#[get("/assets/e2e.js")]
+pub async fn e2e_js<'r>(_brl: RocketGovernor<'r, BaseRateLimitGuard>) -> Option<NamedFile> {
+  NamedFile::open("frontend/assets/e2e.js").await.ok()
+}
+
 #[get("/dash/youtube")]
 pub async fn youtube_page<'r>(_brl: RocketGovernor<'r, BaseRateLimitGuard>, _auth: TokenAuth) -> Option<NamedFile> {
   NamedFile::open("frontend/youtube.html").await.ok()
@@ -1,33 +1,35 @@
-use std::{fs::{self, File}, io::{Cursor, Write}};
+use std::{io::Cursor, path::Path};
 
-use regex::Regex;
 use rocket::{
-    http::{ContentType, Header, Status},
-    response, Request, Response,
+    http::{ContentType, Status},
+    response, serde::json::Json, Request, Response,
 };
 use rocket_governor::RocketGovernor;
 use serde::Serialize;
-use serde_json::{json, Value};
+use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
+    clips::{self, Clip, ClipError, ClipErrorKind},
     db::{user::PermissionKind, video::Video},
+    quota::{self, QuotaError},
     state::State, utils,
 };
 
-use super::{StrictRateLimitGuard, TokenAuth};
+use super::{stream::FileStream, RateLimitGuard, StrictRateLimitGuard, TokenAuth};
 
 // MARK: Models
 pub enum MedalResponse {
-    Ok(MedalOutput),
+    Ok(FileStream),
     Error(MedalError),
 }
 
 #[derive(Serialize)]
-pub struct MedalOutput {
-    pub uuid: String,
-    pub name: String,
-    pub path: String,
+pub struct MedalInfo {
+    pub provider: String,
+    /// Id of the clip the URL points to, the page may list related clips as well.
+    pub clip_id: Option<String>,
+    pub clips: Vec<Clip>,
 }
 
 #[derive(Serialize)]
@@ -48,6 +50,8 @@ pub enum MedalErrorKind {
     NoClipsFound,
     MissingClipContent,
     DownloadFailed,
+    ClipTooLarge,
+    QuotaExceeded,
     ServerIssue,
     NoPermissions,
 }
@@ -79,32 +83,76 @@ impl<'r, 'o: 'r> response::Responder<'r, 'o> for MedalError {
     }
 }
 
-impl<'r, 'o: 'r> response::Responder<'r, 'o> for MedalOutput {
-    fn respond_to(self, req: &Request) -> rocket::response::Result<'o> {
-        let content = match fs::read(&self.path) {
-            Ok(val) => val,
-            Err(e) => {
-                eprintln!("Failed to read file at {:?}: {}", &&self.path, e);
-                return MedalError {
-                    kind: MedalErrorKind::ServerIssue,
-                    status: Status::InternalServerError,
-                    message: e.to_string(),
-                }
-                .respond_to(&req);
+impl From<ClipError> for MedalError {
+    fn from(e: ClipError) -> Self {
+        let kind = match e.kind {
+            ClipErrorKind::RequestFailed => MedalErrorKind::MedalIgnoredRequest,
+            ClipErrorKind::ProviderError => MedalErrorKind::MedalReturnedError,
+            ClipErrorKind::NoBody => MedalErrorKind::MedalNoBody,
+            ClipErrorKind::NoDataFound => MedalErrorKind::NoDataFound,
+            ClipErrorKind::InvalidData => MedalErrorKind::MedalInvalidData,
+            ClipErrorKind::NoClipsFound => MedalErrorKind::NoClipsFound,
+            ClipErrorKind::MissingContent => MedalErrorKind::MissingClipContent,
+            ClipErrorKind::InvalidContent => MedalErrorKind::MedalInvalidData,
+            ClipErrorKind::TooLarge => MedalErrorKind::ClipTooLarge,
+            ClipErrorKind::DownloadFailed => MedalErrorKind::DownloadFailed,
+        };
+        let status = match kind {
+            MedalErrorKind::MedalIgnoredRequest | MedalErrorKind::MedalNoBody => {
+                Status::InternalServerError
             }
+            MedalErrorKind::ClipTooLarge => Status::PayloadTooLarge,
+            _ => Status::NotFound,
         };
 
-        let res = Response::build()
-            .header(Header::new(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}.mp4\"", &self.name),
-            ))
-            .sized_body(content.len(), Cursor::new(content))
-            .finalize();
-        Ok(res)
+        MedalError {
+            kind,
+            status,
+            message: e.message,
+        }
     }
 }
 
+// MARK: Clip info
+/// Lists every clip on the page at `url` with the qualities it can be downloaded in.
+#[get("/api/medal/info?<url>")]
+pub async fn medal_clip_info(
+    _rt: RocketGovernor<'_, RateLimitGuard>,
+    auth: TokenAuth,
+    url: &str,
+) -> Result<Json<MedalInfo>, MedalError> {
+    if !auth.has_permissions_to(PermissionKind::MedalDownload) {
+        return Err(MedalError {
+            kind: MedalErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: "You do not have permission to download medal clips".to_string(),
+        });
+    }
+
+    let provider = match clips::find(url) {
+        Some(provider) => provider,
+        None => {
+            return Err(MedalError {
+                kind: MedalErrorKind::NoDataFound,
+                status: Status::NotFound,
+                message: "Invalid URL supplied".to_string(),
+            });
+        }
+    };
+
+    let client = reqwest::Client::new();
+    let clips = provider.resolve_all(&client, url).await.map_err(|e| {
+        eprintln!("Failed to resolve clips: {}", e);
+        MedalError::from(e)
+    })?;
+
+    Ok(Json(MedalInfo {
+        provider: provider.name().to_string(),
+        clip_id: provider.clip_id(url),
+        clips,
+    }))
+}
+
 // MARK: Download medal clip
 #[get("/api/medal?<url>&<quality>")]
 pub async fn download_medal_clip(
@@ -113,7 +161,7 @@ pub async fn download_medal_clip(
     url: &str,
     quality: Option<u8>,
 ) -> MedalResponse {
-    if !auth.0.has_permissions_to(PermissionKind::MedalDownload) {
+    if !auth.has_permissions_to(PermissionKind::MedalDownload) {
         return MedalResponse::Error(MedalError {
             kind: MedalErrorKind::NoPermissions,
             status: Status::Forbidden,
@@ -121,13 +169,16 @@ pub async fn download_medal_clip(
         });
     }
 
-    if url.is_empty() || url.len() < 20 || !url.starts_with("https://medal.tv") {
-        return MedalResponse::Error(MedalError {
-            kind: MedalErrorKind::NoDataFound,
-            status: Status::NotFound,
-            message: "Invalid URL supplied".to_string(),
-        });
-    }
+    let provider = match clips::find(url) {
+        Some(provider) => provider,
+        None => {
+            return MedalResponse::Error(MedalError {
+                kind: MedalErrorKind::NoDataFound,
+                status: Status::NotFound,
+                message: "Invalid URL supplied".to_string(),
+            });
+        }
+    };
 
     let quality_str = match quality {
         Some(val) => match val {
@@ -135,7 +186,7 @@ pub async fn download_medal_clip(
             1 => "360p".to_string(),
             2 => "720p".to_string(),
             3 => "1080p".to_string(),
-            4 => "".to_string(), // original quality
+            4 => "original".to_string(),
             _ => "720p".to_string(),
         },
         None => "720p".to_string(),
@@ -153,181 +204,125 @@ pub async fn download_medal_clip(
     };
 
     let client = reqwest::Client::new();
-    let request = match client.get(url).build() {
-        Ok(val) => val,
-        Err(e) => {
-            eprintln!("Failed to create a request: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::ServerIssue,
-                status: Status::InternalServerError,
-                message: e.to_string(),
-            });
-        }
-    };
-
-    let response = match client.execute(request).await {
-        Ok(val) => val,
-        Err(e) => {
-            eprintln!("Medal ignored request: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::MedalIgnoredRequest,
-                status: Status::InternalServerError,
-                message: e.to_string(),
-            });
-        }
-    };
-
-    if !response.status().is_success() {
-        return MedalResponse::Error(MedalError {
-            kind: MedalErrorKind::MedalReturnedError,
-            status: Status::NotFound,
-            message: format!("Medal returned an error with status: {}", response.status()),
-        });
-    }
-
-    let body = match response.text().await {
-        Ok(val) => val,
-        Err(e) => {
-            eprintln!("Failed to read response body: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::MedalNoBody,
-                status: Status::InternalServerError,
-                message: e.to_string(),
-            });
-        }
-    };
-
-    let hydration_data_re = Regex::new(r#"var hydrationData=(\{.*\})"#).unwrap();
-    let script_tag = match hydration_data_re.captures(&body) {
-        Some(caps) => caps.get(1).map_or("", |m| m.as_str()),
-        None => {
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::NoDataFound,
-                status: Status::NotFound,
-                message: "Could not find hydration data in response".to_string(),
-            });
-        }
-    };
-
-    let hydration_data = match serde_json::from_str::<Value>(script_tag) {
-        Ok(data) => data,
+    let clip = match provider.resolve(&client, url).await {
+        Ok(clip) => clip,
         Err(e) => {
-            eprintln!("Failed to parse hydration data: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::MedalInvalidData,
-                status: Status::NotFound,
-                message: "Failed to parse hydration data".to_string(),
-            });
+            eprintln!("Failed to resolve clip: {}", e);
+            return MedalResponse::Error(e.into());
         }
     };
 
-    let first_clip_id = match hydration_data["clips"].as_object() {
-        Some(clips) => match clips.keys().next() {
-            Some(id) => id,
-            None => {
-                return MedalResponse::Error(MedalError {
-                    kind: MedalErrorKind::NoClipsFound,
-                    status: Status::NotFound,
-                    message: "Could not find any clips in hydration data".to_string(),
-                });
-            }
-        },
-        None => {
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::NoClipsFound,
-                status: Status::NotFound,
-                message: "Could not find any clip objects in hydration data".to_string(),
-            });
-        }
-    };
-
-    let title = match hydration_data["clips"][first_clip_id]["contentTitle"].as_str() {
-        Some(title) => title,
-        None => {
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::MedalInvalidData,
-                status: Status::NotFound,
-                message: "Could not find title in hydration data".to_string(),
-            });
-        }
-    };
-
-    let content_search_str = format!("contentUrl{}", &quality_str);
-    let content_url = match hydration_data["clips"][first_clip_id][content_search_str].as_str() {
-        Some(url) => url.replace("144", &quality_str),
-        None => {
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::MissingClipContent,
-                status: Status::NotFound,
-                message: "Could not find contentUrl in hydration data".to_string(),
-            });
-        }
+    // Held until the clip is stored and counted
+    let reservation = match quota::reserve(&state, &auth.0, 1, 1).await {
+        Ok(reservation) => reservation,
+        Err(e) => return MedalResponse::Error(quota_error(e)),
     };
+    // The clip may use up what is left of the quota, but not more
+    let max_size_bytes = reservation
+        .remaining
+        .max_size()
+        .map_or(state.config.clips.max_size_bytes, |left| left.min(state.config.clips.max_size_bytes));
 
     let uuid = Uuid::new_v4().to_string();
 
-    let output_path = format!("{}{}-{}", &state.config.upload.upload_location, &uuid, &first_clip_id);
+    let output_path = format!("{}{}-{}", &state.config.upload.upload_location, &uuid, &clip.id);
 
     let video = Video {
-        uuid: uuid.clone(),
+        uuid,
         user: auth.0.id.clone(),
         quality: quality.unwrap_or(12),
         format: 0,
-        vid_id: first_clip_id.to_string(),
-        name: title.to_string(),
+        vid_id: clip.id.clone(),
+        name: clip.title.clone(),
         path: output_path.clone(),
         created: utils::get_current_timestamp().to_string(),
         expires_at: (utils::get_current_timestamp() + 65_321).to_string(),
+        url: url.to_string(),
+        extractor: provider.name().to_string(),
+        batch: String::new(),
+        format_id: String::new(),
+        container: String::new(),
+        section: String::new(),
+        options: String::new(),
     };
 
-    println!("[INFO   ] Downloading medal clip from: {}",  &content_url);
-    let data_request = client.get(content_url);
-    let data_response = match data_request.send().await {
-        Ok(val) => val,
-        Err(e) => {
-            eprintln!("Failed to download medal clip: {}", e);
+    let content = match clip.nearest_quality(&quality_str) {
+        Some(content) => content,
+        None => {
             return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::DownloadFailed,
+                kind: MedalErrorKind::MissingClipContent,
                 status: Status::NotFound,
-                message: e.to_string(),
+                message: "Could not find contentUrl in hydration data".to_string(),
             });
         }
     };
+    if content.label != quality_str {
+        println!(
+            "[INFO  ] Clip {} is not available in {}, using {}",
+            &clip.id, &quality_str, &content.label
+        );
+    }
 
-    let mut file = match File::create(&output_path) {
-        Ok(val) => val,
-        Err(e) => {
-            eprintln!("Failed to create file: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::DownloadFailed,
-                status: Status::NotFound,
-                message: e.to_string(),
-            });
-        } 
-    }; 
-
-    let body = match data_response.bytes().await {
+    println!("[INFO   ] Downloading {} clip {} in {}", provider.name(), &clip.id, &content.label);
+    let data_response = match provider.fetch(&client, content).await {
         Ok(val) => val,
         Err(e) => {
-            eprintln!("Failed to read response body: {}", e);
-            return MedalResponse::Error(MedalError {
-                kind: MedalErrorKind::DownloadFailed,
-                status: Status::NotFound,
-                message: e.to_string(),
-            });
+            eprintln!("Failed to download medal clip: {}", e);
+            return MedalResponse::Error(e.into());
         }
     };
-    file.write_all(&body).unwrap();
+
+    if let Err(e) = clips::save_response(data_response, &output_path, max_size_bytes).await {
+        eprintln!("Failed to download medal clip: {}", e);
+        if e.kind == ClipErrorKind::TooLarge && max_size_bytes < state.config.clips.max_size_bytes {
+            return MedalResponse::Error(quota_error(QuotaError::Exceeded(reservation.remaining)));
+        }
+        return MedalResponse::Error(e.into());
+    }
+
+    if let Err(e) = state
+        .storage
+        .put_file(&output_path, Path::new(&output_path))
+        .await
+    {
+        eprintln!("Failed to store medal clip: {}", e);
+        return MedalResponse::Error(MedalError {
+            kind: MedalErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        });
+    }
 
     if let Err(e) = state.video_db.add(&video).await {
         eprintln!("Failed to add video to database: {}", e);
+    } else {
+        quota::record_video(&state, &video).await;
     }
 
-    MedalResponse::Ok(
-      MedalOutput {
-        uuid,
-        path: output_path,
-        name: title.to_string(),
-      }
-    )
+    match FileStream::open(output_path, format!("{}.mp4", &clip.title), None).await {
+        Ok(stream) => MedalResponse::Ok(stream),
+        Err(e) => MedalResponse::Error(MedalError {
+            kind: MedalErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        }),
+    }
+}
+
+fn quota_error(e: QuotaError) -> MedalError {
+    match e {
+        QuotaError::Exceeded(_) => MedalError {
+            kind: MedalErrorKind::QuotaExceeded,
+            status: Status::PayloadTooLarge,
+            message: "Clip exceeds your quota".to_string(),
+        },
+        QuotaError::Database(e) => {
+            eprintln!("[ERROR] Failed to get quota usage: {}", e);
+            MedalError {
+                kind: MedalErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: "Failed to check quota".to_string(),
+            }
+        }
+    }
 }
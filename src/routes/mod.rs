@@ -5,17 +5,32 @@ use rocket::{
 };
 use rocket_governor::{Quota, RocketGovernable};
 
-use crate::{db::user::User, state::State};
+use crate::{
+    db::user::{ApiKey, PermissionKind, User, API_KEY_PREFIX},
+    state::State,
+};
 
 pub mod api;
+pub mod api_keys;
 pub mod catchers;
 pub mod download;
+pub mod files;
 pub mod index;
+pub mod stream;
 pub mod upload;
+pub mod users;
 pub mod youtube;
 pub mod medal;
 
-pub struct TokenAuth(User);
+pub const SESSION_COOKIE: &str = "session";
+
+pub struct TokenAuth(User, AuthMethod);
+
+pub enum AuthMethod {
+    Session,
+    Token,
+    ApiKey(ApiKey),
+}
 
 #[derive(Debug)]
 pub enum AuthError {
@@ -24,6 +39,25 @@ pub enum AuthError {
     ServerError,
 }
 
+impl TokenAuth {
+    /// Checks the user's role and, for API keys, the key's scopes.
+    pub fn has_permissions_to(&self, kind: PermissionKind) -> bool {
+        self.0.has_permissions_to(kind) && self.allows(kind)
+    }
+
+    /// Checks only the API key scopes, for actions every role may take on its own data.
+    pub fn allows(&self, kind: PermissionKind) -> bool {
+        match &self.1 {
+            AuthMethod::ApiKey(api_key) => api_key.allows(kind),
+            _ => true,
+        }
+    }
+
+    pub fn is_api_key(&self) -> bool {
+        matches!(self.1, AuthMethod::ApiKey(_))
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for TokenAuth {
     type Error = AuthError;
@@ -34,10 +68,34 @@ impl<'r> FromRequest<'r> for TokenAuth {
             Err(_) => return Outcome::Error((Status::InternalServerError, AuthError::ServerError)),
         };
 
-        match request.cookies().get("token") {
-            Some(token_cookie) => match state.user_db.get(&token_cookie.value()).await {
+        // Scripted clients send `Authorization: Bearer <token or API key>`
+        if let Some(authorization) = request.headers().get_one("Authorization") {
+            let token = match authorization.strip_prefix("Bearer ") {
+                Some(token) => token.trim(),
+                None => return Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
+            };
+
+            if token.starts_with(API_KEY_PREFIX) {
+                return match state.user_db.get_by_api_key(token).await {
+                    Ok(Some((user, api_key))) => {
+                        Outcome::Success(TokenAuth(user, AuthMethod::ApiKey(api_key)))
+                    }
+                    Ok(None) => Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
+                    Err(_) => Outcome::Error((Status::InternalServerError, AuthError::ServerError)),
+                };
+            }
+
+            return match state.user_db.get(token).await {
+                Ok(Some(user)) => Outcome::Success(TokenAuth(user, AuthMethod::Token)),
+                Ok(None) => Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
+                Err(_) => Outcome::Error((Status::InternalServerError, AuthError::ServerError)),
+            };
+        }
+
+        match request.cookies().get(SESSION_COOKIE) {
+            Some(session_cookie) => match state.user_db.get_by_session(session_cookie.value()).await {
                 Ok(maybe_user) => match maybe_user {
-                    Some(user) => return Outcome::Success(TokenAuth(user)),
+                    Some(user) => return Outcome::Success(TokenAuth(user, AuthMethod::Session)),
                     None => return Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
                 },
                 Err(_) => {
@@ -52,6 +110,8 @@ impl<'r> FromRequest<'r> for TokenAuth {
 pub struct BaseRateLimitGuard;
 pub struct RateLimitGuard;
 pub struct StrictRateLimitGuard;
+/// Password guesses on protected files.
+pub struct UnlockRateLimitGuard;
 
 impl<'r> RocketGovernable<'r> for BaseRateLimitGuard {
     fn quota(_method: rocket_governor::Method, _route_name: &str) -> Quota {
@@ -70,3 +130,9 @@ impl<'r> RocketGovernable<'r> for StrictRateLimitGuard {
         Quota::per_minute(Self::nonzero(2))
     }
 }
+
+impl<'r> RocketGovernable<'r> for UnlockRateLimitGuard {
+    fn quota(_method: rocket_governor::Method, _route_name: &str) -> Quota {
+        Quota::per_minute(Self::nonzero(5))
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use rocket::{
+    http::{ContentType, Method, Status},
+    request::{FromRequest, Outcome},
+    response, Request, Response,
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::{
+    state::State,
+    storage::{ByteStream, ObjectStat, StorageBackend},
+    utils,
+};
+
+// MARK: Models
+
+/// Streams a stored file, honouring `Range` and conditional request headers.
+pub struct FileStream {
+    pub path: String,
+    pub filename: String,
+    /// Stored content hash, used as a strong `ETag` when known.
+    pub hash: Option<String>,
+    stat: ObjectStat,
+    storage: Arc<dyn StorageBackend>,
+    finish: Finish,
+}
+
+/// Called once the response is done, with whether the whole body was sent.
+pub type FinishHook = Box<dyn FnOnce(bool) + Send>;
+
+/// Runs the hook at most once, with `false` if dropped before the body completed.
+struct Finish(Option<FinishHook>);
+
+/// Request details a download route needs before the body is streamed.
+pub struct StreamRequest {
+    pub range: Option<String>,
+    pub is_head: bool,
+}
+
+impl StreamRequest {
+    /// `HEAD` requests and ranges resuming a transfer are not counted as a new download.
+    pub fn counts_as_download(&self) -> bool {
+        if self.is_head {
+            return false;
+        }
+
+        match &self.range {
+            Some(range) => range.trim().starts_with("bytes=0-"),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Reads the requested range, the backend is only asked once Rocket polls the body.
+struct RangeBody {
+    state: RangeState,
+    remaining: u64,
+    finish: Finish,
+}
+
+enum RangeState {
+    Pending(Pin<Box<dyn Future<Output = io::Result<ByteStream>> + Send>>),
+    Reading(ByteStream),
+}
+
+impl FileStream {
+    /// Looks up `path` in the storage backend, `NotFound` if it is not stored.
+    pub async fn open(path: String, filename: String, hash: Option<String>) -> io::Result<Self> {
+        let storage = State::get()
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .storage
+            .clone();
+
+        let stat = storage.stat(&path).await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} is not stored", &path))
+        })?;
+
+        Ok(Self {
+            path,
+            filename,
+            hash,
+            stat,
+            storage,
+            finish: Finish(None),
+        })
+    }
+
+    /// Runs `hook` once the response is done, e.g. to count finished downloads.
+    /// `Range` is ignored then, so a completed response always sent the whole file.
+    pub fn on_finish(mut self, hook: FinishHook) -> Self {
+        self.finish = Finish(Some(hook));
+        self
+    }
+}
+
+impl Finish {
+    fn done(&mut self, complete: bool) {
+        if let Some(hook) = self.0.take() {
+            hook(complete);
+        }
+    }
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        self.done(false);
+    }
+}
+
+// MARK: Request Guards
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StreamRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(StreamRequest {
+            range: request.headers().get_one("Range").map(|v| v.to_string()),
+            is_head: request.method() == Method::Head,
+        })
+    }
+}
+
+// MARK: Responders
+impl<'r, 'o: 'r> response::Responder<'r, 'o> for FileStream {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let size = self.stat.size;
+        let modified = self.stat.modified;
+        let mut finish = self.finish;
+        let etag = match &self.hash {
+            Some(hash) if hash != "-" => format!("\"{}\"", hash),
+            _ => format!("W/\"{:x}-{:x}\"", size, modified.unwrap_or(0)),
+        };
+
+        let mut res = Response::new();
+        res.set_raw_header("Accept-Ranges", "bytes");
+        // The type comes from the uploaded file name, browsers must not guess a more dangerous one
+        res.set_raw_header("X-Content-Type-Options", "nosniff");
+        res.set_raw_header("ETag", etag.clone());
+        if let Some(modified) = modified {
+            res.set_raw_header("Last-Modified", utils::format_http_date(modified));
+        }
+
+        let headers = req.headers();
+        if is_not_modified(
+            headers.get_one("If-None-Match"),
+            headers.get_one("If-Modified-Since"),
+            &etag,
+            modified,
+        ) {
+            res.set_status(Status::NotModified);
+            return Ok(res);
+        }
+
+        let content_type = utils::get_extension_from_path(&self.filename)
+            .and_then(|ext| ContentType::from_extension(&ext))
+            .unwrap_or(ContentType::Binary);
+        res.set_header(content_type);
+        res.set_raw_header("Content-Disposition", content_disposition(&self.filename));
+
+        let range = match req.headers().get_one("Range") {
+            Some(range)
+                if finish.0.is_none() && if_range_matches(headers.get_one("If-Range"), &etag, modified) =>
+            {
+                parse_range(range, size)
+            }
+            _ => ByteRange::Full,
+        };
+
+        let (start, end) = match range {
+            ByteRange::Full => {
+                if size == 0 {
+                    res.set_sized_body(0, std::io::Cursor::new(Vec::new()));
+                    finish.done(true);
+                    return Ok(res);
+                }
+                (0, size - 1)
+            }
+            ByteRange::Partial(start, end) => {
+                res.set_status(Status::PartialContent);
+                res.set_raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, size));
+                (start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                res.set_status(Status::RangeNotSatisfiable);
+                res.set_raw_header("Content-Range", format!("bytes */{}", size));
+                return Ok(res);
+            }
+        };
+
+        let length = end - start + 1;
+        let (storage, path) = (self.storage, self.path);
+        let body = RangeBody {
+            state: RangeState::Pending(Box::pin(async move {
+                storage.get_range(&path, start, length).await.map_err(|e| {
+                    eprintln!("[ERROR] Storage '{}' failed to read {}: {}", storage.name(), &path, e);
+                    e
+                })
+            })),
+            remaining: length,
+            finish,
+        };
+        res.set_sized_body(length as usize, body);
+
+        Ok(res)
+    }
+}
+
+impl AsyncRead for RangeBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                RangeState::Pending(open) => {
+                    let reader = match open.as_mut().poll(cx) {
+                        Poll::Ready(Ok(reader)) => reader,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    self.state = RangeState::Reading(reader);
+                }
+                RangeState::Reading(reader) => {
+                    let filled = buf.filled().len();
+                    let result = reader.as_mut().poll_read(cx, buf);
+
+                    let read = (buf.filled().len() - filled) as u64;
+                    self.remaining = self.remaining.saturating_sub(read);
+                    if read > 0 && self.remaining == 0 {
+                        self.finish.done(true);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+// Rocket only seeks a sized body to measure it; the size is always preset here
+impl AsyncSeek for RangeBody {
+    fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Stored files can not be seeked"))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+// MARK: Utils
+
+pub fn content_disposition(filename: &str) -> String {
+    let fallback = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let encoded = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect::<String>();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+fn is_not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    modified: Option<u64>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    match (if_modified_since.and_then(utils::parse_http_date), modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn if_range_matches(if_range: Option<&str>, etag: &str, modified: Option<u64>) -> bool {
+    match if_range {
+        None => true,
+        // Weak validators never match for ranges
+        Some(value) if value.starts_with('"') => !etag.starts_with("W/") && value == etag,
+        Some(value) if value.starts_with("W/") => false,
+        Some(value) => utils::parse_http_date(value).is_some_and(|date| Some(date) == modified),
+    }
+}
+
+fn parse_range(value: &str, size: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return ByteRange::Full,
+    };
+
+    // Multiple ranges are allowed to be answered with the full body
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+
+    match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(size.saturating_sub(suffix), size - 1),
+            Err(_) => ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) if start < size => ByteRange::Partial(start, size - 1),
+            Ok(_) => ByteRange::Unsatisfiable,
+            Err(_) => ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start > end => ByteRange::Full,
+            (Ok(start), Ok(end)) if start < size => ByteRange::Partial(start, end.min(size - 1)),
+            (Ok(_), Ok(_)) => ByteRange::Unsatisfiable,
+            _ => ByteRange::Full,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"abc\"";
+    const MODIFIED: u64 = 784_111_777;
+    const MODIFIED_DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), ByteRange::Partial(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range(" bytes= 10 - 20 ", 1000), ByteRange::Partial(10, 20));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_size() {
+        assert_eq!(parse_range("bytes=900-5000", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Partial(0, 999));
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1001", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_invalid_or_multiple_ranges() {
+        assert_eq!(parse_range("items=0-10", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-10,20-30", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=20-10", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=10", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        assert!(is_not_modified(Some(ETAG), None, ETAG, None));
+        assert!(is_not_modified(Some("\"other\", \"abc\""), None, ETAG, None));
+        assert!(is_not_modified(Some("*"), None, ETAG, None));
+        // Weak comparison
+        assert!(is_not_modified(Some("W/\"abc\""), None, ETAG, None));
+        assert!(!is_not_modified(Some("\"other\""), None, ETAG, None));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        assert!(!is_not_modified(Some("\"other\""), Some(MODIFIED_DATE), ETAG, Some(MODIFIED)));
+    }
+
+    #[test]
+    fn matches_if_modified_since() {
+        assert!(is_not_modified(None, Some(MODIFIED_DATE), ETAG, Some(MODIFIED)));
+        assert!(is_not_modified(None, Some(MODIFIED_DATE), ETAG, Some(MODIFIED - 1)));
+        assert!(!is_not_modified(None, Some(MODIFIED_DATE), ETAG, Some(MODIFIED + 1)));
+        assert!(!is_not_modified(None, Some(MODIFIED_DATE), ETAG, None));
+        assert!(!is_not_modified(None, Some("yesterday"), ETAG, Some(MODIFIED)));
+        assert!(!is_not_modified(None, None, ETAG, Some(MODIFIED)));
+    }
+
+    #[test]
+    fn matches_if_range() {
+        assert!(if_range_matches(None, ETAG, None));
+        assert!(if_range_matches(Some(ETAG), ETAG, None));
+        assert!(!if_range_matches(Some("\"other\""), ETAG, None));
+        assert!(if_range_matches(Some(MODIFIED_DATE), ETAG, Some(MODIFIED)));
+        assert!(!if_range_matches(Some(MODIFIED_DATE), ETAG, Some(MODIFIED + 1)));
+    }
+
+    #[test]
+    fn weak_validators_never_match_if_range() {
+        assert!(!if_range_matches(Some("W/\"abc\""), "W/\"abc\"", None));
+        assert!(!if_range_matches(Some("\"abc\""), "W/\"abc\"", None));
+    }
+}
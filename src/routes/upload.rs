@@ -5,6 +5,7 @@
 
 use rocket::data::ByteUnit;
 use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
 use rocket::serde::json::Json;
 use rocket::Data;
 use rocket::{post, response, Request, Response};
@@ -12,16 +13,18 @@ use rocket_governor::RocketGovernor;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
-use std::io::Cursor;
+use std::io::{Cursor, SeekFrom};
 use std::{collections::HashMap, path::Path, sync::Arc};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::{io::AsyncReadExt, sync::RwLock};
 use uuid::Uuid;
 
-use crate::db::file::FileState;
+use crate::db::file::{FileRequest, FileState};
 use crate::db::user::PermissionKind;
+use crate::quota::{self, Allowance, QuotaError};
 use crate::state::State;
+use crate::utils;
 
 use super::{BaseRateLimitGuard, RateLimitGuard, TokenAuth};
 
@@ -31,6 +34,9 @@ pub struct UploadStatus {
     pub state: FileState,
     pub total_bytes: u64,
     pub uploaded_bytes: u64,
+    pub last_activity: u64,
+    #[serde(skip)]
+    pub hasher: Sha256,
 }
 
 #[derive(Deserialize)]
@@ -39,6 +45,18 @@ pub struct UploadRequest {
     pub file_name: String,
     pub file_hash: String,
     pub expires_at: u64,
+    /// The browser encrypted the file, the key never reaches the server.
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Downloads have to enter this password first.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The file is deleted after this many downloads, 0 for no limit.
+    #[serde(default)]
+    pub max_downloads: u32,
+    /// Preset for a single download, deleted once it finished.
+    #[serde(default)]
+    pub burn_after_reading: bool,
 }
 
 #[derive(Serialize)]
@@ -47,6 +65,9 @@ pub struct UploadRequestResponse {
     pub upload_id: String,
 }
 
+/// Argon2 takes any length, this only keeps hashing cheap.
+const MAX_PASSWORD_LEN: usize = 1024;
+
 pub type UploadStatusMap = Arc<RwLock<HashMap<String, UploadStatus>>>;
 
 #[derive(Serialize)]
@@ -56,8 +77,22 @@ pub struct UploadResponse {
     size: i64,
 }
 
-#[derive(Serialize, Debug)]
+pub struct UploadChunkResponse {
+    pub uuid: String,
+    pub state: FileState,
+    pub offset: u64,
+    pub total_bytes: u64,
+    pub hash: Option<String>,
+}
+
+pub struct ChunkHeaders {
+    pub content_range: Option<String>,
+    pub upload_offset: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
 pub enum UploadErrorKind {
+    #[default]
     Unknown,
     InvalidUuid,
     InvalidDataSupplied,
@@ -67,15 +102,34 @@ pub enum UploadErrorKind {
     FileMissing,
     ServerIssue,
     NoPermissions,
+    OffsetMismatch,
+    HashMismatch,
+    QuotaExceeded,
+}
+
+// MARK: Request Guards
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ChunkHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(ChunkHeaders {
+            content_range: headers.get_one("Content-Range").map(|v| v.to_string()),
+            upload_offset: headers.get_one("Upload-Offset").map(|v| v.to_string()),
+        })
+    }
 }
 
 // MARK: Responders
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Default)]
 pub struct UploadError {
     pub uuid: Option<String>,
     pub kind: UploadErrorKind,
     pub status: Status,
     pub message: Option<String>,
+    /// What is left of the user's quota, set with `QuotaExceeded`.
+    pub remaining: Option<Allowance>,
 }
 
 #[rocket::async_trait]
@@ -95,12 +149,15 @@ impl<'r, 'o: 'r> response::Responder<'r, 'o> for UploadError {
 
         let mut res = Response::new();
         res.set_status(self.status);
-        let body = json!({
+        let mut body = json!({
             "status": self.status.code,
             "uuid": m_uuid,
             "kind": self.kind,
             "message": self.message
         });
+        if let Some(remaining) = self.remaining {
+            body["remaining"] = json!(remaining);
+        }
         let body = serde_json::to_string(&body).unwrap();
         println!("[DEBUG ] (Upload Error) {}", &body);
         res.set_sized_body(body.len(), Cursor::new(body));
@@ -109,6 +166,27 @@ impl<'r, 'o: 'r> response::Responder<'r, 'o> for UploadError {
     }
 }
 
+impl<'r, 'o: 'r> response::Responder<'r, 'o> for UploadChunkResponse {
+    fn respond_to(self, _: &Request) -> rocket::response::Result<'o> {
+        let mut res = Response::new();
+        res.set_raw_header("Upload-Offset", self.offset.to_string());
+        res.set_raw_header("Upload-Length", self.total_bytes.to_string());
+        res.set_raw_header("Cache-Control", "no-store");
+
+        let body = json!({
+            "uuid": self.uuid,
+            "state": self.state,
+            "offset": self.offset,
+            "total_bytes": self.total_bytes,
+            "hash": self.hash,
+        });
+        let body = serde_json::to_string(&body).unwrap();
+        res.set_sized_body(body.len(), Cursor::new(body));
+        res.set_header(Header::new("Content-Type", "application/json"));
+        Ok(res)
+    }
+}
+
 // MARK: Upload Request
 #[post("/api/upload/request", format = "json", data = "<data>")]
 pub async fn request_upload<'r>(
@@ -116,12 +194,13 @@ pub async fn request_upload<'r>(
     auth: TokenAuth,
     data: Json<UploadRequest>,
 ) -> Result<Json<UploadRequestResponse>, UploadError> {
-    if !auth.0.has_permissions_to(PermissionKind::FileUpload) {
+    if !auth.has_permissions_to(PermissionKind::FileUpload) {
         return Err(UploadError {
             uuid: None,
             kind: UploadErrorKind::NoPermissions,
             status: Status::Forbidden,
             message: None,
+            ..Default::default()
         });
     }
 
@@ -133,6 +212,7 @@ pub async fn request_upload<'r>(
                 kind: UploadErrorKind::ServerIssue,
                 status: Status::InternalServerError,
                 message: None,
+                ..Default::default()
             })
         }
     };
@@ -145,14 +225,70 @@ pub async fn request_upload<'r>(
             message: Some(format!(
                 "File is too large. Max size is {}",
                 state.config.upload.max_size_bytes
-            ))
+            )),
+            ..Default::default()
+        });
+    }
+
+    let file_hash = data.0.file_hash.to_ascii_lowercase();
+    if file_hash != "-"
+        && (file_hash.len() != 64 || !file_hash.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(UploadError {
+            uuid: None,
+            kind: UploadErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: Some("file_hash must be a hex SHA-256 digest or '-'".to_string()),
+            ..Default::default()
         });
     }
 
-    // Check if the file already exists
+    // Held until the file is added and logged, the row counts towards the quota from then on
+    let _reservation = quota::reserve(&state, &auth.0, 1, data.0.file_size)
+        .await
+        .map_err(|e| match e {
+            QuotaError::Exceeded(remaining) => UploadError {
+                uuid: None,
+                kind: UploadErrorKind::QuotaExceeded,
+                status: Status::PayloadTooLarge,
+                message: Some("Upload exceeds your quota".to_string()),
+                remaining: Some(remaining),
+            },
+            QuotaError::Database(e) => {
+                eprintln!("[ERROR] Failed to get quota usage: {}", e);
+                UploadError {
+                    uuid: None,
+                    kind: UploadErrorKind::ServerIssue,
+                    status: Status::InternalServerError,
+                    message: Some("Failed to check quota".to_string()),
+                    ..Default::default()
+                }
+            }
+        })?;
+
+    let password_hash = match data.0.password.as_deref().filter(|password| !password.is_empty()) {
+        Some(password) => hash_password(password).await?,
+        None => String::new(),
+    };
+
+    // Access options are part of the insert, so the file is never served without them
+    let request = FileRequest {
+        name: data.0.file_name,
+        size: data.0.file_size,
+        declared_hash: file_hash,
+        expires_at: data.0.expires_at,
+        owner: auth.0.id,
+        encrypted: data.0.encrypted,
+        password_hash,
+        max_downloads: if data.0.burn_after_reading { 1 } else { data.0.max_downloads },
+    };
+
+    // Check if the user already uploaded this file,
+    // encrypted uploads use a fresh key every time so there is nothing to share
+    let dedup_hash = if request.encrypted { "-" } else { request.declared_hash.as_str() };
     if let Some(existing_file) = state
         .file_db
-        .get_by_hash(&data.0.file_hash)
+        .get_by_hash_for_owner(dedup_hash, auth.0.id)
         .await
         .map_err(|e| {
             eprintln!(
@@ -164,26 +300,43 @@ pub async fn request_upload<'r>(
                 kind: UploadErrorKind::ServerIssue,
                 status: Status::InternalServerError,
                 message: Some("Failed to check if file exists".to_string()),
+                ..Default::default()
             }
         })
         .unwrap()
     {
-        return Ok(Json(UploadRequestResponse {
-            approved: false,
-            upload_id: existing_file.uuid,
-        }));
+        // Files stored before blobs existed can't be shared, hand out the file itself
+        if existing_file.blob.is_empty() && request.password_hash.is_empty() && request.max_downloads == 0 {
+            return Ok(Json(UploadRequestResponse {
+                approved: false,
+                upload_id: existing_file.uuid,
+            }));
+        }
+
+        // Otherwise the upload gets its own file pointing at the stored blob
+        let upload_id = Uuid::new_v4().to_string();
+        let _blob_lock = state.file_db.lock_blobs().await;
+        if let Ok(Some(blob)) = state.file_db.get_blob(&existing_file.blob).await {
+            if let Err(e) = state
+                .file_db
+                .add_from_blob(&upload_id, &request, &blob)
+                .await
+            {
+                eprintln!("[ERROR] Database 'FileDB' failed to add file from blob: {}", e);
+            } else {
+                return Ok(Json(UploadRequestResponse {
+                    approved: false,
+                    upload_id,
+                }));
+            }
+        }
     }
 
     let upload_id = Uuid::new_v4().to_string();
 
     state
         .file_db
-        .add_from_request(
-            &upload_id,
-            data.0.file_name,
-            data.0.file_size,
-            data.0.expires_at,
-        )
+        .add_from_request(&upload_id, request)
         .await
         .map_err(|e| {
             eprintln!("[ERROR] Database 'FileDB' failed to add file: {}", e);
@@ -192,10 +345,15 @@ pub async fn request_upload<'r>(
                 kind: UploadErrorKind::InvalidDataSupplied,
                 status: Status::InternalServerError,
                 message: Some("Failed to add file to DB".to_string()),
+                ..Default::default()
             }
         })
         .unwrap();
 
+    if let Err(e) = state.file_db.log_upload(auth.0.id, data.0.file_size).await {
+        eprintln!("[ERROR] Database 'FileDB' failed to log upload: {}", e);
+    }
+
     let mut status_map = state.upload_status.write().await;
     status_map.insert(
         upload_id.clone(),
@@ -203,6 +361,8 @@ pub async fn request_upload<'r>(
             state: FileState::AwaitingData,
             total_bytes: data.0.file_size,
             uploaded_bytes: 0,
+            last_activity: utils::get_current_timestamp(),
+            hasher: Sha256::new(),
         },
     );
 
@@ -223,183 +383,577 @@ pub async fn upload_file<'r>(
     let uuid = uuid_raw.to_string();
 
     // Check user permissions
-    if !auth.0.has_permissions_to(PermissionKind::FileUpload) {
+    if !auth.has_permissions_to(PermissionKind::FileUpload) {
         return Err(UploadError {
-            uuid: Some(uuid.clone()),
+            uuid: None,
+            kind: UploadErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: None,
+            ..Default::default()
+        });
+    }
+
+    // The whole body is sent at once, so this is a single chunk starting at 0
+    match receive_chunk(&uuid, auth.0.id, 0, None, data).await? {
+        ChunkOutcome::Finished(response) => Ok(Json(response)),
+        ChunkOutcome::Partial(status) => Err(UploadError {
+            uuid: None,
+            kind: UploadErrorKind::UploadCanceled,
+            status: Status::BadRequest,
+            message: Some(format!(
+                "Upload interrupted at byte {}, resume with PATCH /api/upload/{}",
+                status.uploaded_bytes, uuid
+            )),
+            ..Default::default()
+        }),
+    }
+}
+
+// MARK: Upload Chunk
+#[patch("/api/upload/<uuid_raw>", data = "<data>")]
+pub async fn upload_chunk<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid_raw: &str,
+    headers: ChunkHeaders,
+    data: Data<'_>,
+) -> Result<UploadChunkResponse, UploadError> {
+    let uuid = uuid_raw.to_string();
+
+    if !auth.has_permissions_to(PermissionKind::FileUpload) {
+        return Err(UploadError {
+            uuid: None,
             kind: UploadErrorKind::NoPermissions,
             status: Status::Forbidden,
             message: None,
+            ..Default::default()
+        });
+    }
+
+    let (offset, length) = if let Some(content_range) = &headers.content_range {
+        let (start, length, total) = match parse_content_range(content_range) {
+            Some(range) => range,
+            None => {
+                return Err(UploadError {
+                    uuid: None,
+                    kind: UploadErrorKind::InvalidDataSupplied,
+                    status: Status::BadRequest,
+                    message: Some(format!("Invalid Content-Range header: {}", content_range)),
+                    ..Default::default()
+                })
+            }
+        };
+
+        if let Some(total) = total {
+            let state = match State::get().await {
+                Ok(state) => state,
+                Err(_) => {
+                    return Err(UploadError {
+                        uuid: None,
+                        kind: UploadErrorKind::ServerIssue,
+                        status: Status::InternalServerError,
+                        message: None,
+                        ..Default::default()
+                    })
+                }
+            };
+
+            let expected_total = state
+                .upload_status
+                .read()
+                .await
+                .get(&uuid)
+                .map(|status| status.total_bytes);
+            if expected_total.is_some_and(|expected| expected != total) {
+                return Err(UploadError {
+                    uuid: None,
+                    kind: UploadErrorKind::InvalidDataSupplied,
+                    status: Status::BadRequest,
+                    message: Some(format!(
+                        "Content-Range total {} does not match the requested file size",
+                        total
+                    )),
+                    ..Default::default()
+                });
+            }
+        }
+
+        (start, Some(length))
+    } else if let Some(upload_offset) = &headers.upload_offset {
+        match upload_offset.trim().parse::<u64>() {
+            Ok(offset) => (offset, None),
+            Err(_) => {
+                return Err(UploadError {
+                    uuid: None,
+                    kind: UploadErrorKind::InvalidDataSupplied,
+                    status: Status::BadRequest,
+                    message: Some(format!("Invalid Upload-Offset header: {}", upload_offset)),
+                    ..Default::default()
+                })
+            }
+        }
+    } else {
+        return Err(UploadError {
+            uuid: None,
+            kind: UploadErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: Some("Missing Content-Range or Upload-Offset header".to_string()),
+            ..Default::default()
+        });
+    };
+
+    match receive_chunk(&uuid, auth.0.id, offset, length, data).await? {
+        ChunkOutcome::Finished(response) => Ok(UploadChunkResponse {
+            uuid,
+            state: FileState::Finishing,
+            offset: response.size as u64,
+            total_bytes: response.size as u64,
+            hash: Some(response.hash),
+        }),
+        ChunkOutcome::Partial(status) => Ok(UploadChunkResponse {
+            uuid,
+            state: status.state,
+            offset: status.uploaded_bytes,
+            total_bytes: status.total_bytes,
+            hash: None,
+        }),
+    }
+}
+
+// MARK: Upload Offset
+#[head("/api/upload/<uuid_raw>")]
+pub async fn upload_offset<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid_raw: &str,
+) -> Result<UploadChunkResponse, Status> {
+    let state = match State::get().await {
+        Ok(state) => state,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    // Other users' uploads look like they don't exist
+    match state.file_db.get_by_uuid(uuid_raw).await {
+        Ok(Some(file)) if file.owner == auth.0.id => {}
+        Ok(_) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    let status_map = state.upload_status.read().await;
+    match status_map.get(uuid_raw) {
+        Some(status) => Ok(UploadChunkResponse {
+            uuid: uuid_raw.to_string(),
+            state: status.state.clone(),
+            offset: status.uploaded_bytes,
+            total_bytes: status.total_bytes,
+            hash: None,
+        }),
+        None => Err(Status::NotFound),
+    }
+}
+
+// MARK: Chunk Handling
+enum ChunkOutcome {
+    Partial(UploadStatus),
+    Finished(UploadResponse),
+}
+
+/// Puts a claimed upload back to `Incomplete` if its request ends without doing so,
+/// e.g. when the handler is dropped with the connection.
+struct UploadClaim {
+    uuid: Option<String>,
+    upload_status: UploadStatusMap,
+}
+
+impl UploadClaim {
+    fn release(mut self) {
+        self.uuid = None;
+    }
+}
+
+impl Drop for UploadClaim {
+    fn drop(&mut self) {
+        let uuid = match self.uuid.take() {
+            Some(uuid) => uuid,
+            None => return,
+        };
+
+        let upload_status = self.upload_status.clone();
+        tokio::spawn(async move {
+            if let Some(status) = upload_status.write().await.get_mut(&uuid) {
+                if status.state == FileState::Uploading {
+                    status.state = FileState::Incomplete;
+                }
+            }
         });
     }
+}
+
+/// Streams one chunk of data into the upload at `offset`.
+///
+/// Data that made it to disk before the connection dropped is kept,
+/// so the client can ask for the offset and continue from there.
+async fn receive_chunk(
+    uuid: &str,
+    owner: u16,
+    offset: u64,
+    length: Option<u64>,
+    data: Data<'_>,
+) -> Result<ChunkOutcome, UploadError> {
+    let uuid = uuid.to_string();
 
     // Get the state
     let state = match State::get().await {
         Ok(state) => state,
         Err(_) => {
             return Err(UploadError {
-                uuid: Some(uuid.clone()),
+                uuid: None,
                 kind: UploadErrorKind::ServerIssue,
                 status: Status::InternalServerError,
                 message: None,
+                ..Default::default()
             })
         }
     };
 
-    // Check if the file is already being uploaded or was already uploaded
-    let state_clone = state.upload_status.clone();
-    if let Some(status) = state_clone.write().await.get_mut(&uuid) {
-        if status.state != FileState::AwaitingData {
+    // Get the temporary file from the database
+    let db_file = match state.file_db.get_by_uuid(&uuid).await {
+        // Other users' uploads look like they don't exist
+        Ok(Some(file)) if file.owner != owner => {
             return Err(UploadError {
-                uuid: Some(uuid.clone()),
-                kind: UploadErrorKind::AlreadyInProgress,
+                uuid: None,
+                kind: UploadErrorKind::InvalidUuid,
                 status: Status::BadRequest,
                 message: None,
-            });
+                ..Default::default()
+            })
         }
-
-        status.state = FileState::Uploading;
-        status.uploaded_bytes = 0;
-    } else {
-        return Err(UploadError {
-            uuid: Some(uuid.clone()),
-            kind: UploadErrorKind::InvalidUuid,
-            status: Status::BadRequest,
-            message: None,
-        });
-    }
-
-    // Get the temporary file from the database
-    let mut db_file = match state
-        .file_db
-        .get_by_uuid(&uuid)
-        .await
-        .map_err(|_| UploadError {
-            uuid: Some(uuid.clone()),
-            kind: UploadErrorKind::ServerIssue,
-            status: Status::InternalServerError,
-            message: Some("Failed to get file from DB".to_string()),
-        })
-        .unwrap()
-    {
-        Some(file) => file,
-        None => {
+        Ok(Some(file)) => file,
+        Ok(None) => {
             return Err(UploadError {
                 uuid: Some(uuid.clone()),
                 kind: UploadErrorKind::InvalidUuid,
                 status: Status::BadRequest,
                 message: None,
+                ..Default::default()
+            })
+        }
+        Err(_) => {
+            return Err(UploadError {
+                uuid: None,
+                kind: UploadErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: Some("Failed to get file from DB".to_string()),
+                ..Default::default()
             })
         }
     };
 
-    // Create the file
-    let save_path = Path::new(&db_file.path);
-    let save_parent = save_path.parent().unwrap();
-    if !save_parent.exists() {
-        std::fs::create_dir_all(save_parent).unwrap();
-    }
+    // Check if the upload accepts data at this offset and claim it
+    let (total_bytes, mut hasher) = {
+        let mut status_map = state.upload_status.write().await;
+        let status = match status_map.get_mut(&uuid) {
+            Some(status) => status,
+            None => {
+                return Err(UploadError {
+                    uuid: None,
+                    kind: UploadErrorKind::InvalidUuid,
+                    status: Status::BadRequest,
+                    message: None,
+                    ..Default::default()
+                })
+            }
+        };
 
-    let mut file = fs::File::create(&db_file.path)
-        .await
-        .map_err(|e| UploadError {
-            uuid: Some(uuid.clone()),
-            kind: UploadErrorKind::FileMissing,
-            status: Status::InternalServerError,
-            message: Some(e.to_string()),
-        })?;
+        if status.state != FileState::AwaitingData && status.state != FileState::Incomplete {
+            return Err(UploadError {
+                uuid: None,
+                kind: UploadErrorKind::AlreadyInProgress,
+                status: Status::Conflict,
+                message: None,
+                ..Default::default()
+            });
+        }
+
+        if status.uploaded_bytes != offset {
+            return Err(UploadError {
+                uuid: None,
+                kind: UploadErrorKind::OffsetMismatch,
+                status: Status::Conflict,
+                message: Some(format!(
+                    "Expected data at offset {}, got {}",
+                    status.uploaded_bytes, offset
+                )),
+                ..Default::default()
+            });
+        }
 
-    // Create the hasher
-    let mut hasher = Sha256::new();
-    let mut file_size: u64 = 0;
+        status.state = FileState::Uploading;
+        status.last_activity = utils::get_current_timestamp();
+        (status.total_bytes, status.hasher.clone())
+    };
+    let claim = UploadClaim {
+        uuid: Some(uuid.clone()),
+        upload_status: state.upload_status.clone(),
+    };
 
+    // Open the file and drop anything past the last confirmed byte
+    let mut file = match open_upload_file(&db_file.path, offset).await {
+        Ok(file) => file,
+        Err(e) => {
+            return Err(UploadError {
+                uuid: Some(uuid.clone()),
+                kind: UploadErrorKind::FileMissing,
+                status: Status::InternalServerError,
+                message: Some(e.to_string()),
+                ..Default::default()
+            })
+        }
+    };
 
-    // Loop through the file data and write it to the file
-    let mut stream = data.open(ByteUnit::from(state.config.upload.max_size_bytes.clone()));
+    // Loop through the chunk data and write it to the file
+    let remaining = total_bytes - offset;
+    let limit = length.map_or(remaining, |length| length.min(remaining));
+    let mut stream = data.open(ByteUnit::from(limit));
     let mut buffer = [0u8; 8192]; // 8 KiB buffer
+    let mut uploaded_bytes = offset;
+    let mut interrupted: Option<String> = None;
 
-    drop(state);
     loop {
         match stream.read(&mut buffer).await {
             Ok(0) => break, // End Of File
             Ok(n) => {
                 let chunk = &buffer[..n];
 
-                if file.write_all(chunk).await.is_err() {
+                if let Err(e) = file.write_all(chunk).await {
+                    eprintln!("[ERROR] Failed to write upload data: {}", e);
+                    interrupted = Some(e.to_string());
                     break;
                 }
 
                 hasher.update(chunk);
+                uploaded_bytes += n as u64;
 
-                file_size += n as u64;
-
-                // Update upload status
-                if let Some(status) = state_clone.write().await.get_mut(&uuid) {
-                    status.uploaded_bytes += n as u64;
+                // Update upload status, the hasher too so a dropped request can resume here
+                if let Some(status) = state.upload_status.write().await.get_mut(&uuid) {
+                    status.uploaded_bytes = uploaded_bytes;
+                    status.hasher = hasher.clone();
+                    status.last_activity = utils::get_current_timestamp();
                 }
             }
             Err(e) => {
                 eprintln!("[ERROR] Failed to read stream data: {}", e);
+                interrupted = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = file.flush().await {
+        eprintln!("[ERROR] Failed to flush upload data: {}", e);
+        interrupted = Some(e.to_string());
+    }
+
+    // Keep what was received and wait for the next chunk
+    if interrupted.is_some() || uploaded_bytes < total_bytes {
+        let mut status_map = state.upload_status.write().await;
+        let status = match status_map.get_mut(&uuid) {
+            Some(status) => status,
+            None => {
                 return Err(UploadError {
-                    uuid: Some(uuid.clone()),
+                    uuid: None,
                     kind: UploadErrorKind::UploadCanceled,
                     status: Status::BadRequest,
                     message: None,
-                });
+                    ..Default::default()
+                })
             }
+        };
+        status.state = FileState::Incomplete;
+        status.uploaded_bytes = uploaded_bytes;
+        status.hasher = hasher;
+        claim.release();
+
+        if let Some(message) = interrupted {
+            return Err(UploadError {
+                uuid: None,
+                kind: UploadErrorKind::UploadCanceled,
+                status: Status::BadRequest,
+                message: Some(format!(
+                    "Upload interrupted at byte {}: {}",
+                    uploaded_bytes, message
+                )),
+                ..Default::default()
+            });
         }
+
+        return Ok(ChunkOutcome::Partial(status.clone()));
     }
 
     // Update upload status
-    if let Some(status) = state_clone.write().await.get_mut(&uuid) {
+    if let Some(status) = state.upload_status.write().await.get_mut(&uuid) {
         status.state = FileState::Finishing;
     }
+    claim.release();
 
     // Finalize the hash
-    let hash_str = hex::encode(hasher.clone().finalize());
+    let hash_str = hex::encode(hasher.finalize());
 
-    let state = match State::get().await {
-        Ok(state) => state,
-        Err(_) => {
+    // Compare with the hash the client declared when requesting the upload
+    if db_file.declared_hash != "-" && db_file.declared_hash != hash_str {
+        if let Err(e) = fs::remove_file(&db_file.path).await {
+            eprintln!("[ERROR] Failed to remove mismatched upload: {}", e);
+        }
+
+        return Err(UploadError {
+            uuid: Some(uuid.clone()),
+            kind: UploadErrorKind::HashMismatch,
+            status: Status::UnprocessableEntity,
+            message: Some(format!(
+                "Declared hash {} does not match received data ({})",
+                db_file.declared_hash, hash_str
+            )),
+            ..Default::default()
+        });
+    }
+
+    // Content is stored once per hash, the upload only references the blob
+    let blob_path = format!("{}{}", state.config.upload.upload_location, hash_str);
+    let _blob_lock = state.file_db.lock_blobs().await;
+    let existing_blob = match state.file_db.get_blob(&hash_str).await {
+        Ok(blob) => blob,
+        Err(e) => {
+            eprintln!("[ERROR] Database 'FileDB' failed to get blob: {}", e);
             return Err(UploadError {
                 uuid: Some(uuid.clone()),
                 kind: UploadErrorKind::ServerIssue,
                 status: Status::InternalServerError,
-                message: None,
-            })
+                message: Some("Failed to check stored files".to_string()),
+                ..Default::default()
+            });
         }
     };
 
-    // Update the database
-    db_file.hash = hash_str.clone();
-    db_file.size = file_size as i64;
+    let stored = match &existing_blob {
+        Some(_) => fs::remove_file(&db_file.path).await,
+        // Hand the finished file to the storage backend
+        None => {
+            state
+                .storage
+                .put_file(&blob_path, Path::new(&db_file.path))
+                .await
+        }
+    };
+    if let Err(e) = stored {
+        eprintln!("[ERROR] Storage '{}' failed to store upload: {}", state.storage.name(), e);
+        return Err(UploadError {
+            uuid: Some(uuid.clone()),
+            kind: UploadErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: Some("Failed to store file".to_string()),
+            ..Default::default()
+        });
+    }
 
-    state
+    // Update the database
+    if let Err(e) = state
         .file_db
-        .update_data(&uuid, db_file)
+        .attach_blob(&uuid, &hash_str, &blob_path, uploaded_bytes as i64)
         .await
-        .map_err(|e| {
-            eprintln!("[ERROR] Database 'FileDB' failed to update file: {}", e);
-            UploadError {
-                uuid: Some(uuid.clone()),
-                kind: UploadErrorKind::ServerIssue,
-                status: Status::InternalServerError,
-                message: Some("Failed to update file in DB".to_string()),
-            }
-        })
-        .unwrap();
+    {
+        eprintln!("[ERROR] Database 'FileDB' failed to update file: {}", e);
+        if existing_blob.is_none() {
+            let _ = state.storage.delete(&blob_path).await;
+        }
+        return Err(UploadError {
+            uuid: Some(uuid.clone()),
+            kind: UploadErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: Some("Failed to update file in DB".to_string()),
+            ..Default::default()
+        });
+    }
 
     // Remove from upload status
     if state.upload_status.write().await.remove(&uuid).is_none() {
         eprintln!("[WARN  ] Failed to remove upload status for UUID: {}", uuid);
     }
 
-    Ok(Json(UploadResponse {
+    Ok(ChunkOutcome::Finished(UploadResponse {
         uuid,
         hash: hash_str,
-        size: file_size as i64,
+        size: uploaded_bytes as i64,
     }))
 }
 
+/// Hashes off the async runtime, Argon2 is slow on purpose.
+async fn hash_password(password: &str) -> Result<String, UploadError> {
+    if password.len() > MAX_PASSWORD_LEN {
+        return Err(UploadError {
+            uuid: None,
+            kind: UploadErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: Some(format!("password must be at most {} bytes", MAX_PASSWORD_LEN)),
+            ..Default::default()
+        });
+    }
+
+    let password = password.to_string();
+    match tokio::task::spawn_blocking(move || utils::hash_password(&password)).await {
+        Ok(Ok(hash)) => Ok(hash),
+        result => {
+            eprintln!("[ERROR] Failed to hash file password: {:?}", result.map(|_| ()));
+            Err(UploadError {
+                uuid: None,
+                kind: UploadErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: Some("Failed to hash password".to_string()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+async fn open_upload_file(path: &str, offset: u64) -> std::io::Result<fs::File> {
+    let save_parent = Path::new(path).parent().unwrap();
+    if !save_parent.exists() {
+        fs::create_dir_all(save_parent).await?;
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    Ok(file)
+}
+
+/// Parses `bytes <start>-<end>/<total>`, where total may be `*`.
+/// Returns the start offset, the length of the range and the total.
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let range = value.trim().strip_prefix("bytes")?.trim();
+    let (span, total) = range.split_once('/')?;
+    let (start, end) = span.split_once('-')?;
+
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?;
+    let length = end.checked_sub(start)?.checked_add(1)?;
+
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse::<u64>().ok()?),
+    };
+    if total.is_some_and(|total| end >= total) {
+        return None;
+    }
+
+    Some((start, length, total))
+}
+
 // MARK: Get Upload Status
 #[get("/api/upload_status/<upload_id>")]
 pub async fn get_upload_status<'r>(
@@ -418,3 +972,50 @@ pub async fn get_upload_status<'r>(
         Err(Status::NotFound)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_ranges() {
+        assert_eq!(parse_content_range("bytes 0-99/200"), Some((0, 100, Some(200))));
+        assert_eq!(parse_content_range(" bytes 100-199/200 "), Some((100, 100, Some(200))));
+        assert_eq!(parse_content_range("bytes 5-5/6"), Some((5, 1, Some(6))));
+    }
+
+    #[test]
+    fn parses_unknown_totals() {
+        assert_eq!(parse_content_range("bytes 0-99/*"), Some((0, 100, None)));
+        assert_eq!(parse_content_range("bytes 10-19/ * "), Some((10, 10, None)));
+    }
+
+    #[test]
+    fn rejects_reversed_ranges() {
+        assert_eq!(parse_content_range("bytes 100-99/200"), None);
+        assert_eq!(parse_content_range("bytes 1-0/*"), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_ranges() {
+        let value = format!("bytes 0-{}/*", u64::MAX);
+        assert_eq!(parse_content_range(&value), None);
+        let value = format!("bytes {}-{}/*", u64::MAX, u64::MAX);
+        assert_eq!(parse_content_range(&value), Some((u64::MAX, 1, None)));
+    }
+
+    #[test]
+    fn rejects_ranges_past_total() {
+        assert_eq!(parse_content_range("bytes 0-200/200"), None);
+        assert_eq!(parse_content_range("bytes 150-250/200"), None);
+        assert_eq!(parse_content_range("bytes 0-0/0"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        assert_eq!(parse_content_range("0-99/200"), None);
+        assert_eq!(parse_content_range("bytes 0-99"), None);
+        assert_eq!(parse_content_range("bytes a-99/200"), None);
+        assert_eq!(parse_content_range("bytes 0-99/abc"), None);
+    }
+}
@@ -0,0 +1,352 @@
+use std::io::Cursor;
+
+use rocket::{
+    http::{ContentType, Status},
+    response,
+    serde::json::Json,
+    Request, Response,
+};
+use rocket_governor::RocketGovernor;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::user::{PermissionKind, User, UserKind},
+    state::State,
+    utils,
+};
+
+use super::{RateLimitGuard, TokenAuth};
+
+// MARK: Models
+#[derive(Serialize)]
+pub struct UserInfo {
+    pub id: u16,
+    pub name: String,
+    pub kind: UserKind,
+    pub revoked: bool,
+    pub from_config: bool,
+}
+
+#[derive(Serialize)]
+pub struct UserTokenResponse {
+    pub user: UserInfo,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserCreateRequest {
+    pub name: String,
+    pub kind: UserKind,
+}
+
+#[derive(Deserialize)]
+pub struct UserUpdateRequest {
+    pub name: Option<String>,
+    pub kind: Option<UserKind>,
+    pub revoked: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum UserErrorKind {
+    UserNotFound,
+    InvalidDataSupplied,
+    NameTaken,
+    ServerIssue,
+    NoPermissions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserError {
+    pub kind: UserErrorKind,
+    pub status: Status,
+    pub message: String,
+}
+
+impl UserInfo {
+    pub fn from_user(user: &User) -> Self {
+        Self {
+            id: user.id,
+            name: user.name.clone(),
+            kind: user.kind(),
+            revoked: user.revoked,
+            from_config: user.from_config,
+        }
+    }
+}
+
+// MARK: Responders
+impl<'r, 'o: 'r> response::Responder<'r, 'o> for UserError {
+    fn respond_to(self, _: &Request) -> rocket::response::Result<'o> {
+        let mut res = Response::new();
+        res.set_status(self.status);
+
+        let body = json!({
+            "status": self.status.code,
+            "error": self.kind,
+            "message": self.message
+        })
+        .to_string();
+        res.set_sized_body(body.len(), Cursor::new(body));
+        res.set_header(ContentType::new("application", "json"));
+        Ok(res)
+    }
+}
+
+// MARK: List users
+#[get("/api/users")]
+pub async fn list_users<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+) -> Result<Json<Vec<UserInfo>>, UserError> {
+    check_user_manage(&auth)?;
+
+    let state = get_state().await?;
+    match state.user_db.get_all().await {
+        Ok(users) => Ok(Json(users.iter().map(UserInfo::from_user).collect())),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to list users: {}", e);
+            Err(UserError {
+                kind: UserErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+// MARK: Create user
+#[post("/api/users", format = "json", data = "<data>")]
+pub async fn create_user<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    data: Json<UserCreateRequest>,
+) -> Result<Json<UserTokenResponse>, UserError> {
+    check_user_manage(&auth)?;
+
+    let name = validate_name(&data.0.name)?;
+    let state = get_state().await?;
+    let token = utils::generate_token();
+
+    let id = match state.user_db.insert(&name, data.0.kind.clone(), &token).await {
+        Ok(result) => result.last_insert_rowid() as u16,
+        Err(e) => return Err(map_write_error(e)),
+    };
+
+    Ok(Json(UserTokenResponse {
+        user: UserInfo {
+            id,
+            name,
+            kind: data.0.kind,
+            revoked: false,
+            from_config: false,
+        },
+        token,
+    }))
+}
+
+// MARK: Update user
+#[patch("/api/users/<id>", format = "json", data = "<data>")]
+pub async fn update_user<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    id: u16,
+    data: Json<UserUpdateRequest>,
+) -> Result<Json<UserInfo>, UserError> {
+    check_user_manage(&auth)?;
+
+    let state = get_state().await?;
+    let mut user = get_user(id).await?;
+
+    if id == auth.0.id
+        && (data.0.revoked == Some(true)
+            || data.0.kind.as_ref().is_some_and(|kind| *kind != UserKind::Admin))
+    {
+        return Err(UserError {
+            kind: UserErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("You can not revoke or demote your own account"),
+        });
+    }
+
+    if data.0.name.is_some() || data.0.kind.is_some() {
+        check_not_from_config(&user)?;
+
+        let name = match &data.0.name {
+            Some(name) => validate_name(name)?,
+            None => user.name.clone(),
+        };
+        let kind = data.0.kind.unwrap_or(user.kind());
+
+        if let Err(e) = state.user_db.update(id, &name, kind.clone()).await {
+            return Err(map_write_error(e));
+        }
+        user.name = name;
+        user.kind = kind.as_u8();
+    }
+
+    if let Some(revoked) = data.0.revoked {
+        if let Err(e) = state.user_db.set_revoked(id, revoked).await {
+            return Err(map_write_error(e));
+        }
+        if revoked {
+            if let Err(e) = state.user_db.remove_sessions_for_user(id).await {
+                return Err(map_write_error(e));
+            }
+            if let Err(e) = state.user_db.remove_api_keys_for_user(id).await {
+                return Err(map_write_error(e));
+            }
+        }
+        user.revoked = revoked;
+    }
+
+    Ok(Json(UserInfo::from_user(&user)))
+}
+
+// MARK: Revoke user
+#[delete("/api/users/<id>")]
+pub async fn revoke_user<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    id: u16,
+) -> Result<Status, UserError> {
+    check_user_manage(&auth)?;
+
+    if id == auth.0.id {
+        return Err(UserError {
+            kind: UserErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("You can not revoke your own account"),
+        });
+    }
+
+    let state = get_state().await?;
+    get_user(id).await?;
+
+    if let Err(e) = state.user_db.set_revoked(id, true).await {
+        return Err(map_write_error(e));
+    }
+
+    if let Err(e) = state.user_db.remove_sessions_for_user(id).await {
+        return Err(map_write_error(e));
+    }
+
+    if let Err(e) = state.user_db.remove_api_keys_for_user(id).await {
+        return Err(map_write_error(e));
+    }
+
+    Ok(Status::NoContent)
+}
+
+// MARK: Rotate token
+#[post("/api/users/<id>/token")]
+pub async fn rotate_user_token<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    id: u16,
+) -> Result<Json<UserTokenResponse>, UserError> {
+    check_user_manage(&auth)?;
+
+    let state = get_state().await?;
+    let user = get_user(id).await?;
+    check_not_from_config(&user)?;
+    let token = utils::generate_token();
+
+    if let Err(e) = state.user_db.set_token(id, &token).await {
+        return Err(map_write_error(e));
+    }
+
+    // Sessions opened with the old token go with it
+    if let Err(e) = state.user_db.remove_sessions_for_user(id).await {
+        return Err(map_write_error(e));
+    }
+
+    Ok(Json(UserTokenResponse {
+        user: UserInfo::from_user(&user),
+        token,
+    }))
+}
+
+// MARK: Utils
+
+fn check_user_manage(auth: &TokenAuth) -> Result<(), UserError> {
+    if !auth.has_permissions_to(PermissionKind::UserManage) {
+        return Err(UserError {
+            kind: UserErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
+        });
+    }
+    Ok(())
+}
+
+/// The config is applied again on every start, so changes made here would be lost.
+fn check_not_from_config(user: &User) -> Result<(), UserError> {
+    if user.from_config {
+        return Err(UserError {
+            kind: UserErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Accounts from the config can only be changed in the config"),
+        });
+    }
+    Ok(())
+}
+
+async fn get_state() -> Result<std::sync::Arc<State>, UserError> {
+    State::get().await.map_err(|_| UserError {
+        kind: UserErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: String::from("Server issue"),
+    })
+}
+
+async fn get_user(id: u16) -> Result<User, UserError> {
+    let state = get_state().await?;
+    match state.user_db.get_by_id(id).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(UserError {
+            kind: UserErrorKind::UserNotFound,
+            status: Status::NotFound,
+            message: String::from("User not found"),
+        }),
+        Err(e) => {
+            eprintln!("[ERROR] Database 'UserDB' failed to get user: {}", e);
+            Err(UserError {
+                kind: UserErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<String, UserError> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err(UserError {
+            kind: UserErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Name must be between 1 and 64 characters"),
+        });
+    }
+    Ok(name.to_string())
+}
+
+fn map_write_error(e: sqlx::Error) -> UserError {
+    if let sqlx::Error::Database(db_error) = &e {
+        if db_error.is_unique_violation() {
+            return UserError {
+                kind: UserErrorKind::NameTaken,
+                status: Status::Conflict,
+                message: String::from("A user with this name already exists"),
+            };
+        }
+    }
+
+    eprintln!("[ERROR] Database 'UserDB' failed to write user: {}", e);
+    UserError {
+        kind: UserErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: e.to_string(),
+    }
+}
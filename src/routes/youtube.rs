@@ -1,8 +1,12 @@
-use core::str;
-use std::{fs, io::Cursor, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+    path::Path,
+    sync::Arc,
+};
 
 use rocket::{
-    http::{ContentType, Header, Status},
+    http::{ContentType, Status},
     response,
     serde::json::Json,
     Request, Response,
@@ -10,29 +14,64 @@ use rocket::{
 use rocket_governor::RocketGovernor;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tokio::process::Command;
+use tokio::{process::Command, sync::Mutex};
 use uuid::Uuid;
 
 use crate::{
     db::{
         user::PermissionKind,
-        video::{Video, YoutubeKind, YoutubeQuality},
+        video::{DownloadOptions, Sidecar, SidecarKind, Video, YoutubeKind, YoutubeQuality},
     },
-    routes::{RateLimitGuard, TokenAuth},
-    state, utils,
+    job_queue::{self, DownloadJob, EnqueueError, JobState},
+    quota::{self, QuotaError},
+    routes::{stream::FileStream, BaseRateLimitGuard, RateLimitGuard, TokenAuth},
+    state, storage, utils,
+    yt_dlp::{self, MediaFormat, Playlist},
 };
 
+const MAX_SUBTITLE_LANGUAGES: usize = 10;
+
+/// Per batch, the lock held while its archive is checked or built,
+/// guarding the fingerprint of the items in the stored archive.
+/// An archive without a known fingerprint is built again.
+pub type BatchArchiveMap = Arc<Mutex<HashMap<String, Arc<Mutex<String>>>>>;
+
 // MARK: Models
 
 #[derive(Deserialize)]
 pub struct YoutubeRequest {
     kind: YoutubeKind,
     quality: YoutubeQuality,
+    /// 1-based playlist positions to download.
+    items: Option<Vec<usize>>,
+    /// Inclusive range of playlist positions to download.
+    range: Option<PlaylistRange>,
+    /// Exact format id from the listed formats, overrides `quality`.
+    format_id: Option<String>,
+    /// Target container, e.g. `webm`, `mkv`, `opus`, `flac` or `m4a`, overrides `kind`.
+    container: Option<String>,
+    /// Start of the section to download, `SS`, `MM:SS` or `HH:MM:SS`.
+    start: Option<String>,
+    /// End of the section to download, defaults to the end of the media.
+    end: Option<String>,
+    /// Subtitles, thumbnail, metadata and chapter splitting.
+    options: Option<DownloadOptions>,
+    /// Chapter title to download instead of the whole media.
+    /// yt-dlp writes one file per section, so only one chapter can be picked.
+    chapter: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistRange {
+    start: usize,
+    end: usize,
 }
 
 #[derive(Debug)]
 pub enum YoutubeResponseKind {
     Good(YoutubeRequestResponse),
+    Playlist(YoutubePlaylistResponse),
+    Batch(YoutubeBatchResponse),
     Bad(YoutubeError),
 }
 
@@ -41,14 +80,59 @@ impl YoutubeResponseKind {
         Self::Good(YoutubeRequestResponse {
             found: false,
             uuid: String::new(),
+            formats: Vec::new(),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct YoutubeRequestResponse {
     pub found: bool,
     pub uuid: String,
+    pub formats: Vec<MediaFormat>,
+}
+
+#[derive(Serialize)]
+pub struct SidecarInfo {
+    pub id: i64,
+    pub kind: SidecarKind,
+    pub label: String,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct YoutubeFormatsResponse {
+    pub id: String,
+    pub title: String,
+    pub extractor: String,
+    pub formats: Vec<MediaFormat>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct YoutubePlaylistResponse {
+    pub found: bool,
+    pub playlist: Playlist,
+}
+
+#[derive(Debug, Serialize)]
+pub struct YoutubeBatchResponse {
+    pub found: bool,
+    pub batch: String,
+    pub uuids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct YoutubeBatchStatus {
+    pub batch: String,
+    pub items: Vec<YoutubeBatchItem>,
+}
+
+#[derive(Serialize)]
+pub struct YoutubeBatchItem {
+    pub uuid: String,
+    pub name: String,
+    /// `None` once the job was forgotten without a finished file.
+    pub job: Option<DownloadJob>,
 }
 
 #[derive(Debug, Serialize)]
@@ -58,7 +142,13 @@ pub enum YoutubeErrorKind {
     InvalidDataSupplied,
     OperationCanceled,
     VideoNotFound,
+    ExtractorNotAllowed,
     AlreadyInProgress,
+    DownloadNotReady,
+    DownloadFailed,
+    JobNotFound,
+    TooManyJobs,
+    QuotaExceeded,
     ServerIssue,
     InvalidUser,
     NoPermissions,
@@ -71,32 +161,22 @@ pub struct YoutubeError {
     pub message: String,
 }
 
-pub struct YoutubeOutput {
-    pub uuid: String,
-    pub name: String,
-    pub path: String,
-    pub kind: YoutubeKind,
-}
-
 // MARK: Responders
 #[rocket::async_trait]
 impl<'r, 'o: 'r> response::Responder<'r, 'o> for YoutubeResponseKind {
     fn respond_to(self, req: &Request) -> rocket::response::Result<'o> {
         match self {
             YoutubeResponseKind::Good(good) => good.respond_to(req),
+            YoutubeResponseKind::Playlist(playlist) => Json(playlist).respond_to(req),
+            YoutubeResponseKind::Batch(batch) => Json(batch).respond_to(req),
             YoutubeResponseKind::Bad(bad) => bad.respond_to(req),
         }
     }
 }
 
 impl<'r, 'o: 'r> response::Responder<'r, 'o> for YoutubeRequestResponse {
-    fn respond_to(self, _: &Request) -> rocket::response::Result<'o> {
-        let mut res = Response::new();
-
-        res.set_header(ContentType::new("application", "json"));
-        let body = format!(r#"{{"found": {}, "uuid": "{}"}}"#, self.found, self.uuid);
-        res.set_sized_body(body.len(), Cursor::new(body));
-        Ok(res)
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'o> {
+        Json(self).respond_to(req)
     }
 }
 
@@ -116,39 +196,6 @@ impl<'r, 'o: 'r> response::Responder<'r, 'o> for YoutubeError {
     }
 }
 
-impl<'r, 'o: 'r> response::Responder<'r, 'o> for YoutubeOutput {
-    fn respond_to(self, req: &Request) -> rocket::response::Result<'o> {
-        let content = match fs::read(&self.path) {
-            Ok(val) => val,
-            Err(e) => {
-                eprintln!("Failed to read file at {:?}: {}", &self.path, e);
-                return YoutubeError {
-                    kind: YoutubeErrorKind::ServerIssue,
-                    status: Status::InternalServerError,
-                    message: e.to_string(),
-                }
-                .respond_to(&req);
-            }
-        };
-
-        let file_name = format!(
-            "{}.{}",
-            self.name,
-            utils::get_extension_from_path(&self.path).unwrap()
-        );
-        println!("[DEV   ] File name: {}", &file_name);
-
-        let res = Response::build()
-            .header(Header::new(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", &file_name),
-            ))
-            .sized_body(content.len(), Cursor::new(content))
-            .finalize();
-        Ok(res)
-    }
-}
-
 // MARK: Youtube request
 #[post("/api/youtube/request?<url>", format = "json", data = "<data>")]
 pub async fn youtube_request<'r>(
@@ -157,7 +204,7 @@ pub async fn youtube_request<'r>(
     url: String,
     data: Json<YoutubeRequest>,
 ) -> YoutubeResponseKind {
-    if !auth.0.has_permissions_to(PermissionKind::YoutubeDownload) {
+    if !auth.has_permissions_to(PermissionKind::YoutubeDownload) {
         return YoutubeResponseKind::Bad(YoutubeError {
             kind: YoutubeErrorKind::NoPermissions,
             status: Status::Forbidden,
@@ -192,75 +239,260 @@ pub async fn youtube_request<'r>(
         });
     }
 
-    // Check if the video exists on youtube
-    let output = Command::new(state.config.yt_dlp.dpl_exec_path.clone())
-        .arg("--simulate")
-        .arg("-j")
-        .arg(url)
-        .output()
-        .await
-        .expect("Failed to execute yt-dlp");
+    let video_info = match probe_url(&state, &url).await {
+        Ok(info) => info,
+        Err(e) => return YoutubeResponseKind::Bad(e),
+    };
 
-    if !output.status.success() {
-        return YoutubeResponseKind::Bad(YoutubeError {
-            kind: YoutubeErrorKind::VideoNotFound,
-            status: Status::NotFound,
-            message: String::from("Failed to find video, or server failed to execute yt-dlp"),
-        });
+    if let Some(playlist) = yt_dlp::parse_playlist(&video_info) {
+        // Without a selection only list the entries
+        if data.0.items.is_none() && data.0.range.is_none() {
+            return YoutubeResponseKind::Playlist(YoutubePlaylistResponse {
+                found: true,
+                playlist,
+            });
+        }
+
+        return match request_batch(&state, &auth, &playlist, &data.0).await {
+            Ok(batch) => YoutubeResponseKind::Batch(batch),
+            Err(e) => YoutubeResponseKind::Bad(e),
+        };
     }
 
-    let json_output = str::from_utf8(&output.stdout)
-        .map_err(|e| {
-            YoutubeResponseKind::Bad(YoutubeError {
+    let formats = yt_dlp::parse_formats(&video_info);
+    let mut video = match Video::from_yt_json(video_info.clone()) {
+        Ok(video) => video,
+        Err(e) => {
+            return YoutubeResponseKind::Bad(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
                 message: e.to_string(),
-            })
-        })
-        .unwrap()
-        .trim()
-        .to_string();
-    let video_info: Value = serde_json::from_str(&json_output)
-        .map_err(|_| {
-            YoutubeResponseKind::Bad(YoutubeError {
-                kind: YoutubeErrorKind::ServerIssue,
-                status: Status::InternalServerError,
-                message: format!("Failed to parse yt-dlp output: {}", &json_output),
-            })
-        })
-        .unwrap();
+            });
+        }
+    };
 
-    let mut video = Video::from_yt_json(video_info)
-        .map_err(|e| {
-            YoutubeResponseKind::Bad(YoutubeError {
-                kind: YoutubeErrorKind::ServerIssue,
-                status: Status::InternalServerError,
-                message: e.to_string(),
-            })
-        })
-        .unwrap();
+    if !state.config.yt_dlp.allows_extractor(&video.extractor) {
+        return YoutubeResponseKind::Bad(extractor_not_allowed(&video.extractor));
+    }
 
     let uuid = Uuid::new_v4().to_string();
     video.uuid = uuid.clone();
-    video.user = auth.0.id.clone();
+    video.user = auth.0.id;
     video.quality = data.0.quality.to_u8();
     video.format = data.0.kind.to_u8();
 
-    state
-        .video_db
-        .add(&video)
+    if let Err(e) = apply_format_options(&mut video, &data.0, Some(&formats)) {
+        return YoutubeResponseKind::Bad(e);
+    }
+
+    if let Err(e) = apply_sections(&mut video, &data.0, Some(&video_info)) {
+        return YoutubeResponseKind::Bad(e);
+    }
+
+    if let Err(e) = apply_download_options(&mut video, &data.0) {
+        return YoutubeResponseKind::Bad(e);
+    }
+
+    // The size is only known once the job finished,
+    // so this refuses users already at their limit
+    let mut reservation = match quota::reserve(&state, &auth.0, 1, 1).await {
+        Ok(reservation) => reservation,
+        Err(e) => return YoutubeResponseKind::Bad(quota_error(e)),
+    };
+
+    if let Some(path) = job_queue::reuse_cached(&state, &video).await {
+        video.path = path;
+    }
+
+    if let Err(e) = state.video_db.add(&video).await {
+        eprintln!("[ERROR] Database 'VideoDB' failed to add video: {}", e);
+        return YoutubeResponseKind::Bad(YoutubeError {
+            kind: YoutubeErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        });
+    }
+
+    if !video.path.is_empty() {
+        if !quota::record_video_within(&state, &video, &mut reservation.remaining).await {
+            job_queue::remove_output(&state, &video).await;
+            remove_videos(&state, &[uuid]).await;
+            return YoutubeResponseKind::Bad(quota_error(QuotaError::Exceeded(reservation.remaining)));
+        }
+    } else if let Err(e) = job_queue::enqueue(&state, &uuid, auth.0.id, None).await {
+        remove_videos(&state, &[uuid]).await;
+        return YoutubeResponseKind::Bad(enqueue_error(e));
+    }
+
+    YoutubeResponseKind::Good(YoutubeRequestResponse {
+        found: true,
+        uuid,
+        formats,
+    })
+}
+
+/// Creates a video for every selected playlist entry and queues them as one batch.
+async fn request_batch(
+    state: &state::State,
+    auth: &TokenAuth,
+    playlist: &Playlist,
+    data: &YoutubeRequest,
+) -> Result<YoutubeBatchResponse, YoutubeError> {
+    // Entries without an id are skipped, so positions can have gaps
+    let last_index = playlist.entries.last().map_or(0, |entry| entry.index);
+    let out_of_bounds = || YoutubeError {
+        kind: YoutubeErrorKind::InvalidDataSupplied,
+        status: Status::BadRequest,
+        message: format!("Select items between 1 and {}", last_index),
+    };
+    let max_items = state.config.yt_dlp.max_playlist_items;
+    let too_many = || YoutubeError {
+        kind: YoutubeErrorKind::InvalidDataSupplied,
+        status: Status::BadRequest,
+        message: format!("At most {} items can be downloaded at once", max_items),
+    };
+
+    let mut indices = data.items.clone().unwrap_or_default();
+    if let Some(range) = &data.range {
+        if range.start == 0 || range.start > range.end || range.end > last_index {
+            return Err(out_of_bounds());
+        }
+        if range.end - range.start >= max_items {
+            return Err(too_many());
+        }
+        indices.extend(range.start..=range.end);
+    }
+    let mut seen = HashSet::new();
+    indices.retain(|index| seen.insert(*index));
+
+    if indices.len() > max_items {
+        return Err(too_many());
+    }
+
+    let entries = indices
+        .iter()
+        .map(|index| playlist.entries.iter().find(|entry| entry.index == *index))
+        .collect::<Option<Vec<_>>>()
+        .filter(|entries| !entries.is_empty())
+        .ok_or_else(out_of_bounds)?;
+    if let Some(entry) = entries
+        .iter()
+        .find(|entry| !state.config.yt_dlp.allows_extractor(&entry.extractor))
+    {
+        return Err(extractor_not_allowed(&entry.extractor));
+    }
+
+    let mut reservation = quota::reserve(state, &auth.0, entries.len() as u64, 1)
         .await
-        .map_err(|e| {
+        .map_err(quota_error)?;
+
+    let batch = Uuid::new_v4().to_string();
+    let mut videos = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut video = Video::from_playlist_entry(entry);
+        video.uuid = Uuid::new_v4().to_string();
+        video.user = auth.0.id;
+        video.quality = data.quality.to_u8();
+        video.format = data.kind.to_u8();
+        video.batch = batch.clone();
+        apply_format_options(&mut video, data, None)?;
+        apply_sections(&mut video, data, None)?;
+        apply_download_options(&mut video, data)?;
+
+        if let Some(path) = job_queue::reuse_cached(state, &video).await {
+            video.path = path;
+        }
+
+        if let Err(e) = state.video_db.add(&video).await {
             eprintln!("[ERROR] Database 'VideoDB' failed to add video: {}", e);
-            YoutubeResponseKind::Bad(YoutubeError {
+            remove_videos(state, &videos.iter().map(|v: &Video| v.uuid.clone()).collect::<Vec<_>>()).await;
+            return Err(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
                 message: e.to_string(),
-            })
-        })
-        .unwrap();
+            });
+        }
+        videos.push(video);
+        let video = &videos[videos.len() - 1];
+        if !video.path.is_empty()
+            && !quota::record_video_within(state, video, &mut reservation.remaining).await
+        {
+            for video in videos.iter().filter(|video| !video.path.is_empty()) {
+                job_queue::remove_output(state, video).await;
+            }
+            remove_videos(state, &videos.iter().map(|v| v.uuid.clone()).collect::<Vec<_>>()).await;
+            return Err(quota_error(QuotaError::Exceeded(reservation.remaining)));
+        }
+    }
+
+    let uuids = videos.iter().map(|video| video.uuid.clone()).collect::<Vec<_>>();
+    for video in videos.iter().filter(|video| video.path.is_empty()) {
+        if let Err(e) = job_queue::enqueue(state, &video.uuid, auth.0.id, Some(&batch)).await {
+            remove_videos(state, &uuids).await;
+            return Err(enqueue_error(e));
+        }
+    }
+
+    Ok(YoutubeBatchResponse {
+        found: true,
+        batch,
+        uuids,
+    })
+}
+
+// MARK: Youtube formats
+/// Lists the formats of a video without requesting a download.
+#[get("/api/youtube/formats?<url>")]
+pub async fn youtube_formats<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    url: &str,
+) -> Result<Json<YoutubeFormatsResponse>, YoutubeError> {
+    if !auth.has_permissions_to(PermissionKind::YoutubeDownload) {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
+        });
+    }
+
+    if url.len() <= 20 || !url.starts_with("http") {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Invalid data supplied, url is empty, incorrect or too short"),
+        });
+    }
+
+    let state = get_state().await?;
+    if !state.config.yt_dlp.enabled || state.config.yt_dlp.dpl_exec_path.is_empty() {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: String::from("yt-dlp is not enabled"),
+        });
+    }
+
+    let video_info = probe_url(&state, url).await?;
+    if yt_dlp::parse_playlist(&video_info).is_some() {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::InvalidDataSupplied,
+            status: Status::BadRequest,
+            message: String::from("Formats can only be listed for a single video"),
+        });
+    }
+
+    let extractor = video_info["extractor_key"].as_str().unwrap_or("").to_string();
+    if !state.config.yt_dlp.allows_extractor(&extractor) {
+        return Err(extractor_not_allowed(&extractor));
+    }
 
-    YoutubeResponseKind::Good(YoutubeRequestResponse { found: true, uuid })
+    Ok(Json(YoutubeFormatsResponse {
+        id: video_info["id"].as_str().unwrap_or("").to_string(),
+        title: video_info["title"].as_str().unwrap_or("").to_string(),
+        formats: yt_dlp::parse_formats(&video_info),
+        extractor,
+    }))
 }
 
 // MARK: Youtube download
@@ -269,39 +501,89 @@ pub async fn youtube_download<'r>(
     _rt: RocketGovernor<'r, RateLimitGuard>,
     auth: TokenAuth,
     uuid: &str,
-) -> Result<YoutubeOutput, YoutubeError> {
-    if !auth.0.has_permissions_to(PermissionKind::YoutubeDownload) {
+) -> Result<FileStream, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
+
+    // Range requests for the finished file come back here
+    if is_stored(&state, &video.path).await {
+        let file_name = format!(
+            "{}.{}",
+            video.name,
+            utils::get_extension_from_path(&video.path).unwrap_or_default()
+        );
+        return open_stream(video.path, file_name).await;
+    }
+
+    let job = match job_queue::get(&state, uuid).await {
+        Some(job) => job,
+        // Forgotten after a restart, start over
+        None => job_queue::enqueue(&state, uuid, auth.0.id, video.batch_id())
+            .await
+            .map_err(enqueue_error)?,
+    };
+
+    if job.state == JobState::Failed {
         return Err(YoutubeError {
-            kind: YoutubeErrorKind::NoPermissions,
-            status: Status::Forbidden,
-            message: String::from("No permissions"),
+            kind: YoutubeErrorKind::DownloadFailed,
+            status: Status::InternalServerError,
+            message: job.error.unwrap_or(String::from("Download failed")),
         });
     }
 
-    let state = match state::State::get().await {
-        Ok(state) => state,
-        Err(_) => {
+    Err(YoutubeError {
+        kind: YoutubeErrorKind::DownloadNotReady,
+        status: Status::Conflict,
+        message: String::from("Download is not finished yet, check the job status"),
+    })
+}
+
+// MARK: Sidecar files
+#[get("/api/youtube/download/<uuid>/files")]
+pub async fn youtube_files<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<Json<Vec<SidecarInfo>>, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
+
+    let sidecars = get_sidecars(&state, uuid).await?;
+    Ok(Json(
+        sidecars
+            .iter()
+            .filter(|sidecar| SidecarKind::from_u8(sidecar.kind) != SidecarKind::Bundle)
+            .map(|sidecar| SidecarInfo {
+                id: sidecar.id,
+                kind: SidecarKind::from_u8(sidecar.kind),
+                label: sidecar.label.clone(),
+                name: sidecar_file_name(&video, sidecar),
+            })
+            .collect(),
+    ))
+}
+
+#[get("/api/youtube/download/<uuid>/files/<id>")]
+pub async fn youtube_file<'r>(
+    _rt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+    id: i64,
+) -> Result<FileStream, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
+
+    let sidecar = match state.video_db.get_sidecar(uuid, id).await {
+        Ok(Some(sidecar)) => sidecar,
+        Ok(None) => {
             return Err(YoutubeError {
-                kind: YoutubeErrorKind::ServerIssue,
-                status: Status::InternalServerError,
-                message: String::from("Server issue"),
+                kind: YoutubeErrorKind::VideoNotFound,
+                status: Status::NotFound,
+                message: String::from("File not found"),
             });
         }
-    };
-
-    let mut video = match state.video_db.get_by_uuid(&uuid).await {
-        Ok(video) => match video {
-            Some(vid) => vid,
-            None => {
-                return Err(YoutubeError {
-                    kind: YoutubeErrorKind::VideoNotFound,
-                    status: Status::NotFound,
-                    message: String::from("Video not found"),
-                });
-            }
-        },
         Err(e) => {
-            eprintln!("[ERROR] Database 'VideoDB' failed to get video: {}", e);
+            eprintln!("[ERROR] Database 'VideoDB' failed to get sidecar: {}", e);
             return Err(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
@@ -310,54 +592,263 @@ pub async fn youtube_download<'r>(
         }
     };
 
-    if video.user != auth.0.id {
+    open_stream(sidecar.path.clone(), sidecar_file_name(&video, &sidecar)).await
+}
+
+// MARK: Bundle
+/// Bundles the media with all of its sidecar files into a ZIP archive.
+#[get("/api/youtube/download/<uuid>/bundle")]
+pub async fn youtube_bundle<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<FileStream, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
+
+    if !is_stored(&state, &video.path).await {
         return Err(YoutubeError {
-            kind: YoutubeErrorKind::InvalidUser,
-            status: Status::Forbidden,
-            message: String::from("You can only download from your own requests"),
+            kind: YoutubeErrorKind::DownloadNotReady,
+            status: Status::Conflict,
+            message: String::from("Download is not finished yet, check the job status"),
         });
     }
 
-    let url = format!("https://www.youtube.com/watch?v={}", video.vid_id);
+    let sidecars = get_sidecars(&state, uuid).await?;
+    if let Some(bundle) = sidecars
+        .iter()
+        .find(|sidecar| SidecarKind::from_u8(sidecar.kind) == SidecarKind::Bundle)
+    {
+        if is_stored(&state, &bundle.path).await {
+            return open_stream(bundle.path.clone(), sidecar_file_name(&video, bundle)).await;
+        }
+    }
 
-    let path_str = format!(
-        "{}{}-{}",
-        &state.config.upload.upload_location, &video.uuid, &video.vid_id
+    let mut entries = vec![(
+        format!(
+            "{}.{}",
+            video.name.replace(['/', '\\'], "_"),
+            utils::get_extension_from_path(&video.path).unwrap_or_default()
+        ),
+        video.path.clone(),
+    )];
+    entries.extend(
+        sidecars
+            .iter()
+            .filter(|sidecar| SidecarKind::from_u8(sidecar.kind) != SidecarKind::Bundle)
+            .map(|sidecar| {
+                (
+                    sidecar_file_name(&video, sidecar).replace(['/', '\\'], "_"),
+                    sidecar.path.clone(),
+                )
+            }),
     );
-    let path = Path::new(path_str.as_str());
-    let quality = YoutubeQuality::from_u8(video.quality);
-    let format = YoutubeKind::from_u8(video.format);
 
-    let dpl_exec_path = state.config.yt_dlp.dpl_exec_path.clone();
-    let mut cmd = Command::new(dpl_exec_path);
-    cmd.arg("-o");
-    cmd.arg(&path);
+    let path = format!(
+        "{}{}.bundle.zip",
+        &state.config.upload.upload_location,
+        storage::object_key(video.path.rsplit_once('.').map(|(p, _)| p).unwrap_or(&video.path))
+    );
+    build_zip(&state, &path, entries).await?;
+
+    if let Err(e) = state
+        .video_db
+        .add_sidecar(uuid, SidecarKind::Bundle, "", &path)
+        .await
+    {
+        eprintln!("[ERROR] Database 'VideoDB' failed to add sidecar: {}", e);
+    }
+
+    open_stream(path, format!("{}.zip", video.name)).await
+}
+
+// MARK: Job status
+#[get("/api/youtube/jobs/<uuid>")]
+pub async fn youtube_job_status<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<Json<DownloadJob>, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
 
-    if format.is_audio() {
-        cmd.arg("--extract-audio")
-            .arg("--audio-format")
-            .arg(format.as_str());
+    if let Some(job) = job_queue::get(&state, uuid).await {
+        return Ok(Json(job));
     }
 
-    if quality.use_selection() {
-        if format.is_audio() {
-            cmd.arg("-S").arg(quality.as_str_audio());
-        } else {
-            cmd.arg("-S").arg(quality.as_str_vid());
+    if is_stored(&state, &video.path).await {
+        return Ok(Json(DownloadJob::finished(uuid, video.user)));
+    }
+
+    Err(YoutubeError {
+        kind: YoutubeErrorKind::JobNotFound,
+        status: Status::NotFound,
+        message: String::from("No download job for this video"),
+    })
+}
+
+// MARK: Job enqueue
+/// Queues the download again, e.g. after it failed.
+#[post("/api/youtube/jobs/<uuid>")]
+pub async fn youtube_job_enqueue<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    uuid: &str,
+) -> Result<Json<DownloadJob>, YoutubeError> {
+    let state = get_state().await?;
+    let video = get_own_video(&auth, uuid).await?;
+
+    if is_stored(&state, &video.path).await {
+        return Ok(Json(DownloadJob::finished(uuid, video.user)));
+    }
+
+    job_queue::enqueue(&state, uuid, auth.0.id, video.batch_id())
+        .await
+        .map(Json)
+        .map_err(enqueue_error)
+}
+
+// MARK: Batch status
+#[get("/api/youtube/batch/<batch>")]
+pub async fn youtube_batch_status<'r>(
+    _brt: RocketGovernor<'r, BaseRateLimitGuard>,
+    auth: TokenAuth,
+    batch: &str,
+) -> Result<Json<YoutubeBatchStatus>, YoutubeError> {
+    let state = get_state().await?;
+    let videos = get_own_batch(&auth, batch).await?;
+
+    let mut items = Vec::with_capacity(videos.len());
+    for video in videos {
+        let job = match job_queue::get(&state, &video.uuid).await {
+            Some(job) => Some(job),
+            None if is_stored(&state, &video.path).await => {
+                Some(DownloadJob::finished(&video.uuid, video.user))
+            }
+            None => None,
+        };
+
+        items.push(YoutubeBatchItem {
+            uuid: video.uuid,
+            name: video.name,
+            job,
+        });
+    }
+
+    Ok(Json(YoutubeBatchStatus {
+        batch: batch.to_string(),
+        items,
+    }))
+}
+
+// MARK: Batch archive
+/// Bundles the finished items of a batch into a ZIP archive.
+#[get("/api/youtube/batch/<batch>/zip")]
+pub async fn youtube_batch_zip<'r>(
+    _rt: RocketGovernor<'r, RateLimitGuard>,
+    auth: TokenAuth,
+    batch: &str,
+) -> Result<FileStream, YoutubeError> {
+    let state = get_state().await?;
+    let videos = get_own_batch(&auth, batch).await?;
+
+    for video in &videos {
+        if job_queue::get(&state, &video.uuid)
+            .await
+            .is_some_and(|job| job.state.is_active())
+        {
+            return Err(YoutubeError {
+                kind: YoutubeErrorKind::DownloadNotReady,
+                status: Status::Conflict,
+                message: String::from("Batch is not finished yet, check the batch status"),
+            });
         }
-    } else {
-        if format.is_audio() {
-            cmd.arg("--audio-quality").arg(quality.as_str_audio());
+    }
+
+    let mut entries = Vec::new();
+    for video in &videos {
+        if !is_stored(&state, &video.path).await {
+            continue;
         }
-        cmd.arg("--format").arg(quality.as_str_vid());
+
+        let name = format!(
+            "{:02} - {}.{}",
+            entries.len() + 1,
+            video.name.replace(['/', '\\'], "_"),
+            utils::get_extension_from_path(&video.path).unwrap_or_default()
+        );
+        entries.push((name, video.path.clone()));
+    }
+
+    if entries.is_empty() {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::DownloadFailed,
+            status: Status::NotFound,
+            message: String::from("No item of this batch finished downloading"),
+        });
     }
 
-    cmd.arg(url);
+    // Items finishing later change the archive
+    let fingerprint = utils::sha256_hex(
+        &entries
+            .iter()
+            .map(|(name, path)| format!("{}\0{}", name, path))
+            .collect::<Vec<_>>()
+            .join("\0"),
+    );
+    let archive = Arc::clone(
+        state
+            .batch_archives
+            .lock()
+            .await
+            .entry(batch.to_string())
+            .or_default(),
+    );
+    let mut built = archive.lock().await;
 
-    let mut output = match cmd.spawn() {
-        Ok(output) => output,
+    let path = format!("{}{}.zip", &state.config.upload.upload_location, batch);
+    if *built != fingerprint || !is_stored(&state, &path).await {
+        built.clear();
+        build_zip(&state, &path, entries).await?;
+        *built = fingerprint;
+    }
+    drop(built);
+
+    open_stream(path, format!("{}.zip", batch)).await
+}
+
+// MARK: Utils
+
+async fn get_state() -> Result<std::sync::Arc<state::State>, YoutubeError> {
+    state::State::get().await.map_err(|_| YoutubeError {
+        kind: YoutubeErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: String::from("Server issue"),
+    })
+}
+
+/// Returns the video if it was requested by the authorized user.
+async fn get_own_video(auth: &TokenAuth, uuid: &str) -> Result<Video, YoutubeError> {
+    if !auth.has_permissions_to(PermissionKind::YoutubeDownload) {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
+        });
+    }
+
+    let state = get_state().await?;
+    let video = match state.video_db.get_by_uuid(uuid).await {
+        Ok(Some(video)) => video,
+        Ok(None) => {
+            return Err(YoutubeError {
+                kind: YoutubeErrorKind::VideoNotFound,
+                status: Status::NotFound,
+                message: String::from("Video not found"),
+            });
+        }
         Err(e) => {
-            eprintln!("[ERROR] yt-dlp failed to start: {}", e);
+            eprintln!("[ERROR] Database 'VideoDB' failed to get video: {}", e);
             return Err(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
@@ -366,19 +857,32 @@ pub async fn youtube_download<'r>(
         }
     };
 
-    if let Err(e) = output.wait().await {
-        eprintln!("[ERROR] yt-dlp failed to wait: {}", e);
+    if video.user != auth.0.id {
         return Err(YoutubeError {
-            kind: YoutubeErrorKind::ServerIssue,
-            status: Status::InternalServerError,
-            message: e.to_string(),
+            kind: YoutubeErrorKind::InvalidUser,
+            status: Status::Forbidden,
+            message: String::from("You can only download from your own requests"),
+        });
+    }
+
+    Ok(video)
+}
+
+/// Returns the videos of a batch requested by the authorized user.
+async fn get_own_batch(auth: &TokenAuth, batch: &str) -> Result<Vec<Video>, YoutubeError> {
+    if !auth.has_permissions_to(PermissionKind::YoutubeDownload) {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::NoPermissions,
+            status: Status::Forbidden,
+            message: String::from("No permissions"),
         });
     }
 
-    let complete_path = match utils::get_file_with_extension(&path_str) {
-        Ok(val) => val.unwrap(),
+    let state = get_state().await?;
+    let videos = match state.video_db.get_by_batch(batch).await {
+        Ok(videos) => videos,
         Err(e) => {
-            eprintln!("Failed to get file with extension: {}", e);
+            eprintln!("[ERROR] Database 'VideoDB' failed to get batch: {}", e);
             return Err(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
@@ -387,24 +891,398 @@ pub async fn youtube_download<'r>(
         }
     };
 
-    video.path = complete_path.clone().to_str().unwrap().to_string();
+    if videos.is_empty() {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::VideoNotFound,
+            status: Status::NotFound,
+            message: String::from("Batch not found"),
+        });
+    }
+
+    if videos.iter().any(|video| video.user != auth.0.id) {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::InvalidUser,
+            status: Status::Forbidden,
+            message: String::from("You can only download from your own requests"),
+        });
+    }
+
+    Ok(videos)
+}
 
-    match state.video_db.update_data(&video.uuid, &video).await {
-        Ok(_) => {},
+/// Runs yt-dlp without downloading to learn what `url` points to.
+async fn probe_url(state: &state::State, url: &str) -> Result<Value, YoutubeError> {
+    let output = match Command::new(&state.config.yt_dlp.dpl_exec_path)
+        .arg("--simulate")
+        .arg("-J")
+        .arg("--flat-playlist")
+        .arg("--")
+        .arg(url)
+        .output()
+        .await
+    {
+        Ok(output) => output,
         Err(e) => {
-            eprintln!("[ERROR] Database 'VideoDB' failed to update video: {}", e);
+            eprintln!("[ERROR] yt-dlp failed to start: {}", e);
             return Err(YoutubeError {
                 kind: YoutubeErrorKind::ServerIssue,
                 status: Status::InternalServerError,
-                message: e.to_string(),
+                message: String::from("Failed to execute yt-dlp"),
             });
         }
+    };
+
+    if !output.status.success() {
+        return Err(YoutubeError {
+            kind: YoutubeErrorKind::VideoNotFound,
+            status: Status::NotFound,
+            message: String::from("Failed to find video, or server failed to execute yt-dlp"),
+        });
+    }
+
+    serde_json::from_slice::<Value>(&output.stdout).map_err(|e| YoutubeError {
+        kind: YoutubeErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: format!("Failed to parse yt-dlp output: {}", e),
+    })
+}
+
+async fn remove_videos(state: &state::State, uuids: &[String]) {
+    for uuid in uuids {
+        job_queue::remove(state, uuid).await;
+        if let Err(e) = state.video_db.remove_by_uuid(uuid).await {
+            eprintln!("[ERROR] Database 'VideoDB' failed to remove video: {}", e);
+        }
+    }
+}
+
+/// Validates the requested format id and container and stores them on `video`.
+/// Playlist entries are not probed one by one, so they get no `formats` and no format ids.
+fn apply_format_options(
+    video: &mut Video,
+    data: &YoutubeRequest,
+    formats: Option<&[MediaFormat]>,
+) -> Result<(), YoutubeError> {
+    let invalid = |message: String| YoutubeError {
+        kind: YoutubeErrorKind::InvalidDataSupplied,
+        status: Status::BadRequest,
+        message,
+    };
+
+    if let Some(container) = &data.container {
+        let container = container.to_lowercase();
+        if !yt_dlp::AUDIO_CONTAINERS.contains(&container.as_str())
+            && !yt_dlp::VIDEO_CONTAINERS.contains(&container.as_str())
+        {
+            return Err(invalid(format!(
+                "Unsupported container '{}', use one of: {}, {}",
+                container,
+                yt_dlp::VIDEO_CONTAINERS.join(", "),
+                yt_dlp::AUDIO_CONTAINERS.join(", ")
+            )));
+        }
+        video.container = container;
+    }
+
+    if let Some(format_id) = &data.format_id {
+        let formats = match formats {
+            Some(formats) => formats,
+            None => {
+                return Err(invalid(String::from(
+                    "A format id can not be used for playlist items",
+                )))
+            }
+        };
+
+        let format = match formats.iter().find(|format| &format.format_id == format_id) {
+            Some(format) => format,
+            None => return Err(invalid(format!("Format '{}' is not available", format_id))),
+        };
+
+        let audio = yt_dlp::is_audio_target(video);
+        if audio && !format.has_audio() {
+            return Err(invalid(format!("Format '{}' has no audio", format_id)));
+        }
+        if !audio && !format.has_video() && !video.container.is_empty() {
+            return Err(invalid(format!(
+                "Format '{}' has no video for container '{}'",
+                format_id, &video.container
+            )));
+        }
+
+        video.format_id = format.selector();
+    }
+
+    Ok(())
+}
+
+/// Validates the requested time range or chapter and stores it as `--download-sections` value.
+/// Without `info` (playlist entries) the chapter is passed on unchecked.
+fn apply_sections(
+    video: &mut Video,
+    data: &YoutubeRequest,
+    info: Option<&Value>,
+) -> Result<(), YoutubeError> {
+    let invalid = |message: String| YoutubeError {
+        kind: YoutubeErrorKind::InvalidDataSupplied,
+        status: Status::BadRequest,
+        message,
+    };
+
+    let has_range = data.start.is_some() || data.end.is_some();
+    if has_range && data.chapter.is_some() {
+        return Err(invalid(String::from(
+            "Use either start/end or a chapter, not both",
+        )));
+    }
+
+    if has_range {
+        let parse = |value: &Option<String>, name: &str| match value {
+            Some(value) => yt_dlp::parse_duration(value.trim())
+                .map(Some)
+                .ok_or_else(|| invalid(format!("Invalid {} '{}', use HH:MM:SS", name, value))),
+            None => Ok(None),
+        };
+        let start = parse(&data.start, "start")?.unwrap_or(0);
+        let end = parse(&data.end, "end")?;
+        let duration = info.and_then(|info| info["duration"].as_f64()).map(|d| d as u64);
+
+        if end.is_some_and(|end| end <= start) {
+            return Err(invalid(String::from("End must be after start")));
+        }
+        if duration.is_some_and(|duration| start >= duration) {
+            return Err(invalid(String::from("Start is past the end of the media")));
+        }
+
+        video.section = format!(
+            "*{}-{}",
+            start,
+            end.map(|end| end.to_string()).unwrap_or(String::from("inf"))
+        );
+        video.name = format!(
+            "{} ({}-{})",
+            video.name,
+            yt_dlp::format_duration(start),
+            end.map(yt_dlp::format_duration).unwrap_or(String::from("end"))
+        );
+        return Ok(());
+    }
+
+    let chapter = match &data.chapter {
+        Some(chapter) => chapter.trim(),
+        None => return Ok(()),
+    };
+
+    if chapter.is_empty() || chapter.contains('\n') {
+        return Err(invalid(String::from("Invalid chapter")));
+    }
+
+    if let Some(info) = info {
+        let exists = info["chapters"].as_array().is_some_and(|chapters| {
+            chapters
+                .iter()
+                .any(|available| available["title"].as_str() == Some(chapter))
+        });
+
+        if !exists {
+            return Err(invalid(format!("Chapter '{}' does not exist", chapter)));
+        }
+    }
+
+    // Sections without `*` are regexes matched against chapter titles
+    video.section = format!("^{}$", regex::escape(chapter));
+    video.name = format!("{} ({})", video.name, chapter);
+    Ok(())
+}
+
+/// Validates subtitle, thumbnail, metadata and chapter options and stores them on `video`.
+fn apply_download_options(video: &mut Video, data: &YoutubeRequest) -> Result<(), YoutubeError> {
+    let invalid = |message: &str| YoutubeError {
+        kind: YoutubeErrorKind::InvalidDataSupplied,
+        status: Status::BadRequest,
+        message: message.to_string(),
+    };
+
+    let mut options = match &data.options {
+        Some(options) => options.clone(),
+        None => return Ok(()),
+    };
+
+    options.subtitles = options
+        .subtitles
+        .iter()
+        .map(|language| language.trim().to_string())
+        .collect();
+    options.subtitles.sort();
+    options.subtitles.dedup();
+
+    if options.subtitles.len() > MAX_SUBTITLE_LANGUAGES {
+        return Err(invalid("Too many subtitle languages"));
+    }
+    if options.subtitles.iter().any(|language| {
+        language.is_empty()
+            || language.len() > 16
+            || !language
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }) {
+        return Err(invalid("Invalid subtitle language"));
+    }
+
+    let target = if video.container.is_empty() {
+        YoutubeKind::from_u8(video.format).as_str().to_string()
+    } else {
+        video.container.clone()
+    };
+    if options.embed_thumbnail && (target == "wav" || target == "webm") {
+        return Err(invalid("Thumbnails can not be embedded into this format"));
+    }
+
+    if options.split_chapters && !video.section.is_empty() {
+        return Err(invalid("Chapters can not be split from a section"));
+    }
+
+    let options_json = serde_json::to_string(&options).map_err(|e| YoutubeError {
+        kind: YoutubeErrorKind::ServerIssue,
+        status: Status::InternalServerError,
+        message: e.to_string(),
+    })?;
+    // Defaults are stored empty so they share the cache with requests without options
+    let default_json = serde_json::to_string(&DownloadOptions::default()).unwrap_or_default();
+    if options_json != default_json {
+        video.options = options_json;
+    }
+
+    Ok(())
+}
+
+fn sidecar_file_name(video: &Video, sidecar: &Sidecar) -> String {
+    let ext = utils::get_extension_from_path(&sidecar.path).unwrap_or_default();
+    match SidecarKind::from_u8(sidecar.kind) {
+        SidecarKind::Subtitle => format!("{}.{}.{}", video.name, sidecar.label, ext),
+        SidecarKind::Thumbnail | SidecarKind::Bundle => format!("{}.{}", video.name, ext),
+        SidecarKind::Chapter => format!("{} - Chapter {}.{}", video.name, sidecar.label, ext),
+    }
+}
+
+fn extractor_not_allowed(extractor: &str) -> YoutubeError {
+    YoutubeError {
+        kind: YoutubeErrorKind::ExtractorNotAllowed,
+        status: Status::Forbidden,
+        message: format!("Downloads from '{}' are not allowed", extractor),
+    }
+}
+
+async fn get_sidecars(state: &state::State, uuid: &str) -> Result<Vec<Sidecar>, YoutubeError> {
+    state.video_db.get_sidecars(uuid).await.map_err(|e| {
+        eprintln!("[ERROR] Database 'VideoDB' failed to get sidecars: {}", e);
+        YoutubeError {
+            kind: YoutubeErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        }
+    })
+}
+
+/// Whether the file at `path` finished and is in storage.
+async fn is_stored(state: &state::State, path: &str) -> bool {
+    !path.is_empty() && state.storage.exists(path).await
+}
+
+async fn open_stream(path: String, filename: String) -> Result<FileStream, YoutubeError> {
+    FileStream::open(path, filename, None).await.map_err(|e| {
+        eprintln!("[ERROR] Failed to open stored file: {}", e);
+        YoutubeError {
+            kind: YoutubeErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e.to_string(),
+        }
+    })
+}
+
+/// Writes `entries` (name in the archive, stored path) into a ZIP archive and stores it at `path`.
+async fn build_zip(
+    state: &state::State,
+    path: &str,
+    entries: Vec<(String, String)>,
+) -> Result<(), YoutubeError> {
+    let staging = &state.config.upload.upload_location;
+    let mut files = Vec::with_capacity(entries.len());
+    let mut local_entries = Vec::with_capacity(entries.len());
+    let mut result = Ok(());
+
+    for (name, stored_path) in entries {
+        match state.storage.local_file(&stored_path, staging).await {
+            Ok(file) => {
+                local_entries.push((name, file.path.to_string_lossy().to_string()));
+                files.push(file);
+            }
+            Err(e) => {
+                result = Err(e.to_string());
+                break;
+            }
+        }
     }
 
-    Ok(YoutubeOutput {
-        path: video.path,
-        uuid: uuid.to_string(),
-        name: video.name,
-        kind: format,
+    // Concurrent builds of the same archive must not share a file
+    let part_path = format!("{}.{}.part", path, Uuid::new_v4().simple());
+    if result.is_ok() {
+        let write_path = part_path.clone();
+        result = tokio::task::spawn_blocking(move || utils::write_zip(&write_path, &local_entries))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()));
+    }
+    for file in files {
+        file.release().await;
+    }
+
+    if result.is_ok() {
+        result = state
+            .storage
+            .put_file(path, Path::new(&part_path))
+            .await
+            .map_err(|e| e.to_string());
+    }
+
+    result.map_err(|e| {
+        eprintln!("[ERROR] Failed to build archive {}: {}", path, e);
+        let part_path = part_path.clone();
+        tokio::spawn(async move {
+            let _ = tokio::fs::remove_file(&part_path).await;
+        });
+        YoutubeError {
+            kind: YoutubeErrorKind::ServerIssue,
+            status: Status::InternalServerError,
+            message: e,
+        }
     })
 }
+
+fn quota_error(e: QuotaError) -> YoutubeError {
+    match e {
+        QuotaError::Exceeded(_) => YoutubeError {
+            kind: YoutubeErrorKind::QuotaExceeded,
+            status: Status::PayloadTooLarge,
+            message: String::from("Download exceeds your quota"),
+        },
+        QuotaError::Database(e) => {
+            eprintln!("[ERROR] Failed to get quota usage: {}", e);
+            YoutubeError {
+                kind: YoutubeErrorKind::ServerIssue,
+                status: Status::InternalServerError,
+                message: String::from("Failed to check quota"),
+            }
+        }
+    }
+}
+
+fn enqueue_error(e: EnqueueError) -> YoutubeError {
+    match e {
+        EnqueueError::TooManyJobs => YoutubeError {
+            kind: YoutubeErrorKind::TooManyJobs,
+            status: Status::TooManyRequests,
+            message: String::from("Too many downloads in progress, wait for one to finish"),
+        },
+    }
+}
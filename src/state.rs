@@ -1,7 +1,7 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{config::Config, db::{file::FileDB, user::UserDB, video::VideoDB}, routes::upload::UploadStatusMap};
-use tokio::sync::{OnceCell, RwLock};
+use crate::{config::Config, db::{file::{File, FileDB}, user::UserDB, video::VideoDB}, job_queue::JobMap, quota::QuotaLockMap, routes::{upload::UploadStatusMap, youtube::BatchArchiveMap}, storage::{self, StorageBackend}};
+use tokio::sync::{Mutex, OnceCell, RwLock, Semaphore};
 
 static APP_STATE: OnceCell<Arc<State>> = OnceCell::const_new();
 pub struct State {
@@ -11,6 +11,15 @@ pub struct State {
    pub config: Config,
    pub config_path: String,
    pub upload_status: UploadStatusMap,
+   pub jobs: JobMap,
+   /// Limits how many yt-dlp processes run at once.
+   pub job_slots: Arc<Semaphore>,
+   /// Serializes building the archive of each batch, see [`BatchArchiveMap`].
+   pub batch_archives: BatchArchiveMap,
+   /// Serializes quota checks of each user, see [`quota::reserve`](crate::quota::reserve).
+   pub quota_locks: QuotaLockMap,
+   /// Where finished files are kept, see [`storage`].
+   pub storage: Arc<dyn StorageBackend>,
 }
 
 impl State {
@@ -46,6 +55,12 @@ impl State {
     let user_db = UserDB::init(&config.database.user_db_path).await?;
     let video_db = VideoDB::init(&config.database.video_db_path).await?;
     let upload_status = Arc::new(RwLock::new(HashMap::new()));
+    let jobs = Arc::new(RwLock::new(HashMap::new()));
+    let job_slots = Arc::new(Semaphore::new(config.yt_dlp.max_concurrent_jobs));
+    let batch_archives = Arc::new(Mutex::new(HashMap::new()));
+    let quota_locks = Arc::new(Mutex::new(HashMap::new()));
+    let storage = storage::from_config(&config)?;
+    println!("[INFO  ] Using storage backend: {}", storage.name());
 
     Ok(Arc::new(Self {
       file_db,
@@ -54,6 +69,11 @@ impl State {
       config,
       config_path,
       upload_status,
+      jobs,
+      job_slots,
+      batch_archives,
+      quota_locks,
+      storage,
     }))
   }
 
@@ -63,4 +83,26 @@ impl State {
     let mut upload_state = self.upload_status.write().await;
     upload_state.remove(uuid);
   }
+
+  /// Removes `file` and its stored data right away, shared blobs stay until their last file is gone.
+  pub async fn remove_file(&self, file: &File) -> Result<(), Box<dyn std::error::Error>> {
+    self.remove_upload_status(&file.uuid).await;
+    self.file_db.remove_by_uuid(&file.uuid).await?;
+
+    // Held until the data is gone, so an upload of the same content can't store it in between
+    let _blob_lock = self.file_db.lock_blobs().await;
+    let path = if file.blob.is_empty() {
+      Some(file.path.clone())
+    } else {
+      self.file_db.take_unreferenced_blob(&file.blob).await?.map(|blob| blob.path)
+    };
+
+    if let Some(path) = path {
+      if let Err(e) = self.storage.delete(&path).await {
+        // The background worker cleans up anything left behind
+        eprintln!("[WARN  ] Failed to remove file {}: {}", &path, e);
+      }
+    }
+    Ok(())
+  }
 }
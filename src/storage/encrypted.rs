@@ -0,0 +1,654 @@
+use std::{
+    io::{self, Cursor},
+    path::Path,
+    sync::Arc,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use futures_util::{stream, StreamExt};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+
+use crate::config::{Config, EncryptionConfig};
+
+use super::{local::same_file, ByteStream, ObjectStat, StorageBackend};
+
+// Stored objects start with a fixed size header followed by the chunks,
+// each chunk is sealed on its own so ranges can be read without the rest.
+//
+// magic (4) | version (1) | key id (8) | chunk size (4) | size (8) | base nonce (16)
+// | wrap nonce (24) | wrapped data key (48)
+const MAGIC: &[u8; 4] = b"RENC";
+const VERSION: u8 = 1;
+const HEADER_LEN: u64 = 113;
+/// Part of the header the wrapped data key is bound to.
+const AUTHENTICATED_LEN: usize = 41;
+const CHUNK_SIZE: u32 = 64 * 1024;
+const TAG_LEN: u64 = 16;
+
+// MARK: Keys
+/// Master keys wrapping the per-file data keys.
+pub struct MasterKeys {
+    current: [u8; 32],
+    previous: Vec<[u8; 32]>,
+}
+
+impl MasterKeys {
+    /// Reads the keys from `RIST_MASTER_KEY` and `RIST_PREVIOUS_MASTER_KEYS`, falling back to the config.
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let current = std::env::var("RIST_MASTER_KEY").unwrap_or(config.master_key.clone());
+        let previous = match std::env::var("RIST_PREVIOUS_MASTER_KEYS") {
+            Ok(keys) => keys
+                .split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect::<Vec<_>>(),
+            Err(_) => config.previous_keys.clone(),
+        };
+
+        if current.is_empty() {
+            return Err("storage.encryption.master_key or RIST_MASTER_KEY must be set".into());
+        }
+
+        Ok(Self {
+            current: parse_key(&current)?,
+            previous: previous
+                .iter()
+                .map(|key| parse_key(key))
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+
+    fn find(&self, id: &[u8; 8]) -> Option<&[u8; 32]> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| &key_id(key) == id)
+    }
+
+    /// Wraps `data_key` with the current master key, filling in the key fields of `header`.
+    fn seal(&self, header: &mut Header, data_key: &[u8; 32]) -> io::Result<()> {
+        header.key_id = key_id(&self.current);
+        OsRng.fill_bytes(&mut header.wrap_nonce);
+
+        let wrapped = cipher(&self.current)
+            .encrypt(
+                XNonce::from_slice(&header.wrap_nonce),
+                Payload {
+                    msg: data_key,
+                    aad: &header.encode()[..AUTHENTICATED_LEN],
+                },
+            )
+            .map_err(|_| crypto_error("Failed to wrap data key"))?;
+        header.wrapped_key.copy_from_slice(&wrapped);
+        Ok(())
+    }
+
+    /// Unwraps the data key of `header`, this also authenticates the header.
+    fn open(&self, header: &Header) -> io::Result<[u8; 32]> {
+        let master = self.find(&header.key_id).ok_or_else(|| {
+            crypto_error(format!("Unknown master key {}", hex::encode(header.key_id)))
+        })?;
+
+        let data_key = cipher(master)
+            .decrypt(
+                XNonce::from_slice(&header.wrap_nonce),
+                Payload {
+                    msg: &header.wrapped_key,
+                    aad: &header.encode()[..AUTHENTICATED_LEN],
+                },
+            )
+            .map_err(|_| crypto_error("Failed to unwrap data key"))?;
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&data_key);
+        Ok(key)
+    }
+}
+
+// MARK: Header
+struct Header {
+    key_id: [u8; 8],
+    chunk_size: u32,
+    /// Size of the plaintext.
+    size: u64,
+    base_nonce: [u8; 16],
+    wrap_nonce: [u8; 24],
+    wrapped_key: [u8; 48],
+}
+
+impl Header {
+    fn new(size: u64) -> Self {
+        let mut header = Self {
+            key_id: [0; 8],
+            chunk_size: CHUNK_SIZE,
+            size,
+            base_nonce: [0; 16],
+            wrap_nonce: [0; 24],
+            wrapped_key: [0; 48],
+        };
+        OsRng.fill_bytes(&mut header.base_nonce);
+        header
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN as usize);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.chunk_size.to_le_bytes());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&self.base_nonce);
+        bytes.extend_from_slice(&self.wrap_nonce);
+        bytes.extend_from_slice(&self.wrapped_key);
+        bytes
+    }
+
+    /// `None` if `bytes` do not start an encrypted object.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN as usize || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+
+        let chunk_size = u32::from_le_bytes(bytes[13..17].try_into().ok()?);
+        if chunk_size == 0 {
+            return None;
+        }
+
+        Some(Self {
+            key_id: bytes[5..13].try_into().ok()?,
+            chunk_size,
+            size: u64::from_le_bytes(bytes[17..25].try_into().ok()?),
+            base_nonce: bytes[25..41].try_into().ok()?,
+            wrap_nonce: bytes[41..65].try_into().ok()?,
+            wrapped_key: bytes[65..113].try_into().ok()?,
+        })
+    }
+
+    /// Size of the stored object.
+    fn stored_size(&self) -> u64 {
+        let chunk_size = self.chunk_size as u64;
+        HEADER_LEN + self.size + self.size.div_ceil(chunk_size) * TAG_LEN
+    }
+
+    fn chunk_offset(&self, index: u64) -> u64 {
+        HEADER_LEN + index * (self.chunk_size as u64 + TAG_LEN)
+    }
+
+    /// Nonce of chunk `index`, so chunks can't be reordered.
+    fn chunk_nonce(&self, index: u64) -> XNonce {
+        let mut nonce = [0; 24];
+        nonce[..16].copy_from_slice(&self.base_nonce);
+        nonce[16..].copy_from_slice(&index.to_be_bytes());
+        *XNonce::from_slice(&nonce)
+    }
+}
+
+// MARK: Backend
+/// Encrypts objects of another backend with XChaCha20-Poly1305.
+///
+/// Every object gets its own data key, stored in the object header wrapped by
+/// the master key. Objects without a header are passed through unchanged, so
+/// encryption can be enabled on an existing store.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    keys: Arc<MasterKeys>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, keys: MasterKeys) -> Self {
+        Self {
+            inner,
+            keys: Arc::new(keys),
+        }
+    }
+
+    /// `None` for objects stored without encryption.
+    async fn header(&self, key: &str, stat: &ObjectStat) -> io::Result<Option<Header>> {
+        if stat.size < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut bytes = vec![0; HEADER_LEN as usize];
+        self.inner
+            .get_range(key, 0, HEADER_LEN)
+            .await?
+            .read_exact(&mut bytes)
+            .await?;
+        Ok(Header::decode(&bytes))
+    }
+
+    /// Re-wraps the data key of `key` with the current master key.
+    /// Returns `false` if there was nothing to do.
+    async fn rotate(&self, key: &str) -> io::Result<bool> {
+        let stat = match self.inner.stat(key).await? {
+            Some(stat) => stat,
+            None => return Ok(false),
+        };
+        let mut header = match self.header(key, &stat).await? {
+            Some(header) if header.key_id != key_id(&self.keys.current) => header,
+            _ => return Ok(false),
+        };
+
+        let data_key = self.keys.open(&header)?;
+        self.keys.seal(&mut header, &data_key)?;
+
+        // Only the header changes, the chunks are copied as they are
+        let body = self
+            .inner
+            .get_range(key, HEADER_LEN, stat.size - HEADER_LEN)
+            .await?;
+        let header = Cursor::new(header.encode());
+        self.inner
+            .put(key, Box::pin(header.chain(body)), stat.size)
+            .await?;
+        Ok(true)
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for EncryptedStorage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn put(&self, key: &str, body: ByteStream, size: u64) -> io::Result<()> {
+        let mut data_key = [0; 32];
+        OsRng.fill_bytes(&mut data_key);
+
+        let mut header = Header::new(size);
+        self.keys.seal(&mut header, &data_key)?;
+        let stored_size = header.stored_size();
+
+        self.inner
+            .put(key, encrypt(header, &data_key, body), stored_size)
+            .await
+    }
+
+    async fn get_range(&self, key: &str, start: u64, length: u64) -> io::Result<ByteStream> {
+        let stat = self.inner.stat(key).await?.ok_or_else(|| super::not_found(key))?;
+        let header = match self.header(key, &stat).await? {
+            Some(header) => header,
+            None => return self.inner.get_range(key, start, length).await,
+        };
+
+        let length = length.min(header.size.saturating_sub(start));
+        if length == 0 {
+            return Ok(Box::pin(tokio::io::empty()));
+        }
+
+        let data_key = self.keys.open(&header)?;
+        let chunk_size = header.chunk_size as u64;
+        let first = start / chunk_size;
+        let last = (start + length - 1) / chunk_size;
+
+        let offset = header.chunk_offset(first);
+        let end = header.chunk_offset(last + 1).min(header.stored_size());
+        let body = self.inner.get_range(key, offset, end - offset).await?;
+
+        Ok(decrypt(header, &data_key, body, first, start - first * chunk_size, length))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectStat>> {
+        let stat = match self.inner.stat(key).await? {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+
+        Ok(Some(match self.header(key, &stat).await? {
+            Some(header) => ObjectStat {
+                size: header.size,
+                modified: stat.modified,
+            },
+            None => stat,
+        }))
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list().await
+    }
+
+    fn shares_staging(&self) -> bool {
+        self.inner.shares_staging()
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        // Local stores encrypt staged files in place
+        let in_place = self
+            .inner
+            .local_path(key)
+            .is_some_and(|target| same_file(path, &target));
+
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        self.put(key, Box::pin(file), size).await?;
+
+        if in_place {
+            return Ok(());
+        }
+        tokio::fs::remove_file(path).await
+    }
+
+    /// Copies the stored bytes, both objects share the data key.
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.copy(from, to).await
+    }
+}
+
+// MARK: Rotation
+/// Re-wraps the data keys of all stored objects with the current master key,
+/// after which the previous keys can be removed from the config.
+pub async fn rotate_keys(config: &Config) -> Result<usize, Box<dyn std::error::Error>> {
+    let storage = EncryptedStorage::new(
+        super::backend_from_config(config)?,
+        MasterKeys::from_config(&config.storage.encryption)?,
+    );
+
+    let mut rotated = 0;
+    for key in storage.list().await? {
+        match storage.rotate(&key).await {
+            Ok(true) => {
+                println!("[INFO  ] Rotated key of {}", key);
+                rotated += 1;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("[ERROR] Failed to rotate key of {}: {}", key, e);
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(rotated)
+}
+
+// MARK: Streams
+fn encrypt(header: Header, data_key: &[u8; 32], body: ByteStream) -> ByteStream {
+    let cipher = Arc::new(cipher(data_key));
+    let header = Arc::new(header);
+    let size = header.size;
+    let encoded = header.encode();
+
+    let chunks = stream::try_unfold((body, 0u64, size), move |(mut body, index, remaining)| {
+        let (cipher, header) = (cipher.clone(), header.clone());
+        async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+
+            let mut chunk = vec![0; remaining.min(header.chunk_size as u64) as usize];
+            body.read_exact(&mut chunk).await?;
+            let sealed = cipher
+                .encrypt(&header.chunk_nonce(index), chunk.as_slice())
+                .map_err(|_| crypto_error("Failed to encrypt chunk"))?;
+
+            let remaining = remaining - chunk.len() as u64;
+            Ok::<_, io::Error>(Some((Cursor::new(sealed), (body, index + 1, remaining))))
+        }
+    });
+
+    let header = stream::once(async move { Ok(Cursor::new(encoded)) });
+    Box::pin(StreamReader::new(header.chain(chunks)))
+}
+
+/// Decrypts chunks starting at chunk `first`, skipping `skip` bytes of it
+/// and returning `length` bytes in total.
+fn decrypt(
+    header: Header,
+    data_key: &[u8; 32],
+    body: ByteStream,
+    first: u64,
+    skip: u64,
+    length: u64,
+) -> ByteStream {
+    let cipher = Arc::new(cipher(data_key));
+    let header = Arc::new(header);
+
+    let chunks = stream::try_unfold(
+        (body, first, skip as usize, length),
+        move |(mut body, index, skip, remaining)| {
+            let (cipher, header) = (cipher.clone(), header.clone());
+            async move {
+                if remaining == 0 {
+                    return Ok(None);
+                }
+
+                let chunk_size = header.chunk_size as u64;
+                let plain_len = chunk_size.min(header.size - index * chunk_size);
+                let mut sealed = vec![0; (plain_len + TAG_LEN) as usize];
+                body.read_exact(&mut sealed).await?;
+                let chunk = cipher
+                    .decrypt(&header.chunk_nonce(index), sealed.as_slice())
+                    .map_err(|_| crypto_error(format!("Chunk {} failed authentication", index)))?;
+
+                let end = (skip as u64 + remaining).min(chunk.len() as u64) as usize;
+                let part = chunk[skip..end].to_vec();
+                let remaining = remaining - part.len() as u64;
+                Ok::<_, io::Error>(Some((Cursor::new(part), (body, index + 1, 0, remaining))))
+            }
+        },
+    );
+
+    Box::pin(StreamReader::new(chunks))
+}
+
+// MARK: Utils
+fn cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new_from_slice(key).expect("XChaCha20-Poly1305 keys are 32 bytes")
+}
+
+/// Identifies a master key in headers without revealing it.
+fn key_id(key: &[u8; 32]) -> [u8; 8] {
+    let digest = Sha256::digest(key);
+    let mut id = [0; 8];
+    id.copy_from_slice(&digest[..8]);
+    id
+}
+
+fn parse_key(key: &str) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let bytes = hex::decode(key.trim())?;
+    bytes
+        .try_into()
+        .map_err(|_| "Master keys must be 32 bytes (64 hex characters)".into())
+}
+
+fn crypto_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    /// Keeps objects in memory, so tests see exactly what was stored.
+    #[derive(Default)]
+    struct MemoryStorage {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MemoryStorage {
+        fn stored_len(&self, key: &str) -> u64 {
+            self.objects.lock().unwrap()[key].len() as u64
+        }
+    }
+
+    #[rocket::async_trait]
+    impl StorageBackend for MemoryStorage {
+        fn name(&self) -> &'static str {
+            "memory"
+        }
+
+        async fn put(&self, key: &str, body: ByteStream, size: u64) -> io::Result<()> {
+            let mut bytes = Vec::new();
+            body.take(size).read_to_end(&mut bytes).await?;
+            self.objects.lock().unwrap().insert(key.to_string(), bytes);
+            Ok(())
+        }
+
+        async fn get_range(&self, key: &str, start: u64, length: u64) -> io::Result<ByteStream> {
+            let objects = self.objects.lock().unwrap();
+            let bytes = objects.get(key).ok_or_else(|| crate::storage::not_found(key))?;
+            let start = (start as usize).min(bytes.len());
+            let end = start.saturating_add(length as usize).min(bytes.len());
+            Ok(Box::pin(Cursor::new(bytes[start..end].to_vec())))
+        }
+
+        async fn delete(&self, key: &str) -> io::Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn stat(&self, key: &str) -> io::Result<Option<ObjectStat>> {
+            Ok(self.objects.lock().unwrap().get(key).map(|bytes| ObjectStat {
+                size: bytes.len() as u64,
+                modified: None,
+            }))
+        }
+
+        async fn list(&self) -> io::Result<Vec<String>> {
+            Ok(self.objects.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    fn keys(current: u8, previous: &[u8]) -> MasterKeys {
+        MasterKeys {
+            current: [current; 32],
+            previous: previous.iter().map(|key| [*key; 32]).collect(),
+        }
+    }
+
+    fn storage(inner: &Arc<MemoryStorage>, keys: MasterKeys) -> EncryptedStorage {
+        EncryptedStorage::new(inner.clone(), keys)
+    }
+
+    fn data(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn store(storage: &EncryptedStorage, key: &str, bytes: &[u8]) {
+        let body = Box::pin(Cursor::new(bytes.to_vec()));
+        storage.put(key, body, bytes.len() as u64).await.unwrap();
+    }
+
+    async fn read(storage: &EncryptedStorage, key: &str, start: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        storage.get_range(key, start, length).await?.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn round_trips_empty_files() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = storage(&inner, keys(1, &[]));
+        store(&storage, "empty", &[]).await;
+
+        assert_eq!(inner.stored_len("empty"), HEADER_LEN);
+        assert_eq!(storage.stat("empty").await.unwrap().unwrap().size, 0);
+        assert!(read(&storage, "empty", 0, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn round_trips_exact_chunk_multiples() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = storage(&inner, keys(1, &[]));
+
+        for chunks in [1, 2, 3] {
+            let bytes = data(chunks * CHUNK);
+            store(&storage, "file", &bytes).await;
+
+            assert_eq!(inner.stored_len("file"), HEADER_LEN + chunks * (CHUNK + TAG_LEN));
+            assert_eq!(storage.stat("file").await.unwrap().unwrap().size, chunks * CHUNK);
+            assert_eq!(read(&storage, "file", 0, chunks * CHUNK).await.unwrap(), bytes);
+
+            let last = (chunks - 1) * CHUNK;
+            assert_eq!(read(&storage, "file", last, CHUNK).await.unwrap(), bytes[last as usize..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_across_chunk_edges() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = storage(&inner, keys(1, &[]));
+        let bytes = data(3 * CHUNK + 100);
+        store(&storage, "file", &bytes).await;
+
+        for (start, length) in [
+            (0, 1),
+            (CHUNK - 1, 2),
+            (CHUNK - 10, 20),
+            (CHUNK, CHUNK),
+            (CHUNK - 1, CHUNK + 2),
+            (10, 2 * CHUNK + 80),
+            (3 * CHUNK - 1, 101),
+            (3 * CHUNK + 99, 1),
+        ] {
+            let range = start as usize..(start + length) as usize;
+            assert_eq!(
+                read(&storage, "file", start, length).await.unwrap(),
+                bytes[range],
+                "range {}+{}",
+                start,
+                length
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn clamps_reads_past_eof() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = storage(&inner, keys(1, &[]));
+        let size = CHUNK + 10;
+        let bytes = data(size);
+        store(&storage, "file", &bytes).await;
+
+        assert_eq!(read(&storage, "file", CHUNK, 1000).await.unwrap(), bytes[CHUNK as usize..]);
+        assert_eq!(read(&storage, "file", 0, u64::MAX).await.unwrap(), bytes);
+        assert!(read(&storage, "file", size, 10).await.unwrap().is_empty());
+        assert!(read(&storage, "file", size + CHUNK, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rotates_objects_under_a_previous_key() {
+        let inner = Arc::new(MemoryStorage::default());
+        let bytes = data(2 * CHUNK + 7);
+        store(&storage(&inner, keys(1, &[])), "file", &bytes).await;
+
+        // Before the rotation the old key is still needed
+        let without_old = storage(&inner, keys(2, &[]));
+        let error = read(&without_old, "file", 0, 10).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let rotating = storage(&inner, keys(2, &[1]));
+        assert_eq!(read(&rotating, "file", 0, u64::MAX).await.unwrap(), bytes);
+        assert!(rotating.rotate("file").await.unwrap());
+        assert!(!rotating.rotate("file").await.unwrap());
+
+        assert_eq!(inner.stored_len("file"), HEADER_LEN + 2 * CHUNK + 7 + 3 * TAG_LEN);
+        assert_eq!(read(&without_old, "file", 0, u64::MAX).await.unwrap(), bytes);
+        assert_eq!(read(&without_old, "file", CHUNK - 3, 6).await.unwrap(), bytes[(CHUNK - 3) as usize..(CHUNK + 3) as usize]);
+    }
+
+    #[tokio::test]
+    async fn passes_plain_objects_through() {
+        let inner = Arc::new(MemoryStorage::default());
+        let bytes = data(500);
+        inner.put("plain", Box::pin(Cursor::new(bytes.clone())), 500).await.unwrap();
+
+        let storage = storage(&inner, keys(1, &[]));
+        assert_eq!(storage.stat("plain").await.unwrap().unwrap().size, 500);
+        assert_eq!(read(&storage, "plain", 100, 50).await.unwrap(), bytes[100..150]);
+        assert!(!storage.rotate("plain").await.unwrap());
+    }
+}
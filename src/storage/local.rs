@@ -0,0 +1,140 @@
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{object_key, ByteStream, ObjectStat, StorageBackend};
+
+/// Stores files in a directory, the same one uploads are staged in.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(object_key(key))
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, key: &str, mut body: ByteStream, size: u64) -> io::Result<()> {
+        let path = self.path(key);
+        let part_path = PathBuf::from(format!("{}.part", path.display()));
+
+        let mut file = tokio::fs::File::create(&part_path).await?;
+        let written = tokio::io::copy(&mut (&mut body).take(size), &mut file).await;
+        match written {
+            Ok(written) if written == size => tokio::fs::rename(&part_path, &path).await,
+            Ok(written) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Expected {} bytes, got {}", size, written),
+                ))
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_range(&self, key: &str, start: u64, length: u64) -> io::Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(file.take(length)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectStat>> {
+        let metadata = match tokio::fs::metadata(self.path(key)).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Some(ObjectStat {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+        }))
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut keys = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                keys.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+
+    fn shares_staging(&self) -> bool {
+        true
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        let target = self.path(key);
+        if same_file(path, &target) {
+            return Ok(());
+        }
+
+        if tokio::fs::rename(path, &target).await.is_err() {
+            // Staging may be on another file system
+            tokio::fs::copy(path, &target).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Hard links where possible so copies share the disk space.
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (self.path(from), self.path(to));
+        if let Err(e) = tokio::fs::hard_link(&from, &to).await {
+            println!("[WARN  ] Failed to link {}, copying instead: {}", from.display(), e);
+            tokio::fs::copy(&from, &to).await?;
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{config::Config, utils};
+
+pub mod encrypted;
+pub mod local;
+pub mod s3;
+
+/// Readers handed to and returned by storage backends.
+pub type ByteStream = Pin<Box<dyn AsyncRead + Send>>;
+
+// MARK: Models
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectStat {
+    pub size: u64,
+    /// Unix timestamp of the last modification, if the backend knows it.
+    pub modified: Option<u64>,
+}
+
+/// A stored object made available as a local file.
+pub struct LocalFile {
+    pub path: PathBuf,
+    /// Set when the file was fetched from the backend and should be removed after use.
+    pub temporary: bool,
+}
+
+// MARK: Backend
+/// Where finished files live.
+///
+/// Uploads, yt-dlp output and clips are first written to `upload_location` and
+/// handed to the backend once complete. Keys are file names; the paths stored
+/// in the databases are accepted as keys and reduced to their file name.
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Stores `size` bytes read from `body` under `key`, replacing an existing object.
+    async fn put(&self, key: &str, body: ByteStream, size: u64) -> io::Result<()>;
+
+    /// Reads `length` bytes of `key` starting at `start`.
+    async fn get_range(&self, key: &str, start: u64, length: u64) -> io::Result<ByteStream>;
+
+    /// Removes `key`, missing objects are not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// `None` if `key` is not stored.
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectStat>>;
+
+    /// Keys of all stored objects.
+    async fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Path of `key` on this machine, for backends that store files locally.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether objects are kept in the staging directory itself.
+    fn shares_staging(&self) -> bool {
+        false
+    }
+
+    /// Moves the finished local file at `path` into the backend under `key`.
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        self.put(key, Box::pin(file), size).await?;
+        tokio::fs::remove_file(path).await
+    }
+
+    /// Stores the content of `from` under `to` as well.
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let stat = self.stat(from).await?.ok_or_else(|| not_found(from))?;
+        let body = self.get_range(from, 0, stat.size).await?;
+        self.put(to, body, stat.size).await
+    }
+
+    async fn exists(&self, key: &str) -> bool {
+        match self.stat(key).await {
+            Ok(stat) => stat.is_some(),
+            Err(e) => {
+                eprintln!("[ERROR] Storage '{}' failed to stat {}: {}", self.name(), key, e);
+                false
+            }
+        }
+    }
+
+    /// Makes `key` available as a local file, fetching it into `staging` if needed.
+    async fn local_file(&self, key: &str, staging: &str) -> io::Result<LocalFile> {
+        if let Some(path) = self.local_path(key) {
+            return Ok(LocalFile {
+                path,
+                temporary: false,
+            });
+        }
+
+        let stat = self.stat(key).await?.ok_or_else(|| not_found(key))?;
+        let path = PathBuf::from(format!(
+            "{}{}.{}.fetch",
+            staging,
+            object_key(key),
+            uuid::Uuid::new_v4().simple()
+        ));
+
+        let mut body = self.get_range(key, 0, stat.size).await?.take(stat.size);
+        let mut file = tokio::fs::File::create(&path).await?;
+        if let Err(e) = tokio::io::copy(&mut body, &mut file).await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        Ok(LocalFile {
+            path,
+            temporary: true,
+        })
+    }
+}
+
+impl LocalFile {
+    pub async fn release(self) {
+        if self.temporary {
+            let _ = tokio::fs::remove_file(&self.path).await;
+        }
+    }
+}
+
+/// Builds the backend selected in `config.storage`, encrypting if enabled.
+pub fn from_config(config: &Config) -> Result<Arc<dyn StorageBackend>, Box<dyn std::error::Error>> {
+    let backend = backend_from_config(config)?;
+    if !config.storage.encryption.enabled {
+        return Ok(backend);
+    }
+
+    let keys = encrypted::MasterKeys::from_config(&config.storage.encryption)?;
+    println!("[INFO  ] Encrypting stored files");
+    Ok(Arc::new(encrypted::EncryptedStorage::new(backend, keys)))
+}
+
+/// The backend selected in `config.storage.backend`, without encryption.
+pub fn backend_from_config(config: &Config) -> Result<Arc<dyn StorageBackend>, Box<dyn std::error::Error>> {
+    match config.storage.backend.as_str() {
+        "local" => Ok(Arc::new(local::LocalStorage::new(&config.upload.upload_location))),
+        "s3" => Ok(Arc::new(s3::S3Storage::new(&config.storage.s3)?)),
+        backend => Err(format!("Unknown storage backend: {}", backend).into()),
+    }
+}
+
+/// Object key of a stored path.
+pub fn object_key(path: &str) -> String {
+    utils::get_filename_from_path(path).unwrap_or_default()
+}
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} is not stored", key))
+}
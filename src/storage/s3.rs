@@ -0,0 +1,316 @@
+use std::{io, sync::OnceLock};
+
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::{header::CONTENT_LENGTH, Method, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{config::S3Config, utils};
+
+use super::{object_key, ByteStream, ObjectStat, StorageBackend};
+
+/// Bodies are streamed, so they are not part of the signature.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Stores files in an S3 compatible bucket (AWS, MinIO, ...), signed with SigV4.
+/// Single requests are used, so objects are limited to 5 GiB.
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+    path_style: bool,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.bucket.is_empty() {
+            return Err("storage.s3.bucket must be set".into());
+        }
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: reqwest::Url::parse(&config.endpoint)?,
+            bucket: config.bucket.clone(),
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            prefix: config.prefix.clone(),
+            path_style: config.path_style,
+        })
+    }
+
+    /// URL of the bucket, or of `key` in it.
+    fn url(&self, key: Option<&str>) -> io::Result<reqwest::Url> {
+        let mut url = self.endpoint.clone();
+        let mut path = String::new();
+
+        if self.path_style {
+            path.push('/');
+            path.push_str(&uri_encode(&self.bucket, false));
+        } else {
+            let host = format!("{}.{}", self.bucket, url.host_str().unwrap_or_default());
+            url.set_host(Some(&host)).map_err(io::Error::other)?;
+        }
+
+        path.push('/');
+        if let Some(key) = key {
+            path.push_str(&uri_encode(&format!("{}{}", self.prefix, object_key(key)), false));
+        }
+        url.set_path(&path);
+        Ok(url)
+    }
+
+    /// Builds a signed request, `query` has to be sorted by name.
+    fn request(
+        &self,
+        method: Method,
+        url: reqwest::Url,
+        query: &[(&str, &str)],
+    ) -> reqwest::RequestBuilder {
+        let mut url = url;
+        let canonical_query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+        if !canonical_query.is_empty() {
+            url.set_query(Some(&canonical_query));
+        }
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let amz_date = utils::format_amz_date(utils::get_current_timestamp());
+        let date = &amz_date[..8];
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method.as_str(),
+            url.path(),
+            canonical_query,
+            host,
+            UNSIGNED_PAYLOAD,
+            amz_date,
+            UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        self.client
+            .request(method, url)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header("x-amz-date", amz_date.as_str())
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.access_key, scope, signature
+                ),
+            )
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> io::Result<reqwest::Response> {
+        request.send().await.map_err(io::Error::other)
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, key: &str, body: ByteStream, size: u64) -> io::Result<()> {
+        let request = self
+            .request(Method::PUT, self.url(Some(key))?, &[])
+            .header("Content-Length", size)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(body)));
+
+        let response = self.send(request).await?;
+        check_status(key, response).await.map(|_| ())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, length: u64) -> io::Result<ByteStream> {
+        if length == 0 {
+            return Ok(Box::pin(tokio::io::empty()));
+        }
+
+        let request = self
+            .request(Method::GET, self.url(Some(key))?, &[])
+            .header("Range", format!("bytes={}-{}", start, start + length - 1));
+
+        let response = check_status(key, self.send(request).await?).await?;
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let response = self
+            .send(self.request(Method::DELETE, self.url(Some(key))?, &[]))
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        check_status(key, response).await.map(|_| ())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<ObjectStat>> {
+        let response = self
+            .send(self.request(Method::HEAD, self.url(Some(key))?, &[]))
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(key, response).await?;
+
+        // `content_length()` is the size of the (empty) HEAD body, not of the object
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("S3 sent no valid Content-Length for {}", key),
+                )
+            })?;
+
+        Ok(Some(ObjectStat {
+            size,
+            modified: response
+                .headers()
+                .get("Last-Modified")
+                .and_then(|value| value.to_str().ok())
+                .and_then(utils::parse_http_date),
+        }))
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            query.push(("list-type", "2"));
+            if !self.prefix.is_empty() {
+                query.push(("prefix", self.prefix.as_str()));
+            }
+
+            let response = self
+                .send(self.request(Method::GET, self.url(None)?, &query))
+                .await?;
+            let body = check_status("bucket listing", response)
+                .await?
+                .text()
+                .await
+                .map_err(io::Error::other)?;
+
+            keys.extend(
+                xml_values(&body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.prefix).map(|key| key.to_string())),
+            );
+
+            match xml_values(&body, "NextContinuationToken").into_iter().next() {
+                Some(next) if xml_values(&body, "IsTruncated").first().map(|v| v.as_str()) == Some("true") => {
+                    token = Some(next)
+                }
+                _ => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        // Server side copy, the source is given as `/<bucket>/<key>`
+        let source = format!(
+            "/{}/{}",
+            self.bucket,
+            uri_encode(&format!("{}{}", self.prefix, object_key(from)), false)
+        );
+        let request = self
+            .request(Method::PUT, self.url(Some(to))?, &[])
+            .header("x-amz-copy-source", source);
+
+        check_status(to, self.send(request).await?).await.map(|_| ())
+    }
+}
+
+async fn check_status(key: &str, response: reqwest::Response) -> io::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let kind = match status {
+        StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+        StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    let body = response.text().await.unwrap_or_default();
+    Err(io::Error::new(
+        kind,
+        format!("S3 returned {} for {}: {}", status, key, body),
+    ))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent encodes everything except unreserved characters, `/` is kept for paths.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) || (b == b'/' && !encode_slash) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// Values of all `<tag>` elements, good enough for S3's flat list responses.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    let re = TAG_RE.get_or_init(|| Regex::new(r"<(\w+)>([^<]*)</(\w+)>").unwrap());
+
+    re.captures_iter(xml)
+        .filter(|caps| &caps[1] == tag && &caps[3] == tag)
+        .map(|caps| {
+            caps[2]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
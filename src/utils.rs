@@ -1,3 +1,9 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
 use std::{
     fs, io,
     path::{Path, PathBuf},
@@ -48,6 +54,230 @@ pub fn get_file_with_extension(input_path: &str) -> Result<Option<PathBuf>, io::
     Ok(target_file_path)
 }
 
+/// Generates a random 64 character hex token.
+pub fn generate_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Hashes a token with a random salt, stored as `sha256$<salt>$<hash>`.
+pub fn hash_token(token: &str) -> String {
+    let salt = uuid::Uuid::new_v4().simple().to_string();
+    format!("sha256${}${}", salt, salted_hash(&salt, token))
+}
+
+/// Checks a token against a value produced by [`hash_token`].
+pub fn verify_token(token: &str, stored: &str) -> bool {
+    let mut parts = stored.splitn(3, '$');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("sha256"), Some(salt), Some(hash)) => {
+            constant_time_eq(salted_hash(salt, token).as_bytes(), hash.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Hashes a password with Argon2id, stored in the PHC string format.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Checks a password against a value produced by [`hash_password`].
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `message`.
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks a signature produced by [`sign`].
+pub fn verify_signature(secret: &str, message: &str, signature: &str) -> bool {
+    constant_time_eq(sign(secret, message).as_bytes(), signature.as_bytes())
+}
+
+pub fn sha256_hex(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+fn salted_hash(salt: &str, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(token.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn get_extension_from_path(path: &str) -> Option<String> {
     path.split(".").last().map(|p| p.to_string())
 }
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a unix timestamp as an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`).
+pub fn format_http_date(timestamp: u64) -> String {
+    let days = timestamp / 86_400;
+    let secs = timestamp % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+/// Formats a unix timestamp as an ISO 8601 basic date (`19941106T084937Z`).
+pub fn format_amz_date(timestamp: u64) -> String {
+    let secs = timestamp % 86_400;
+    let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+/// Parses an HTTP date in the IMF-fixdate format back into a unix timestamp.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day = parts[1].parse::<u32>().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[2])? as u32 + 1;
+    let year = parts[3].parse::<i64>().ok()?;
+
+    let time = parts[4]
+        .split(':')
+        .map(|p| p.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    Some(days as u64 * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+// Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// Writes `entries` (name in the archive, path on disk) into an uncompressed ZIP archive.
+/// Media is already compressed, storing keeps archiving cheap.
+pub fn write_zip(path: &str, entries: &[(String, String)]) -> zip::result::ZipResult<()> {
+    let mut zip = zip::ZipWriter::new(fs::File::create(path)?);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .large_file(true);
+
+    for (name, file_path) in entries {
+        zip.start_file(name.as_str(), options)?;
+        io::copy(&mut fs::File::open(file_path)?, &mut zip)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(format_http_date(4_102_444_799), "Thu, 31 Dec 2099 23:59:59 GMT");
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date(" Thu, 01 Jan 1970 00:00:00 GMT "), Some(0));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"), Some(951_782_400));
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        for timestamp in [0, 59, 86_399, 86_400, 951_868_799, 1_700_000_000, 4_102_444_799] {
+            assert_eq!(parse_http_date(&format_http_date(timestamp)), Some(timestamp));
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_http_dates() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:60:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Wed, 01 Jan 1969 00:00:00 GMT"), None);
+    }
+}
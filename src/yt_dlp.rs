@@ -0,0 +1,407 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::{
+    config::YtDlpConfig,
+    db::video::{Video, YoutubeKind, YoutubeQuality},
+};
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub percent: f32,
+    pub eta_secs: Option<u64>,
+    pub speed: Option<String>,
+}
+
+/// Containers that only hold audio, extracted with `--extract-audio`.
+pub const AUDIO_CONTAINERS: [&str; 5] = ["mp3", "wav", "opus", "flac", "m4a"];
+/// Containers video is merged or remuxed into.
+pub const VIDEO_CONTAINERS: [&str; 3] = ["mp4", "webm", "mkv"];
+
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+const SUBTITLE_EXTENSIONS: [&str; 6] = ["srt", "vtt", "ass", "ssa", "lrc", "ttml"];
+const TEMP_EXTENSIONS: [&str; 4] = ["part", "ytdl", "temp", "tmp"];
+
+/// One entry of the `formats` list in yt-dlp's JSON.
+#[derive(Serialize, Clone, Debug)]
+pub struct MediaFormat {
+    pub format_id: String,
+    pub ext: String,
+    pub note: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub fps: Option<f64>,
+    /// `none` for audio-only formats, missing if unknown.
+    pub vcodec: Option<String>,
+    /// `none` for video-only formats, missing if unknown.
+    pub acodec: Option<String>,
+    /// Audio bitrate in kbit/s.
+    pub abr: Option<f64>,
+    /// Exact size if known, otherwise yt-dlp's estimate.
+    pub filesize: Option<u64>,
+}
+
+impl MediaFormat {
+    pub fn has_video(&self) -> bool {
+        self.vcodec.as_deref() != Some("none")
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.acodec.as_deref() != Some("none")
+    }
+
+    /// Selector downloading this format, video-only formats get the best audio added.
+    pub fn selector(&self) -> String {
+        if self.has_video() && !self.has_audio() {
+            format!("{0}+bestaudio/{0}", self.format_id)
+        } else {
+            self.format_id.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Playlist {
+    pub id: String,
+    pub title: String,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PlaylistEntry {
+    /// 1-based position in the playlist.
+    pub index: usize,
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub extractor: String,
+}
+
+/// Output path of `video` without extension.
+/// Ids of other extractors may contain anything, so only a safe part is kept.
+pub fn output_path(upload_location: &str, video: &Video) -> String {
+    let id = video
+        .vid_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(32)
+        .collect::<String>();
+
+    format!("{}{}-{}", upload_location, video.uuid, id)
+}
+
+/// What a file yt-dlp wrote next to the output is.
+#[derive(Debug, PartialEq)]
+pub enum OutputFile {
+    Media,
+    Subtitle(String),
+    Thumbnail,
+    Chapter(u32),
+}
+
+/// Classifies `file_name` written for the output `prefix` (file name without extension).
+/// Temporary and unrelated files return `None`.
+pub fn classify_output(prefix: &str, file_name: &str) -> Option<OutputFile> {
+    let rest = file_name.strip_prefix(prefix)?.strip_prefix('.')?;
+    let parts = rest.split('.').collect::<Vec<_>>();
+
+    match parts.as_slice() {
+        [ext] if IMAGE_EXTENSIONS.contains(ext) => Some(OutputFile::Thumbnail),
+        [ext] if !TEMP_EXTENSIONS.contains(ext) => Some(OutputFile::Media),
+        [name, ext] if !TEMP_EXTENSIONS.contains(ext) => match name.strip_prefix("chapter") {
+            Some(number) => number.parse().ok().map(OutputFile::Chapter),
+            None if SUBTITLE_EXTENSIONS.contains(ext) => Some(OutputFile::Subtitle(name.to_string())),
+            None => None,
+        },
+        _ => None,
+    }
+}
+
+/// Builds the yt-dlp command downloading `video` to `output` (without extension).
+/// `max_filesize` makes yt-dlp skip formats it knows to be larger.
+pub fn download_command(
+    config: &YtDlpConfig,
+    video: &Video,
+    output: &str,
+    max_filesize: Option<u64>,
+) -> Command {
+    let quality = YoutubeQuality::from_u8(video.quality);
+    let format = YoutubeKind::from_u8(video.format);
+
+    let mut cmd = Command::new(&config.dpl_exec_path);
+    cmd.args(&config.dpl_args);
+    cmd.arg("--newline");
+    // Every job downloads exactly one item, playlists are split into batches
+    cmd.arg("--no-playlist");
+    cmd.arg("-o");
+    cmd.arg(format!("{}.%(ext)s", output));
+
+    let audio = is_audio_target(video);
+    if audio {
+        let audio_format = if video.container.is_empty() {
+            format.as_str()
+        } else {
+            video.container.as_str()
+        };
+        cmd.arg("--extract-audio")
+            .arg("--audio-format")
+            .arg(audio_format);
+    } else if !video.container.is_empty() {
+        cmd.arg("--merge-output-format")
+            .arg(&video.container)
+            .arg("--remux-video")
+            .arg(&video.container);
+    }
+
+    if !video.format_id.is_empty() {
+        cmd.arg("--format").arg(&video.format_id);
+    } else if quality.use_selection() {
+        if audio {
+            cmd.arg("-S").arg(quality.as_str_audio());
+        } else {
+            cmd.arg("-S").arg(quality.as_str_vid());
+        }
+    } else {
+        if audio {
+            cmd.arg("--audio-quality").arg(quality.as_str_audio());
+        }
+        cmd.arg("--format").arg(quality.as_str_vid());
+    }
+
+    if !video.section.is_empty() {
+        cmd.arg("--download-sections").arg(&video.section);
+    }
+
+    if let Some(max_filesize) = max_filesize {
+        cmd.arg("--max-filesize").arg(max_filesize.to_string());
+    }
+
+    let options = video.options();
+    if !options.subtitles.is_empty() {
+        cmd.arg("--write-subs")
+            .arg("--sub-langs")
+            .arg(options.subtitles.join(","));
+        if options.auto_subtitles {
+            cmd.arg("--write-auto-subs");
+        }
+        if let Some(subtitle_format) = options.subtitle_format {
+            cmd.arg("--convert-subs").arg(subtitle_format.as_str());
+        }
+    }
+    if options.write_thumbnail {
+        cmd.arg("--write-thumbnail")
+            .arg("--convert-thumbnails")
+            .arg("jpg");
+    }
+    if options.embed_thumbnail {
+        cmd.arg("--embed-thumbnail");
+    }
+    if options.embed_metadata {
+        cmd.arg("--embed-metadata");
+    }
+    if options.split_chapters {
+        cmd.arg("--split-chapters")
+            .arg("-o")
+            .arg(format!("chapter:{}.chapter%(section_number)03d.%(ext)s", output));
+    }
+
+    cmd.arg("--").arg(video.source_url());
+    cmd
+}
+
+/// Whether `video` ends up as an audio file.
+pub fn is_audio_target(video: &Video) -> bool {
+    if video.container.is_empty() {
+        YoutubeKind::from_u8(video.format).is_audio()
+    } else {
+        AUDIO_CONTAINERS.contains(&video.container.as_str())
+    }
+}
+
+/// Lists the downloadable formats of a single video JSON, storyboards are skipped.
+pub fn parse_formats(json: &Value) -> Vec<MediaFormat> {
+    json["formats"]
+        .as_array()
+        .map(|formats| {
+            formats
+                .iter()
+                .filter_map(|format| {
+                    let format = MediaFormat {
+                        format_id: format["format_id"].as_str()?.to_string(),
+                        ext: format["ext"].as_str().unwrap_or("").to_string(),
+                        note: format["format_note"].as_str().map(|note| note.to_string()),
+                        width: format["width"].as_u64(),
+                        height: format["height"].as_u64(),
+                        fps: format["fps"].as_f64(),
+                        vcodec: format["vcodec"].as_str().map(|codec| codec.to_string()),
+                        acodec: format["acodec"].as_str().map(|codec| codec.to_string()),
+                        abr: format["abr"].as_f64(),
+                        filesize: format["filesize"]
+                            .as_u64()
+                            .or(format["filesize_approx"].as_u64()),
+                    };
+
+                    if !format.has_video() && !format.has_audio() {
+                        return None;
+                    }
+                    Some(format)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parses a `--newline` progress line like
+/// `[download]  45.3% of ~ 10.52MiB at  1.23MiB/s ETA 00:07 (frag 3/10)`.
+pub fn parse_progress(line: &str) -> Option<Progress> {
+    static PROGRESS_RE: OnceLock<Regex> = OnceLock::new();
+    let re = PROGRESS_RE.get_or_init(|| {
+        Regex::new(r"^\[download\]\s+([\d.]+)%(?:.*?\bat\s+(\S+))?(?:.*?\bETA\s+(\S+))?").unwrap()
+    });
+
+    let caps = re.captures(line.trim())?;
+    let percent = caps.get(1)?.as_str().parse::<f32>().ok()?;
+    let speed = caps
+        .get(2)
+        .map(|m| m.as_str().to_string())
+        .filter(|speed| !speed.starts_with("Unknown"));
+    let eta_secs = caps.get(3).and_then(|m| parse_duration(m.as_str()));
+
+    Some(Progress {
+        percent,
+        eta_secs,
+        speed,
+    })
+}
+
+/// Formats seconds as `MM:SS` or `HH:MM:SS`.
+pub fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{:02}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Parses `SS`, `MM:SS` or `HH:MM:SS` into seconds.
+pub fn parse_duration(value: &str) -> Option<u64> {
+    if value.split(':').count() > 3 {
+        return None;
+    }
+
+    value
+        .split(':')
+        .try_fold(0u64, |total, part| {
+            total.checked_mul(60)?.checked_add(part.parse::<u64>().ok()?)
+        })
+}
+
+/// Reads a `-J --flat-playlist` listing, `None` if the JSON describes a single video.
+pub fn parse_playlist(json: &Value) -> Option<Playlist> {
+    if json["_type"].as_str() != Some("playlist") {
+        return None;
+    }
+
+    let fallback_extractor = json["extractor_key"].as_str().unwrap_or("Generic");
+    let entries = json["entries"]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .enumerate()
+                .filter_map(|(i, entry)| {
+                    let id = entry["id"].as_str()?.to_string();
+                    let url = entry["url"]
+                        .as_str()
+                        .or(entry["webpage_url"].as_str())?
+                        .to_string();
+
+                    Some(PlaylistEntry {
+                        index: i + 1,
+                        title: entry["title"].as_str().unwrap_or("").to_string(),
+                        extractor: entry["ie_key"]
+                            .as_str()
+                            .or(entry["extractor_key"].as_str())
+                            .unwrap_or(fallback_extractor)
+                            .to_string(),
+                        id,
+                        url,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(Playlist {
+        id: json["id"].as_str().unwrap_or("").to_string(),
+        title: json["title"].as_str().unwrap_or("").to_string(),
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_progress_lines() {
+        let progress = parse_progress("[download]  45.3% of ~  10.52MiB at    1.23MiB/s ETA 00:07 (frag 3/10)").unwrap();
+        assert_eq!(progress.percent, 45.3);
+        assert_eq!(progress.speed.as_deref(), Some("1.23MiB/s"));
+        assert_eq!(progress.eta_secs, Some(7));
+
+        let progress = parse_progress("[download]   2.0% of  512.00MiB at  8.00MiB/s ETA 01:02:03").unwrap();
+        assert_eq!(progress.percent, 2.0);
+        assert_eq!(progress.speed.as_deref(), Some("8.00MiB/s"));
+        assert_eq!(progress.eta_secs, Some(3723));
+    }
+
+    #[test]
+    fn parses_progress_without_speed_or_eta() {
+        let progress = parse_progress("[download]   0.0% of   10.52MiB at  Unknown B/s ETA Unknown").unwrap();
+        assert_eq!(progress.percent, 0.0);
+        assert_eq!(progress.speed, None);
+        assert_eq!(progress.eta_secs, None);
+
+        let progress = parse_progress("[download] 100% of   10.52MiB in 00:00:05 at 2.00MiB/s").unwrap();
+        assert_eq!(progress.percent, 100.0);
+        assert_eq!(progress.speed.as_deref(), Some("2.00MiB/s"));
+        assert_eq!(progress.eta_secs, None);
+
+        let progress = parse_progress("[download]  12.5% of ~   3.00GiB").unwrap();
+        assert_eq!(progress.percent, 12.5);
+        assert_eq!(progress.speed, None);
+        assert_eq!(progress.eta_secs, None);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("42"), Some(42));
+        assert_eq!(parse_duration("01:30"), Some(90));
+        assert_eq!(parse_duration("1:02:03"), Some(3723));
+        assert_eq!(parse_duration("1:2:3:4"), None);
+        assert_eq!(parse_duration("1:xx"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn refuses_overflowing_durations() {
+        assert_eq!(parse_duration("18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_duration("18446744073709551615:00"), None);
+        assert_eq!(parse_duration("307445734561825861:00:00"), None);
+        assert_eq!(parse_duration("18446744073709551616"), None);
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert!(parse_progress("[download] Destination: video.f137.mp4").is_none());
+        assert!(parse_progress("[download] video.mp4 has already been downloaded").is_none());
+        assert!(parse_progress("[youtube] dQw4w9WgXcQ: Downloading webpage").is_none());
+        assert!(parse_progress("[Merger] Merging formats into \"video.mkv\"").is_none());
+        assert!(parse_progress("").is_none());
+    }
+}